@@ -12,6 +12,7 @@ use std::env;
 use std::fs::File;
 use std::io::Write;
 use std::path::PathBuf;
+use std::process::Command;
 
 fn main() {
     // Put `memory.x` in our output directory and ensure it's
@@ -28,4 +29,33 @@ fn main() {
     // here, we ensure the build script is only re-run when
     // `memory.x` is changed.
     println!("cargo:rerun-if-changed=memory.x");
+
+    // The build ID lets the host tell a freshly flashed image is running even when
+    // the crate version hasn't changed. It's the commit the firmware was built from,
+    // marked dirty if the firmware has uncommitted changes. It's left out of the USB
+    // serial number, which must stay the same across updates.
+    let commit = git(&["rev-parse", "--short", "HEAD"]).unwrap_or_else(|| "unknown".to_owned());
+    let dirty = Command::new("git")
+        .args(["diff", "--quiet", "HEAD", "--", "."])
+        .status()
+        .map(|status| status.code() == Some(1))
+        .unwrap_or(false);
+    println!("cargo:rustc-env=VERITAS_BUILD_ID={commit}{}", if dirty { "-dirty" } else { "" });
+    println!("cargo:rerun-if-changed=src");
+    println!("cargo:rerun-if-changed=core/src");
+    if let Some(git_dir) = git(&["rev-parse", "--git-dir"]) {
+        println!("cargo:rerun-if-changed={git_dir}/HEAD");
+        println!("cargo:rerun-if-changed={git_dir}/index");
+    }
+}
+
+/// Runs git with the given arguments, returning its trimmed output if it succeeded.
+fn git(args: &[&str]) -> Option<String> {
+    Command::new("git")
+        .args(args)
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|output| output.trim().to_owned())
 }
@@ -0,0 +1,109 @@
+//! Arrangement of controller input bits on the input displays.
+
+/// Bits covered by a layout: the first two bytes shown on a display.
+pub const LAYOUT_BITS: usize = 16;
+
+/// Which input bit each display bit shows. Bits are numbered from the MSB of the first byte, so bit `n` of the
+/// display is taken from bit `self.0[n]` of the input.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct BitLayout([u8; LAYOUT_BITS]);
+impl BitLayout {
+    /// Shows every bit where the console's input puts it.
+    pub const IDENTITY: Self = Self::swapped(0, 0);
+    
+    /// The identity layout, with bits `a` and `b` exchanged.
+    pub const fn swapped(a: usize, b: usize) -> Self {
+        let mut map = [0; LAYOUT_BITS];
+        let mut i = 0;
+        while i < LAYOUT_BITS {
+            map[i] = i as u8;
+            i += 1;
+        }
+        map[a] = b as u8;
+        map[b] = a as u8;
+        
+        Self(map)
+    }
+    
+    /// Returns a layout, if every entry names one of the bits it covers.
+    pub fn new(map: [u8; LAYOUT_BITS]) -> Option<Self> {
+        map.iter().all(|bit| (*bit as usize) < LAYOUT_BITS).then_some(Self(map))
+    }
+    
+    /// Rearranges the first two bytes of `data`. Bits taken from beyond the end of the data are shown as 0.
+    pub fn apply(&self, data: &mut [u8]) {
+        let len = data.len().min(LAYOUT_BITS / 8);
+        let mut input = [0u8; LAYOUT_BITS / 8];
+        input[..len].copy_from_slice(&data[..len]);
+        
+        let bit = |bits: &[u8], n: usize| bits[n / 8] & (0x80 >> (n % 8)) != 0;
+        for (n, src) in self.0.iter().enumerate().take(len * 8) {
+            if bit(&input, *src as usize) {
+                data[n / 8] |= 0x80 >> (n % 8);
+            } else {
+                data[n / 8] &= !(0x80 >> (n % 8));
+            }
+        }
+    }
+}
+
+impl Default for BitLayout {
+    fn default() -> Self {
+        Self::IDENTITY
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn identity_leaves_data_alone() {
+        let mut data = [0xA5, 0x3C, 0xFF];
+        BitLayout::IDENTITY.apply(&mut data);
+        
+        assert_eq!(data, [0xA5, 0x3C, 0xFF]);
+    }
+    
+    #[test]
+    fn swapped_matches_swap_bits() {
+        // Bits 2 and 3 from the MSB are bits 5 and 4 from the LSB
+        for byte in [0b0010_0000, 0b0001_0000, 0b1011_0001, 0b1110_1111] {
+            let mut data = [byte, 0x00];
+            BitLayout::swapped(2, 3).apply(&mut data);
+            
+            assert_eq!(data, [crate::genesis::swap_bits(byte, 5, 4), 0x00]);
+        }
+    }
+    
+    #[test]
+    fn bits_move_between_bytes() {
+        let mut map = BitLayout::IDENTITY.0;
+        map.swap(0, 15);
+        let layout = BitLayout::new(map).unwrap();
+        
+        let mut data = [0x80, 0x00];
+        layout.apply(&mut data);
+        
+        assert_eq!(data, [0x00, 0x01]);
+    }
+    
+    #[test]
+    fn bits_beyond_short_data_read_as_zero() {
+        let mut map = BitLayout::IDENTITY.0;
+        map[0] = 8;
+        map[1] = 0;
+        let mut data = [0x80];
+        BitLayout::new(map).unwrap().apply(&mut data);
+        
+        assert_eq!(data, [0x40]);
+    }
+    
+    #[test]
+    fn rejects_bits_outside_layout() {
+        let mut map = BitLayout::IDENTITY.0;
+        map[3] = 16;
+        
+        assert_eq!(BitLayout::new(map), None);
+    }
+}
\ No newline at end of file
@@ -0,0 +1,121 @@
+//! The ring the event log is kept in, where every record is numbered in the order it was added, and the
+//! oldest are overwritten once it's full.
+
+use alloc::vec::Vec;
+use heapless::Deque;
+
+/// Up to `N` records, and the number of the oldest still held.
+pub struct EventRing<T, const N: usize> {
+    records: Deque<T, N>,
+    first: u32,
+}
+
+impl<T: Copy, const N: usize> EventRing<T, N> {
+    pub const fn new() -> Self {
+        Self {
+            records: Deque::new(),
+            first: 0,
+        }
+    }
+    
+    /// Adds a record, overwriting the oldest if the ring is full.
+    pub fn push(&mut self, record: T) {
+        if self.records.is_full() {
+            self.records.pop_front();
+            self.first = self.first.wrapping_add(1);
+        }
+        self.records.push_back(record).ok();
+    }
+    
+    /// Number the next record will get.
+    pub fn next(&self) -> u32 {
+        self.first.wrapping_add(self.records.len() as u32)
+    }
+    
+    /// Returns up to `max` records, starting from record number `from` (or the oldest still held, if it's been
+    /// overwritten), along with the number of the first one returned. If there are none, that's the number the
+    /// next record will get.
+    ///
+    /// Numbers wrap around, so `from` is taken as overwritten if it's less than half the number space behind the
+    /// oldest record, and as not yet recorded otherwise.
+    pub fn read(&self, from: u32, max: usize) -> (u32, Vec<T>) {
+        let behind = self.first.wrapping_sub(from);
+        let skip = if behind != 0 && behind <= u32::MAX / 2 {
+            0
+        } else {
+            (from.wrapping_sub(self.first) as usize).min(self.records.len())
+        };
+        
+        let records = self.records.iter()
+            .skip(skip)
+            .take(max)
+            .copied()
+            .collect();
+        
+        (self.first.wrapping_add(skip as u32), records)
+    }
+}
+
+impl<T: Copy, const N: usize> Default for EventRing<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn filled<const N: usize>(first: u32, count: u32) -> EventRing<u32, N> {
+        let mut ring = EventRing { records: Deque::new(), first };
+        for i in 0..count {
+            ring.push(first.wrapping_add(i));
+        }
+        
+        ring
+    }
+    
+    #[test]
+    fn records_are_read_from_the_number_asked_for() {
+        let ring = filled::<8>(0, 5);
+        
+        assert_eq!(ring.read(0, 10), (0, vec![0, 1, 2, 3, 4]));
+        assert_eq!(ring.read(3, 10), (3, vec![3, 4]));
+        assert_eq!(ring.read(1, 2), (1, vec![1, 2]));
+    }
+    
+    #[test]
+    fn reading_past_the_newest_returns_the_next_number() {
+        let ring = filled::<8>(0, 5);
+        
+        assert_eq!(ring.read(5, 10), (5, vec![]));
+        assert_eq!(ring.read(100, 10), (5, vec![]));
+        assert_eq!(filled::<8>(0, 0).read(0, 10), (0, vec![]));
+    }
+    
+    #[test]
+    fn overwritten_records_are_skipped() {
+        let ring = filled::<4>(0, 10);
+        
+        assert_eq!(ring.next(), 10);
+        assert_eq!(ring.read(0, 10), (6, vec![6, 7, 8, 9]));
+        assert_eq!(ring.read(5, 10), (6, vec![6, 7, 8, 9]));
+        assert_eq!(ring.read(6, 2), (6, vec![6, 7]));
+        assert_eq!(ring.read(9, 10), (9, vec![9]));
+    }
+    
+    #[test]
+    fn numbers_wrap_around() {
+        let ring = filled::<4>(u32::MAX - 5, 10);
+        
+        assert_eq!(ring.next(), 4);
+        assert_eq!(ring.read(u32::MAX - 5, 10), (0, vec![0, 1, 2, 3]));
+        assert_eq!(ring.read(u32::MAX, 10), (0, vec![0, 1, 2, 3]));
+        assert_eq!(ring.read(2, 10), (2, vec![2, 3]));
+        assert_eq!(ring.read(4, 10), (4, vec![]));
+        
+        let ring = filled::<4>(u32::MAX - 1, 3);
+        assert_eq!(ring.read(u32::MAX, 10), (u32::MAX, vec![u32::MAX, 0]));
+        assert_eq!(ring.read(1, 10), (1, vec![]));
+    }
+}
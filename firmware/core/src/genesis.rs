@@ -0,0 +1,306 @@
+//! Pin sets presented by the Genesis select-line responder, and reading them back while spying.
+
+use crate::hal::{Gpio, StateMachine, Timer};
+
+/// Position of each of a port's data lines, relative to its lowest data pin.
+#[derive(Debug, Copy, Clone)]
+pub struct PinLayout {
+    pub up: u8,
+    pub down: u8,
+    pub left_0: u8,
+    pub right_0: u8,
+    pub b_a: u8,
+    pub c_start: u8,
+}
+
+/// Data pins to drive high for a select level. `input` is active-low, in the order
+/// A, Start, Up, Down, Left, Right, B, C (MSB first).
+#[inline(always)]
+pub fn calc_state(layout: &PinLayout, input: u8, select_high: bool) -> u16 {
+    let bit = |n: usize| (input >> n) & 1 != 0;
+    let levels = if select_high {
+        [(layout.c_start, bit(0)), (layout.b_a, bit(1)), (layout.right_0, bit(2)), (layout.left_0, bit(3)), (layout.down, bit(4)), (layout.up, bit(5))]
+    } else {
+        [(layout.c_start, bit(6)), (layout.b_a, bit(7)), (layout.right_0, false), (layout.left_0, false), (layout.down, bit(4)), (layout.up, bit(5))]
+    };
+    
+    levels.iter()
+        .filter(|(_, high)| *high)
+        .fold(0, |set, (pin, _)| set | (1 << pin))
+}
+
+/// Both pin sets for an input, as loaded into a responder: select high in the low half, and select low in
+/// the high half.
+#[inline(always)]
+pub fn frame_word(layout: &PinLayout, input: u8) -> u32 {
+    calc_state(layout, input, true) as u32 | ((calc_state(layout, input, false) as u32) << 16)
+}
+
+/// Input read back from both of a controller's pin sets, in the same order as [`calc_state`]. A 3-button
+/// controller only presents A and Start while select is low, so everything else comes from the high set.
+#[inline(always)]
+pub fn decode_state(layout: &PinLayout, high: u16, low: u16) -> u8 {
+    let pin = |set: u16, n: u8| (set >> n) & 1 != 0;
+    let bits = [
+        pin(low, layout.b_a),
+        pin(low, layout.c_start),
+        pin(high, layout.up),
+        pin(high, layout.down),
+        pin(high, layout.left_0),
+        pin(high, layout.right_0),
+        pin(high, layout.b_a),
+        pin(high, layout.c_start),
+    ];
+    
+    bits.iter().fold(0, |input, high| (input << 1) | *high as u8)
+}
+
+/// Loads a frame's word into a port's responder, which presents it straight away from `refresh`.
+#[inline(always)]
+pub fn present<S: StateMachine>(sm: &mut S, word: u32, refresh: u8) {
+    sm.fifo_write(word);
+    sm.jump(refresh);
+}
+
+/// Follows a port's select line to find the end of each frame's reads: once select has toggled, then been
+/// still for the step timeout.
+#[derive(Debug, Copy, Clone)]
+pub struct SelectWatcher {
+    timeout_us: u32,
+    select_high: bool,
+    last_edge_at: u32,
+    reading: bool,
+}
+impl SelectWatcher {
+    pub fn new<T: Timer>(timeout_us: u32, select_high: bool, timer: &T) -> Self { Self {
+        timeout_us,
+        select_high,
+        last_edge_at: timer.now_us(),
+        reading: false,
+    }}
+    
+    /// Called with select's current level. Returns true once, when a frame's reads have ended.
+    #[inline(always)]
+    pub fn poll<T: Timer>(&mut self, select_high: bool, timer: &T) -> bool {
+        if select_high != self.select_high {
+            self.select_high = select_high;
+            self.last_edge_at = timer.now_us();
+            self.reading = true;
+        } else if self.reading && timer.elapsed_us(self.last_edge_at) >= self.timeout_us {
+            self.reading = false;
+            return true;
+        }
+        
+        false
+    }
+}
+
+/// Where a port's lines are connected.
+#[derive(Debug, Copy, Clone)]
+pub struct PortPins {
+    pub select: usize,
+    /// Lowest data pin, which `layout` is relative to.
+    pub data_base: usize,
+    pub layout: PinLayout,
+}
+
+/// Reads 3-button controllers plugged through the device, keeping the last pin set seen at each select
+/// level. Port 1's select line marks the end of each frame.
+#[derive(Debug, Copy, Clone)]
+pub struct Spy {
+    ports: [PortPins; 2],
+    watcher: SelectWatcher,
+    /// Pin sets of each port, while select is low and high.
+    sets: [[u16; 2]; 2],
+}
+impl Spy {
+    pub fn new<G: Gpio, T: Timer>(ports: [PortPins; 2], timeout_us: u32, gpio: &G, timer: &T) -> Self { Self {
+        ports,
+        watcher: SelectWatcher::new(timeout_us, gpio.is_high(ports[0].select), timer),
+        sets: [[u16::MAX; 2]; 2],
+    }}
+    
+    /// Samples both ports. Returns their input once a frame has ended.
+    #[inline(always)]
+    pub fn poll<G: Gpio, T: Timer>(&mut self, gpio: &G, timer: &T) -> Option<[u8; 2]> {
+        let levels = gpio.levels();
+        for (port, sets) in self.ports.iter().zip(&mut self.sets) {
+            let high = levels & (1 << port.select) != 0;
+            sets[high as usize] = (levels >> port.data_base) as u16;
+        }
+        
+        let select_high = levels & (1 << self.ports[0].select) != 0;
+        if !self.watcher.poll(select_high, timer) {
+            return None;
+        }
+        
+        Some([0, 1].map(|n| decode_state(&self.ports[n].layout, self.sets[n][1], self.sets[n][0])))
+    }
+}
+
+/// Swaps bits `a` and `b`.
+#[inline(always)]
+pub fn swap_bits(data: u8, a: usize, b: usize) -> u8 {
+    let x = ((data >> a) ^ (data >> b)) & 1;
+    
+    data ^ ((x << a) | (x << b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{GenesisConsole, GenesisPort};
+    
+    /// Port 1's data lines, which are interleaved with port 2's.
+    const LAYOUT: PinLayout = PinLayout { up: 0, down: 2, left_0: 4, right_0: 6, b_a: 8, c_start: 11 };
+    /// Both ports, wired as on the device.
+    const PORTS: [PortPins; 2] = [
+        PortPins { select: 11, data_base: 13, layout: LAYOUT },
+        PortPins { select: 9, data_base: 10, layout: PinLayout { up: 0, down: 2, left_0: 4, right_0: 8, b_a: 10, c_start: 12 } },
+    ];
+    const STEP_TIMEOUT_US: u32 = 1500;
+    
+    /// Select edges of a frame's reads: two 3-button reads, 20µs apart, starting at `at`.
+    fn reads_at(at: u32) -> Vec<u32> {
+        (0..4).map(|n| at + n * 20).collect()
+    }
+    
+    /// Polls the spy every microsecond until `until`, returning when each frame ended and what was read.
+    fn poll_until(spy: &mut Spy, console: &GenesisConsole, until: u32) -> Vec<(u32, [u8; 2])> {
+        let mut frames = vec![];
+        
+        while console.now() < until {
+            if let Some(inputs) = spy.poll(console, console) {
+                frames.push((console.now(), inputs));
+            }
+            console.advance(1);
+        }
+        
+        frames
+    }
+    
+    fn words(inputs: [u8; 2]) -> [u32; 2] {
+        [0, 1].map(|port| frame_word(&PORTS[port].layout, inputs[port]))
+    }
+    
+    #[test]
+    fn select_high_presents_directions_b_and_c() {
+        let port = GenesisPort::new(LAYOUT, frame_word(&LAYOUT, 0b1101_0110));
+        
+        // Up, Down, Left, Right, B, C
+        assert_eq!(port.read(true), [false, true, false, true, true, false]);
+    }
+    
+    #[test]
+    fn select_low_presents_a_and_start() {
+        let port = GenesisPort::new(LAYOUT, frame_word(&LAYOUT, 0b0111_1111));
+        
+        // Up, Down, 0, 0, A, Start
+        assert_eq!(port.read(false), [true, true, false, false, false, true]);
+    }
+    
+    #[test]
+    fn left_and_right_read_low_while_select_is_low() {
+        // A 3-button controller is identified by both being low, whatever is pressed
+        let port = GenesisPort::new(LAYOUT, frame_word(&LAYOUT, 0xFF));
+        let pins = port.read(false);
+        
+        assert!(!pins[2] && !pins[3]);
+    }
+    
+    #[test]
+    fn released_controller_drives_every_line_high_while_select_is_high() {
+        let port = GenesisPort::new(LAYOUT, frame_word(&LAYOUT, 0xFF));
+        
+        assert!(port.read(true).iter().all(|pin| *pin));
+    }
+    
+    #[test]
+    fn decode_state_reverses_both_pin_sets() {
+        for input in [0x00, 0xFF, 0b1101_0110, 0b0111_1111, 0b1010_0101] {
+            let high = calc_state(&LAYOUT, input, true);
+            let low = calc_state(&LAYOUT, input, false);
+            
+            assert_eq!(decode_state(&LAYOUT, high, low), input);
+        }
+    }
+    
+    #[test]
+    fn decode_state_ignores_the_other_ports_pins() {
+        let other = 0b0110_1010_1010;
+        let high = calc_state(&LAYOUT, 0b0110_1001, true) | other;
+        let low = calc_state(&LAYOUT, 0b0110_1001, false) | other;
+        
+        assert_eq!(decode_state(&LAYOUT, high, low), 0b0110_1001);
+    }
+    
+    #[test]
+    fn spy_reads_what_the_responders_present() {
+        let console = GenesisConsole::new(PORTS, words([0b1101_0110, 0b0111_1111]), reads_at(100));
+        let mut spy = Spy::new(PORTS, STEP_TIMEOUT_US, &console, &console);
+        
+        let frames = poll_until(&mut spy, &console, 5000);
+        
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].1, [0b1101_0110, 0b0111_1111]);
+    }
+    
+    #[test]
+    fn frame_ends_once_select_is_still_for_the_step_timeout() {
+        let console = GenesisConsole::new(PORTS, words([0xFF; 2]), reads_at(100));
+        let mut spy = Spy::new(PORTS, STEP_TIMEOUT_US, &console, &console);
+        
+        let frames = poll_until(&mut spy, &console, 5000);
+        
+        // The last edge is at 160µs
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].0, 160 + STEP_TIMEOUT_US);
+    }
+    
+    #[test]
+    fn edges_closer_than_the_step_timeout_keep_the_frame_open() {
+        let console = GenesisConsole::new(PORTS, words([0xFF; 2]), vec![100, 1100, 2100, 3100]);
+        let mut spy = Spy::new(PORTS, STEP_TIMEOUT_US, &console, &console);
+        
+        let frames = poll_until(&mut spy, &console, 10000);
+        
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].0, 3100 + STEP_TIMEOUT_US);
+    }
+    
+    #[test]
+    fn each_frame_is_read_from_the_word_presented_for_it() {
+        let edges = [reads_at(100), reads_at(16783)].concat();
+        let console = GenesisConsole::new(PORTS, words([0x0F, 0xF0]), edges);
+        let mut spy = Spy::new(PORTS, STEP_TIMEOUT_US, &console, &console);
+        
+        let mut frames = poll_until(&mut spy, &console, 10000);
+        // The replay loads the next frame once the first frame's reads have ended
+        for (port, word) in words([0x55, 0xAA]).into_iter().enumerate() {
+            present(&mut *console.responder(port), word, 0);
+        }
+        frames.extend(poll_until(&mut spy, &console, 20000));
+        
+        let inputs: Vec<_> = frames.iter().map(|(_, inputs)| *inputs).collect();
+        assert_eq!(inputs, [[0x0F, 0xF0], [0x55, 0xAA]]);
+    }
+    
+    #[test]
+    fn queued_word_is_only_presented_once_the_responder_refreshes() {
+        let mut port = GenesisPort::new(LAYOUT, frame_word(&LAYOUT, 0xFF));
+        port.fifo_write(frame_word(&LAYOUT, 0x00));
+        
+        assert!(port.read(true).iter().all(|pin| *pin));
+        
+        port.jump(0);
+        assert!(port.read(true).iter().all(|pin| !*pin));
+    }
+    
+    #[test]
+    fn swap_bits_exchanges_only_the_given_bits() {
+        assert_eq!(swap_bits(0b0010_0000, 5, 4), 0b0001_0000);
+        assert_eq!(swap_bits(0b1011_0001, 5, 4), 0b1011_0001);
+        assert_eq!(swap_bits(0b1110_1111, 4, 0), 0b1111_1110);
+        assert_eq!(swap_bits(0b0000_0001, 0, 7), 0b1000_0000);
+    }
+}
@@ -0,0 +1,36 @@
+//! Interfaces the responders use to reach the hardware. The firmware implements them on top of its
+//! register-level HAL, and `sim` implements them for tests.
+
+/// Levels of the GPIO pins, numbered as on the RP2040.
+pub trait Gpio {
+    /// Levels of every pin, with bit n for pin n. They're read at once, so pins sampled together agree.
+    fn levels(&self) -> u32;
+    
+    fn is_high(&self, pin: usize) -> bool {
+        self.levels() & (1 << pin) != 0
+    }
+}
+
+/// Free-running microsecond counter.
+pub trait Timer {
+    fn now_us(&self) -> u32;
+    
+    /// Microseconds since `since`, a previous value of `now_us`. Handles the counter wrapping.
+    fn elapsed_us(&self, since: u32) -> u32 {
+        self.now_us().wrapping_sub(since)
+    }
+}
+
+/// A PIO state machine running one of the responders.
+pub trait StateMachine {
+    /// Attempts to read a word from the RX FIFO.
+    fn fifo_read(&mut self) -> Option<u32>;
+    
+    /// Attempts to write a word to the TX FIFO. Returns true if it was written.
+    fn fifo_write(&mut self, data: u32) -> bool;
+    
+    fn is_tx_empty(&self) -> bool;
+    
+    /// Immediately jumps to an address in the state machine's program.
+    fn jump(&mut self, address: u8);
+}
@@ -0,0 +1,181 @@
+//! N64 joybus encoding, and the controller side of a transaction.
+//!
+//! The responder shifts out one line level per microsecond, so each bit is sent as four levels: a 1 is
+//! low for 1µs then high for 3µs, and a 0 is low for 3µs then high for 1µs.
+
+use crate::hal::{StateMachine, Timer};
+
+/// Line levels of the controller's stop bit: low for 2µs, then released.
+pub const STOP_BIT: u32 = 0x3FFFFFFF;
+
+/// Line levels that send a byte, MSB first.
+#[inline(always)]
+pub fn encode(mut data: u8) -> u32 {
+    let mut out = 0;
+    for _ in 0..8 {
+        out <<= 4;
+        if (data & 0x80) != 0 {
+            out |= 0b0111;
+        } else {
+            out |= 0b0001;
+        }
+        
+        data <<= 1;
+    }
+    
+    out
+}
+
+/// 5-bit CRC sent in the low bits of a pak address.
+pub fn address_crc(address: u16) -> u8 {
+    let mut address = address;
+    let mut crc = 0u8;
+    for _ in 0..16 {
+        let xor = if (crc & 0x10) != 0 { 0x15 } else { 0x00 };
+        crc <<= 1;
+        if (address & 0x8000) != 0 {
+            crc |= 1;
+        }
+        address <<= 1;
+        crc = (crc ^ xor) & 0x1F;
+    }
+    
+    crc
+}
+
+/// 8-bit CRC (polynomial 0x85) of a pak data block.
+#[cfg_attr(target_os = "none", link_section = ".ram_code")]
+pub fn data_crc(data: &[u8; 32]) -> u8 {
+    let mut crc = 0u8;
+    // The block is followed by 8 zero bits, to flush it through the CRC
+    for byte in data.iter().copied().chain([0]) {
+        for bit in (0..8).rev() {
+            let xor = if (crc & 0x80) != 0 { 0x85 } else { 0x00 };
+            crc <<= 1;
+            if (byte & (1 << bit)) != 0 {
+                crc |= 1;
+            }
+            crc ^= xor;
+        }
+    }
+    
+    crc
+}
+
+/// Reads the rest of a request, which the console sends straight after the command byte. Returns false if
+/// the console stopped sending before `buf` was filled.
+#[inline(always)]
+pub fn read_payload<S: StateMachine, T: Timer>(sm: &mut S, timer: &T, buf: &mut [u8], timeout_us: u32) -> bool {
+    for byte in buf.iter_mut() {
+        let start = timer.now_us();
+        loop {
+            if let Some(data) = sm.fifo_read() {
+                *byte = data as u8;
+                break;
+            }
+            if timer.elapsed_us(start) > timeout_us {
+                return false;
+            }
+        }
+    }
+    
+    true
+}
+
+/// Sends a response followed by the stop bit, returning once the last of it has been queued.
+#[inline(always)]
+pub fn write_response<S: StateMachine>(sm: &mut S, write_vector: u8, data: &[u8]) {
+    sm.jump(write_vector);
+    
+    for byte in data {
+        let encoded = encode(*byte);
+        while !sm.fifo_write(encoded) {}
+    }
+    
+    while !sm.fifo_write(STOP_BIT) {}
+    
+    while !sm.is_tx_empty() {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{JoybusLine, MockTimer};
+    
+    #[test]
+    fn encodes_each_bit_as_four_levels() {
+        assert_eq!(encode(0x00), 0x11111111);
+        assert_eq!(encode(0xFF), 0x77777777);
+        assert_eq!(encode(0b1000_0001), 0x71111117);
+    }
+    
+    #[test]
+    fn console_decodes_what_is_encoded() {
+        for byte in 0..=255u8 {
+            assert_eq!(JoybusLine::decode(&[encode(byte)]), vec![byte]);
+        }
+    }
+    
+    #[test]
+    fn response_is_followed_by_stop_bit() {
+        let mut line = JoybusLine::new(&[]);
+        write_response(&mut line, 7, &[0x05, 0x00, 0x02]);
+        
+        assert_eq!(line.jumps, [7]);
+        assert_eq!(line.sent.last(), Some(&STOP_BIT));
+        assert_eq!(JoybusLine::decode(&line.sent[..3]), [0x05, 0x00, 0x02]);
+        assert!(JoybusLine::is_stop_bit(*line.sent.last().unwrap()));
+    }
+    
+    #[test]
+    fn reads_payload_bytes_in_order() {
+        let mut line = JoybusLine::new(&[0x80, 0x01, 0xAA]);
+        let timer = MockTimer::new(5);
+        let mut buf = [0u8; 3];
+        
+        assert!(read_payload(&mut line, &timer, &mut buf, 100));
+        assert_eq!(buf, [0x80, 0x01, 0xAA]);
+    }
+    
+    #[test]
+    fn short_payload_times_out() {
+        let mut line = JoybusLine::new(&[0x80]);
+        let timer = MockTimer::new(5);
+        let mut buf = [0u8; 2];
+        
+        assert!(!read_payload(&mut line, &timer, &mut buf, 100));
+    }
+    
+    #[test]
+    fn payload_timeout_survives_timer_wrapping() {
+        let mut line = JoybusLine::new(&[]);
+        let timer = MockTimer::starting_at(u32::MAX - 10, 5);
+        let mut buf = [0u8; 1];
+        
+        assert!(!read_payload(&mut line, &timer, &mut buf, 100));
+        assert!(timer.now_us() > 90 && timer.now_us() < 200);
+    }
+    
+    #[test]
+    fn address_crc_matches_known_pak_addresses() {
+        // Rumble pak identification, and motor control
+        assert_eq!(0x8000 | address_crc(0x8000) as u16, 0x8001);
+        assert_eq!(0xC000 | address_crc(0xC000) as u16, 0xC01B);
+        assert_eq!(address_crc(0x0000), 0x00);
+    }
+    
+    #[test]
+    fn data_crc_catches_single_bit_errors() {
+        let block = [0x5Au8; 32];
+        let crc = data_crc(&block);
+        
+        assert_eq!(data_crc(&[0; 32]), 0x00);
+        for i in 0..32 {
+            for bit in 0..8 {
+                let mut corrupted = block;
+                corrupted[i] ^= 1 << bit;
+                assert_ne!(data_crc(&corrupted), crc);
+            }
+        }
+    }
+}
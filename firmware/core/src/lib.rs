@@ -0,0 +1,23 @@
+//! Parts of the firmware that don't touch the hardware directly, so they can be tested on the host. The
+//! definitions both the firmware and the host software depend on live here too, so they can't drift apart.
+//!
+//! The firmware's cargo config builds for the RP2040 by default, so tests need the host's target given
+//! explicitly, e.g. `cargo test --target x86_64-unknown-linux-gnu`.
+
+#![cfg_attr(not(test), no_std)]
+
+extern crate alloc;
+
+pub mod display;
+pub mod events;
+pub mod genesis;
+pub mod hal;
+pub mod joybus;
+pub mod nes;
+pub mod packet;
+pub mod pio;
+pub mod stored;
+pub mod transition;
+
+#[cfg(test)]
+mod sim;
@@ -0,0 +1,177 @@
+//! Words shifted out by the NES and SNES shift register responders.
+//!
+//! Each port's responder outputs its word MSB first: one bit when the console latches, then another on
+//! every clock. Once the controller's own bits have been read, the rest of the word is the overread
+//! level, which an official controller holds high (released).
+
+use crate::hal::{StateMachine, Timer};
+
+/// Word for a NES controller. `input` is active-low, with A in the MSB.
+#[inline(always)]
+pub fn nes_frame_word(input: u8, overread: bool) -> u32 {
+    ((input as u32) << 24) | (overread_bits(overread) >> 8)
+}
+
+/// Word for a SNES controller. `input` is active-low, with B in the MSB.
+#[inline(always)]
+pub fn snes_frame_word(input: u16, overread: bool) -> u32 {
+    ((input as u32) << 16) | (overread_bits(overread) >> 16)
+}
+
+#[inline(always)]
+fn overread_bits(overread: bool) -> u32 {
+    if overread { u32::MAX } else { 0 }
+}
+
+/// Queues a frame's word in each port's responder. It's presented from the console's next latch.
+#[inline(always)]
+pub fn present<S: StateMachine>(ports: &mut [S; 2], words: [u32; 2]) {
+    for (sm, word) in ports.iter_mut().zip(words) {
+        sm.fifo_write(word);
+    }
+}
+
+/// Groups the console's latches into frames. The first latch starts a frame, and any latches within the
+/// filter time of it are rereads of the same frame, like the ones NES games make to dodge DPCM corruption.
+#[derive(Debug, Copy, Clone)]
+pub struct LatchFilter {
+    filter_us: u32,
+    latched_at: Option<u32>,
+}
+impl LatchFilter {
+    pub const fn new(filter_us: u32) -> Self { Self {
+        filter_us,
+        latched_at: None,
+    }}
+    
+    pub fn filter_us(&self) -> u32 {
+        self.filter_us
+    }
+    
+    /// Called on each latch. Returns true if it starts a new frame.
+    #[inline(always)]
+    pub fn latch<T: Timer>(&mut self, timer: &T) -> bool {
+        if self.latched_at.is_some() {
+            return false;
+        }
+        
+        self.latched_at = Some(timer.now_us());
+        true
+    }
+    
+    /// Returns true once the filter time has passed since the frame started, after which the next latch
+    /// starts a new frame. Only returns true once for each frame.
+    #[inline(always)]
+    pub fn expired<T: Timer>(&mut self, timer: &T) -> bool {
+        match self.latched_at {
+            Some(at) if timer.elapsed_us(at) >= self.filter_us => {
+                self.latched_at = None;
+                true
+            },
+            _ => false,
+        }
+    }
+    
+    /// Ends the current frame, for when the filter time is kept by an alarm instead.
+    #[inline(always)]
+    pub fn end(&mut self) {
+        self.latched_at = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{MockTimer, ShiftRegister};
+    
+    #[test]
+    fn nes_shifts_input_msb_first() {
+        let mut reg = ShiftRegister::new(nes_frame_word(0b0110_1001, true));
+        
+        assert_eq!(reg.read(8), [false, true, true, false, true, false, false, true]);
+    }
+    
+    #[test]
+    fn nes_overread_follows_setting() {
+        let mut high = ShiftRegister::new(nes_frame_word(0x00, true));
+        let mut low = ShiftRegister::new(nes_frame_word(0xFF, false));
+        
+        assert!(high.read(24)[8..].iter().all(|bit| *bit));
+        assert!(low.read(24)[8..].iter().all(|bit| !*bit));
+    }
+    
+    #[test]
+    fn snes_shifts_both_bytes_then_overreads() {
+        let mut reg = ShiftRegister::new(snes_frame_word(0x7FFE, true));
+        let bits = reg.read(20);
+        
+        assert!(!bits[0]);
+        assert!(bits[1..15].iter().all(|bit| *bit));
+        assert!(!bits[15]);
+        assert!(bits[16..].iter().all(|bit| *bit));
+    }
+    
+    /// Runs a replay of `frames` against a console latching at each of `latches`, with the filter's alarm
+    /// checked before every latch. Returns the first 8 bits each port shifts out on each latch.
+    fn replay(frames: &[[u8; 2]], latches: &[u32]) -> Vec<[Vec<bool>; 2]> {
+        let timer = MockTimer::new(0);
+        let mut filter = LatchFilter::new(8000);
+        let mut frames = frames.iter().map(|input| input.map(|input| nes_frame_word(input, true)));
+        let mut ports = frames.next().unwrap().map(ShiftRegister::new);
+        
+        latches.iter().map(|at| {
+            timer.set(*at);
+            if filter.expired(&timer) {
+                present(&mut ports, frames.next().unwrap_or([u32::MAX; 2]));
+            }
+            filter.latch(&timer);
+            
+            [ports[0].read(8), ports[1].read(8)]
+        }).collect()
+    }
+    
+    fn bits(input: u8) -> Vec<bool> {
+        (0..8).rev().map(|n| (input >> n) & 1 != 0).collect()
+    }
+    
+    #[test]
+    fn rereads_within_the_filter_present_the_same_frame() {
+        // Two reads a frame, 1ms apart, at 60Hz
+        let reads = replay(&[[0x0F, 0xF0], [0x55, 0xAA]], &[0, 1000, 16639, 17639]);
+        
+        assert_eq!(reads[0], [bits(0x0F), bits(0xF0)]);
+        assert_eq!(reads[1], reads[0]);
+        assert_eq!(reads[2], [bits(0x55), bits(0xAA)]);
+        assert_eq!(reads[3], reads[2]);
+    }
+    
+    #[test]
+    fn frames_without_a_latch_consume_no_input() {
+        // The console misses two frames, so the next latch gets the second frame rather than the fourth
+        let reads = replay(&[[0x01, 0x01], [0x02, 0x02], [0x03, 0x03]], &[0, 50000]);
+        
+        assert_eq!(reads[1], [bits(0x02), bits(0x02)]);
+    }
+    
+    #[test]
+    fn latch_just_after_the_filter_starts_a_new_frame() {
+        let reads = replay(&[[0x01, 0x01], [0x02, 0x02]], &[0, 7999, 8000]);
+        
+        assert_eq!(reads[1], [bits(0x01), bits(0x01)]);
+        assert_eq!(reads[2], [bits(0x02), bits(0x02)]);
+    }
+    
+    #[test]
+    fn filter_expires_once_per_frame() {
+        let timer = MockTimer::new(0);
+        let mut filter = LatchFilter::new(8000);
+        
+        assert!(!filter.expired(&timer));
+        assert!(filter.latch(&timer));
+        timer.set(100);
+        assert!(!filter.latch(&timer));
+        timer.set(8000);
+        assert!(filter.expired(&timer));
+        assert!(!filter.expired(&timer));
+    }
+}
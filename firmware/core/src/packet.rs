@@ -0,0 +1,183 @@
+//! Framing of the host protocol: a 4-byte big-endian length, followed by that many bytes of payload.
+
+/// A frame the assembler has finished receiving.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Frame<'a> {
+    Complete(&'a [u8]),
+    /// The payload didn't fit in the buffer, so it was discarded.
+    Oversized,
+}
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+enum State {
+    Length { header: [u8; 4], received: usize },
+    Payload { len: usize, received: usize },
+    Discard { remaining: usize },
+    Complete(usize),
+    Oversized,
+}
+
+/// Collects a frame out of bytes that arrive in arbitrarily sized pieces, into a fixed buffer of `N` bytes.
+///
+/// Once a frame is finished, no more bytes are taken until it's released, so [`PacketAssembler::wanted`] can be
+/// used to avoid reading past the end of a frame.
+pub struct PacketAssembler<const N: usize> {
+    buf: [u8; N],
+    state: State,
+}
+
+impl<const N: usize> PacketAssembler<N> {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; N],
+            state: State::Length { header: [0; 4], received: 0 },
+        }
+    }
+    
+    /// Number of bytes needed to finish the current part of the frame, or 0 if a frame is waiting to be released.
+    pub fn wanted(&self) -> usize {
+        match self.state {
+            State::Length { received, .. } => 4 - received,
+            State::Payload { len, received } => len - received,
+            State::Discard { remaining } => remaining,
+            State::Complete(_) | State::Oversized => 0,
+        }
+    }
+    
+    /// Takes bytes up to the end of the current frame. Returns how many were used.
+    pub fn push(&mut self, data: &[u8]) -> usize {
+        let mut used = 0;
+        while used < data.len() {
+            let count = self.wanted().min(data.len() - used);
+            if count == 0 {
+                break;
+            }
+            let chunk = &data[used..(used + count)];
+            used += count;
+            
+            self.state = match self.state {
+                State::Length { mut header, received } => {
+                    header[received..(received + count)].copy_from_slice(chunk);
+                    if received + count < 4 {
+                        State::Length { header, received: received + count }
+                    } else {
+                        Self::start_payload(u32::from_be_bytes(header) as usize)
+                    }
+                },
+                State::Payload { len, received } => {
+                    self.buf[received..(received + count)].copy_from_slice(chunk);
+                    if received + count < len {
+                        State::Payload { len, received: received + count }
+                    } else {
+                        State::Complete(len)
+                    }
+                },
+                State::Discard { remaining } if remaining > count => State::Discard { remaining: remaining - count },
+                State::Discard { .. } => State::Oversized,
+                state => state,
+            };
+        }
+        
+        used
+    }
+    
+    fn start_payload(len: usize) -> State {
+        if len == 0 {
+            State::Complete(0)
+        } else if len > N {
+            State::Discard { remaining: len }
+        } else {
+            State::Payload { len, received: 0 }
+        }
+    }
+    
+    /// The finished frame, if there is one.
+    pub fn frame(&self) -> Option<Frame<'_>> {
+        match self.state {
+            State::Complete(len) => Some(Frame::Complete(&self.buf[..len])),
+            State::Oversized => Some(Frame::Oversized),
+            _ => None,
+        }
+    }
+    
+    /// Drops the finished frame (or any partial one), and starts waiting for the next.
+    pub fn release(&mut self) {
+        self.state = State::Length { header: [0; 4], received: 0 };
+    }
+}
+
+impl<const N: usize> Default for PacketAssembler<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn framed(payload: &[u8]) -> Vec<u8> {
+        let mut data = (payload.len() as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(payload);
+        
+        data
+    }
+    
+    #[test]
+    fn assembles_frame_split_across_reads() {
+        let data = framed(&[1, 2, 3, 4, 5]);
+        let mut rx = PacketAssembler::<16>::new();
+        
+        for byte in &data[..8] {
+            assert_eq!(rx.push(&[*byte]), 1);
+            assert_eq!(rx.frame(), None);
+        }
+        assert_eq!(rx.push(&data[8..]), 1);
+        
+        assert_eq!(rx.frame(), Some(Frame::Complete(&[1, 2, 3, 4, 5])));
+    }
+    
+    #[test]
+    fn stops_at_end_of_frame() {
+        let mut data = framed(&[0xAA; 3]);
+        data.extend(framed(&[0xBB]));
+        let mut rx = PacketAssembler::<16>::new();
+        
+        assert_eq!(rx.push(&data), 7);
+        assert_eq!(rx.wanted(), 0);
+        assert_eq!(rx.push(&data[7..]), 0);
+        assert_eq!(rx.frame(), Some(Frame::Complete(&[0xAA; 3])));
+        
+        rx.release();
+        assert_eq!(rx.push(&data[7..]), 5);
+        assert_eq!(rx.frame(), Some(Frame::Complete(&[0xBB])));
+    }
+    
+    #[test]
+    fn empty_payload_completes_with_length() {
+        let mut rx = PacketAssembler::<16>::new();
+        
+        assert_eq!(rx.push(&framed(&[])), 4);
+        assert_eq!(rx.frame(), Some(Frame::Complete(&[])));
+    }
+    
+    #[test]
+    fn oversized_frame_is_consumed_and_reported() {
+        let mut data = framed(&[0x55; 40]);
+        data.extend(framed(&[0x01]));
+        let mut rx = PacketAssembler::<16>::new();
+        
+        let mut used = 0;
+        while rx.frame().is_none() {
+            let end = (used + 8).min(data.len());
+            used += rx.push(&data[used..end]);
+        }
+        
+        assert_eq!(used, 44);
+        assert_eq!(rx.frame(), Some(Frame::Oversized));
+        
+        rx.release();
+        rx.push(&data[used..]);
+        assert_eq!(rx.frame(), Some(Frame::Complete(&[0x01])));
+    }
+}
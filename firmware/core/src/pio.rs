@@ -0,0 +1,76 @@
+//! Placement of programs in a PIO block's instruction memory.
+
+/// Instructions each PIO block holds.
+pub const INSTR_MEM_SIZE: usize = 32;
+
+/// Slots a program of `len` instructions occupies at `offset`, with bit n for address n.
+pub fn slot_mask(offset: u8, len: usize) -> u32 {
+    let mask = if len >= INSTR_MEM_SIZE { u32::MAX } else { (1 << len) - 1 };
+    
+    mask << offset
+}
+
+/// Finds where a program of `len` instructions fits, given the slots already in use. A program with an origin
+/// only fits there. Otherwise the highest free space is used, which keeps the low addresses free for programs
+/// that need them.
+pub fn find_offset(used: u32, len: usize, origin: Option<u8>) -> Option<u8> {
+    if len == 0 || len > INSTR_MEM_SIZE {
+        return None;
+    }
+    
+    let fits = |offset: u8| offset as usize + len <= INSTR_MEM_SIZE && used & slot_mask(offset, len) == 0;
+    match origin {
+        Some(origin) => Some(origin).filter(|origin| fits(*origin)),
+        None => (0..=(INSTR_MEM_SIZE - len) as u8).rev().find(|offset| fits(*offset)),
+    }
+}
+
+/// Moves an instruction into a program loaded at `offset`. JMP is the only instruction with an address in it,
+/// which is kept in the low 5 bits.
+pub fn relocate(instr: u16, offset: u8) -> u16 {
+    if instr & 0xE000 != 0 {
+        return instr;
+    }
+    
+    let address = (instr & 0x1F) + offset as u16;
+    (instr & !0x1F) | (address & 0x1F)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn programs_fill_from_the_top() {
+        assert_eq!(find_offset(0, 32, None), Some(0));
+        assert_eq!(find_offset(0, 10, None), Some(22));
+        assert_eq!(find_offset(slot_mask(22, 10), 10, None), Some(12));
+        assert_eq!(find_offset(slot_mask(22, 10) | slot_mask(12, 10), 13, None), None);
+    }
+    
+    #[test]
+    fn programs_fit_around_used_slots() {
+        let used = slot_mask(0, 4) | slot_mask(20, 12);
+        
+        assert_eq!(find_offset(used, 16, None), Some(4));
+        assert_eq!(find_offset(used, 17, None), None);
+    }
+    
+    #[test]
+    fn origin_is_kept() {
+        assert_eq!(find_offset(0, 8, Some(0)), Some(0));
+        assert_eq!(find_offset(slot_mask(4, 1), 8, Some(0)), None);
+        assert_eq!(find_offset(0, 8, Some(28)), None);
+    }
+    
+    #[test]
+    fn only_jumps_are_relocated() {
+        // jmp !y 3 [2], with a delay that must survive
+        assert_eq!(relocate(0x0263, 20), 0x0277);
+        // jmp 30 wraps around the instruction memory, as the hardware's adder does
+        assert_eq!(relocate(0x001E, 4), 0x0002);
+        // mov isr, null and set pins, 1 keep their operands
+        assert_eq!(relocate(0xA0C3, 20), 0xA0C3);
+        assert_eq!(relocate(0xE001, 20), 0xE001);
+    }
+}
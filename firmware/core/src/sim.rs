@@ -0,0 +1,250 @@
+//! Stand-ins for the hardware used by the tests: the console's latch, clock and select waveforms, and the
+//! lines the responders drive in answer.
+//!
+//! The PIO programs are modelled only as far as the CPU sees them: which word a responder presents after
+//! its FIFO is written or it's sent to `refresh`, and the bits that word puts on the lines.
+
+use std::cell::{Cell, RefCell, RefMut};
+use std::collections::VecDeque;
+use crate::genesis::{PinLayout, PortPins};
+use crate::hal::{Gpio, StateMachine, Timer};
+
+/// A NES/SNES port's shift register responder, read by a console driving latch and clock.
+///
+/// A word written to the FIFO between latches is only picked up on the next latch, and the last word is
+/// repeated if none is written. Once all 32 bits are shifted out, the line reads low.
+pub struct ShiftRegister {
+    pending: Option<u32>,
+    word: u32,
+    shifter: u32,
+}
+impl ShiftRegister {
+    pub fn new(word: u32) -> Self { Self {
+        pending: Some(word),
+        word: 0,
+        shifter: 0,
+    }}
+    
+    pub fn latch(&mut self) {
+        if let Some(word) = self.pending.take() {
+            self.word = word;
+        }
+        self.shifter = self.word;
+    }
+    
+    pub fn clock(&mut self) {
+        self.shifter <<= 1;
+    }
+    
+    /// Level of the data line.
+    pub fn data(&self) -> bool {
+        (self.shifter & 0x80000000) != 0
+    }
+    
+    /// Latches, then reads `count` bits, clocking after each one.
+    pub fn read(&mut self, count: usize) -> Vec<bool> {
+        self.latch();
+        
+        (0..count).map(|_| {
+            let bit = self.data();
+            self.clock();
+            bit
+        }).collect()
+    }
+}
+
+impl StateMachine for ShiftRegister {
+    fn fifo_read(&mut self) -> Option<u32> {
+        None
+    }
+    
+    fn fifo_write(&mut self, data: u32) -> bool {
+        self.pending = Some(data);
+        true
+    }
+    
+    fn is_tx_empty(&self) -> bool {
+        self.pending.is_none()
+    }
+    
+    fn jump(&mut self, _address: u8) {}
+}
+
+/// A Genesis port's select-line responder, read by a console toggling select.
+///
+/// A word written to the FIFO is presented once the responder jumps to `refresh`, which every jump is taken
+/// to be.
+pub struct GenesisPort {
+    layout: PinLayout,
+    pending: Option<u32>,
+    word: u32,
+}
+impl GenesisPort {
+    pub fn new(layout: PinLayout, word: u32) -> Self { Self {
+        layout,
+        pending: None,
+        word,
+    }}
+    
+    /// Levels of the port's own data lines for a select level, relative to its lowest data pin.
+    pub fn pins(&self, select_high: bool) -> u16 {
+        let pins = if select_high { self.word & 0xFFFF } else { self.word >> 16 };
+        let l = &self.layout;
+        
+        [l.up, l.down, l.left_0, l.right_0, l.b_a, l.c_start].iter()
+            .fold(0, |set, pin| set | (pins as u16 & (1 << pin)))
+    }
+    
+    /// Levels of the six data lines for a select level, in connector order: Up, Down, Left/0, Right/0,
+    /// B/A, C/Start.
+    pub fn read(&self, select_high: bool) -> [bool; 6] {
+        let pins = self.pins(select_high);
+        let l = &self.layout;
+        
+        [l.up, l.down, l.left_0, l.right_0, l.b_a, l.c_start].map(|pin| (pins >> pin) & 1 != 0)
+    }
+}
+impl StateMachine for GenesisPort {
+    fn fifo_read(&mut self) -> Option<u32> {
+        None
+    }
+    
+    fn fifo_write(&mut self, data: u32) -> bool {
+        self.pending = Some(data);
+        true
+    }
+    
+    fn is_tx_empty(&self) -> bool {
+        self.pending.is_none()
+    }
+    
+    fn jump(&mut self, _address: u8) {
+        if let Some(word) = self.pending.take() {
+            self.word = word;
+        }
+    }
+}
+
+/// A Genesis with a responder in each port. Both select lines start high and toggle together at each of
+/// the given edge times. Time only moves on when it's advanced.
+pub struct GenesisConsole {
+    pins: [PortPins; 2],
+    responders: [RefCell<GenesisPort>; 2],
+    edges: Vec<u32>,
+    now: Cell<u32>,
+}
+impl GenesisConsole {
+    pub fn new(pins: [PortPins; 2], words: [u32; 2], edges: Vec<u32>) -> Self { Self {
+        pins,
+        responders: [0, 1].map(|port| RefCell::new(GenesisPort::new(pins[port].layout, words[port]))),
+        edges,
+        now: Cell::new(0),
+    }}
+    
+    pub fn responder(&self, port: usize) -> RefMut<'_, GenesisPort> {
+        self.responders[port].borrow_mut()
+    }
+    
+    pub fn now(&self) -> u32 {
+        self.now.get()
+    }
+    
+    pub fn advance(&self, us: u32) {
+        self.now.set(self.now.get() + us);
+    }
+    
+    fn select_high(&self) -> bool {
+        self.edges.iter().filter(|at| **at <= self.now()).count() % 2 == 0
+    }
+}
+impl Gpio for GenesisConsole {
+    fn levels(&self) -> u32 {
+        let select_high = self.select_high();
+        
+        self.pins.iter().zip(&self.responders).fold(0, |levels, (pins, responder)| {
+            let select = (select_high as u32) << pins.select;
+            let data = (responder.borrow().pins(select_high) as u32) << pins.data_base;
+            levels | select | data
+        })
+    }
+}
+impl Timer for GenesisConsole {
+    fn now_us(&self) -> u32 {
+        self.now()
+    }
+}
+
+/// An N64 controller port, as seen by the console. Requests are received by the responder one byte at a
+/// time, and responses are recorded as the line levels handed to it.
+pub struct JoybusLine {
+    received: VecDeque<u8>,
+    pub sent: Vec<u32>,
+    pub jumps: Vec<u8>,
+}
+impl JoybusLine {
+    pub fn new(request: &[u8]) -> Self { Self {
+        received: request.iter().copied().collect(),
+        sent: vec![],
+        jumps: vec![],
+    }}
+    
+    /// Samples each bit 2µs after its falling edge, as the console does.
+    pub fn decode(words: &[u32]) -> Vec<u8> {
+        words.iter().map(|word| {
+            (0..8).fold(0u8, |byte, i| {
+                let cell = (word >> (28 - i * 4)) & 0xF;
+                (byte << 1) | ((cell >> 2) & 1) as u8
+            })
+        }).collect()
+    }
+    
+    /// Whether a word pulls the line low for 2µs, then releases it.
+    pub fn is_stop_bit(word: u32) -> bool {
+        (word >> 30) == 0 && (word & 0x3FFFFFFF) == 0x3FFFFFFF
+    }
+}
+impl StateMachine for JoybusLine {
+    fn fifo_read(&mut self) -> Option<u32> {
+        self.received.pop_front().map(|byte| byte as u32)
+    }
+    
+    fn fifo_write(&mut self, data: u32) -> bool {
+        self.sent.push(data);
+        true
+    }
+    
+    fn is_tx_empty(&self) -> bool {
+        true
+    }
+    
+    fn jump(&mut self, address: u8) {
+        self.jumps.push(address);
+    }
+}
+
+/// A timer that moves on by a fixed step every time it's read.
+pub struct MockTimer {
+    now: Cell<u32>,
+    step: u32,
+}
+impl MockTimer {
+    pub fn new(step: u32) -> Self {
+        Self::starting_at(0, step)
+    }
+    
+    pub fn starting_at(now: u32, step: u32) -> Self { Self {
+        now: Cell::new(now),
+        step,
+    }}
+    
+    pub fn set(&self, now: u32) {
+        self.now.set(now);
+    }
+}
+impl Timer for MockTimer {
+    fn now_us(&self) -> u32 {
+        let now = self.now.get();
+        self.now.set(now.wrapping_add(self.step));
+        now
+    }
+}
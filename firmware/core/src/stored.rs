@@ -0,0 +1,158 @@
+//! Layout of the movie stored in the device's flash, which the host uploads and the device replays on its own.
+//!
+//! The region starts with a header sector: the magic, the encoded length (u32, little-endian), then the
+//! encoded [`StoredMovie`]. The inputs follow from the next sector on. The last page of the header sector holds
+//! a commit marker, which is only written once the rest of the header has been, so a header that was cut short
+//! by a power loss is never loaded.
+
+use alloc::vec::Vec;
+use bincode::config::Configuration;
+use bincode::{Decode, Encode};
+use crate::transition::{TransitionData, TransitionTiming};
+
+const BINCODE_CONFIG: Configuration = bincode::config::standard();
+
+/// Size of the flash region reserved for a stored movie.
+pub const REGION_SIZE: usize = 0x100000;
+/// Size of the header, which is the first sector of the region.
+pub const HEADER_SIZE: usize = 4096;
+/// Offset within the region where the inputs begin.
+pub const INPUTS_OFFSET: usize = HEADER_SIZE;
+/// Offset within the region of the commit marker, at the start of the header sector's last page.
+pub const COMMIT_OFFSET: usize = HEADER_SIZE - 256;
+/// Changed whenever the encoding of [`StoredMovie`] does, so a movie stored by older firmware isn't misread.
+pub const MAGIC: [u8; 4] = *b"VTA2";
+pub const COMMIT: [u8; 4] = *b"DONE";
+
+/// Header of the stored movie.
+#[derive(Debug, PartialEq, Eq, Clone, Encode, Decode)]
+pub struct StoredMovie {
+    /// System the movie is for, as its protocol value.
+    pub system: u8,
+    /// Number of frames of input.
+    pub length: u32,
+    pub latch_filter_us: u32,
+    pub use_initial_reset: bool,
+    pub wait_for_console: bool,
+    /// Starts the replay when the console is powered on, without needing a host.
+    pub autostart: bool,
+    pub timing: TransitionTiming,
+    pub transitions: Vec<TransitionData>,
+}
+
+/// Encodes the header, up to but not including the commit marker. Returns None if it doesn't fit before the
+/// marker.
+pub fn encode_header(movie: &StoredMovie) -> Option<Vec<u8>> {
+    let payload = bincode::encode_to_vec(movie, BINCODE_CONFIG).ok()?;
+    if MAGIC.len() + 4 + payload.len() > COMMIT_OFFSET {
+        return None;
+    }
+    
+    let mut data = MAGIC.to_vec();
+    data.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    data.extend_from_slice(&payload);
+    
+    Some(data)
+}
+
+/// Decodes the header sector, if it holds a header that was completely written.
+pub fn decode_header(sector: &[u8]) -> Option<StoredMovie> {
+    if sector.len() < HEADER_SIZE || sector[0..4] != MAGIC || sector[COMMIT_OFFSET..(COMMIT_OFFSET + 4)] != COMMIT {
+        return None;
+    }
+    
+    let len = u32::from_le_bytes(sector[4..8].try_into().unwrap()) as usize;
+    if len > COMMIT_OFFSET - 8 {
+        return None;
+    }
+    
+    bincode::decode_from_slice(&sector[8..(8 + len)], BINCODE_CONFIG)
+        .ok()
+        .map(|(movie, _)| movie)
+}
+
+/// Checks if `size` bytes of inputs fit in the region after the header.
+pub fn inputs_fit(size: usize) -> bool {
+    INPUTS_OFFSET + size <= REGION_SIZE
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn movie() -> StoredMovie {
+        StoredMovie {
+            system: 0x01,
+            length: 1000,
+            latch_filter_us: 8000,
+            use_initial_reset: true,
+            wait_for_console: false,
+            autostart: true,
+            timing: TransitionTiming::new(),
+            transitions: vec![TransitionData::new(500, 0x01, 0x01)],
+        }
+    }
+    
+    /// An erased sector, with `header` written at the start, and the commit marker if `commit` is set.
+    fn sector(header: &[u8], commit: bool) -> Vec<u8> {
+        let mut sector = vec![0xFF; HEADER_SIZE];
+        sector[..header.len()].copy_from_slice(header);
+        if commit {
+            sector[COMMIT_OFFSET..(COMMIT_OFFSET + 4)].copy_from_slice(&COMMIT);
+        }
+        
+        sector
+    }
+    
+    #[test]
+    fn committed_header_round_trips() {
+        let header = encode_header(&movie()).unwrap();
+        
+        assert_eq!(decode_header(&sector(&header, true)), Some(movie()));
+    }
+    
+    #[test]
+    fn header_without_commit_marker_is_ignored() {
+        let header = encode_header(&movie()).unwrap();
+        
+        assert_eq!(decode_header(&sector(&header, false)), None);
+    }
+    
+    #[test]
+    fn partly_written_header_is_ignored() {
+        // Programming stopped partway through the header, leaving the rest of the sector erased
+        let header = encode_header(&movie()).unwrap();
+        
+        assert_eq!(decode_header(&sector(&header[..header.len() / 2], false)), None);
+        assert_eq!(decode_header(&sector(&header[..4], false)), None);
+        assert_eq!(decode_header(&sector(&[], false)), None);
+    }
+    
+    #[test]
+    fn invalid_headers_are_ignored() {
+        let mut header = encode_header(&movie()).unwrap();
+        header[0] = b'X';
+        assert_eq!(decode_header(&sector(&header, true)), None);
+        
+        // A length running into the commit marker
+        let mut header = encode_header(&movie()).unwrap();
+        header[4..8].copy_from_slice(&(COMMIT_OFFSET as u32).to_le_bytes());
+        assert_eq!(decode_header(&sector(&header, true)), None);
+        
+        assert_eq!(decode_header(&sector(&MAGIC, true)[..HEADER_SIZE - 1]), None);
+    }
+    
+    #[test]
+    fn header_must_fit_before_the_commit_marker() {
+        let mut movie = movie();
+        movie.transitions = vec![TransitionData::new(u64::MAX, 0x01, 0x01); COMMIT_OFFSET / 8];
+        
+        assert_eq!(encode_header(&movie), None);
+    }
+    
+    #[test]
+    fn inputs_fit_after_the_header() {
+        assert!(inputs_fit(REGION_SIZE - HEADER_SIZE));
+        assert!(!inputs_fit(REGION_SIZE - HEADER_SIZE + 1));
+    }
+}
@@ -0,0 +1,57 @@
+//! Transitions as they're sent to the device, either over the host protocol or in a stored movie.
+
+use bincode::{Decode, Encode};
+
+/// A transition performed once the console has consumed `index` inputs, if the index kind is a frame.
+#[derive(Debug, PartialEq, Eq, Clone, Encode, Decode)]
+pub struct TransitionData {
+    index: u64,
+    index_kind: u8,
+    transition_kind: u8,
+}
+impl TransitionData {
+    pub fn new(index: u64, index_kind: u8, transition_kind: u8) -> Self { Self {
+        index,
+        index_kind,
+        transition_kind,
+    }}
+    
+    pub fn index(&self) -> u64 {
+        self.index
+    }
+    
+    pub fn index_kind(&self) -> u8 {
+        self.index_kind
+    }
+    
+    pub fn transition_kind(&self) -> u8 {
+        self.transition_kind
+    }
+}
+
+/// Durations of each step of a transition.
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Encode, Decode)]
+pub struct TransitionTiming {
+    /// Time in microseconds that the console's reset line is held during a soft reset.
+    pub reset_hold_us: u32,
+    /// Time in microseconds after releasing reset, before controller emulation is re-armed.
+    pub reset_settle_us: u32,
+    /// Time in milliseconds that the console's reset header is held during a hard reset.
+    pub hard_reset_hold_ms: u32,
+    /// Time in milliseconds after releasing the reset header, to let the console boot before controller
+    /// emulation is re-armed.
+    pub hard_reset_boot_ms: u32,
+}
+impl TransitionTiming {
+    pub const fn new() -> Self { Self {
+        reset_hold_us: 33333,
+        reset_settle_us: 67,
+        hard_reset_hold_ms: 500,
+        hard_reset_boot_ms: 100,
+    }}
+}
+impl Default for TransitionTiming {
+    fn default() -> Self {
+        Self::new()
+    }
+}
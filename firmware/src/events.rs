@@ -0,0 +1,95 @@
+use alloc::vec::Vec;
+use bincode::{Decode, Encode};
+use rp2040_pac::TIMER;
+use crate::hal::sync::{LOCK_EVENTS, SpinMutex};
+use crate::replaycore;
+use crate::replaycore::{REPLAY_STATE, VeritasMode};
+use crate::utilcore::comms::FailsafeReason;
+use veritas_core::events::EventRing;
+
+/// Events kept in RAM, after which the oldest are overwritten.
+const LOG_LEN: usize = 256;
+
+/// Something worth knowing about when looking back at a replay.
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Encode, Decode)]
+pub enum Event {
+    ModeChanged(VeritasMode),
+    /// A transition (by its TASD kind) started, once `index` inputs had been consumed.
+    Transition {
+        kind: u8,
+        index: u32,
+    },
+    /// The console needed an input that hadn't arrived yet, once `index` inputs had been consumed. The
+    /// controllers read as released instead.
+    Underrun {
+        index: u32,
+    },
+    /// The console was reset before the replay began.
+    InitialReset,
+    /// The console was detected turning on (true) or off.
+    ConsoleDetected(bool),
+    /// A command from the host that couldn't be decoded.
+    BadCommand,
+    /// A response that didn't fit in the transmit buffer, and was dropped.
+    ResponseTooLarge,
+    Failsafe(FailsafeReason),
+}
+
+/// An event, and when it happened in microseconds since boot.
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Encode, Decode)]
+pub struct EventRecord {
+    pub timestamp_us: u64,
+    pub event: Event,
+}
+
+static LOG: SpinMutex<EventRing<EventRecord, LOG_LEN>> = SpinMutex::new(LOCK_EVENTS, EventRing::new());
+
+/// Whether the console was powered when last checked. Do not use outside of CORE1!
+static mut CONSOLE_DETECTED: bool = false;
+
+/// Full 64-bit timer value. The high half is read again, in case the low half wrapped in between.
+#[inline(always)]
+fn now_us() -> u64 {
+    let timer = unsafe { &*TIMER::ptr() };
+    loop {
+        let high = timer.timerawh.read().bits();
+        let low = timer.timerawl.read().bits();
+        if timer.timerawh.read().bits() == high {
+            return ((high as u64) << 32) | low as u64;
+        }
+    }
+}
+
+/// Adds an event to the log, overwriting the oldest if it's full. Can be used from either core, including
+/// from interrupts.
+pub fn record(event: Event) {
+    let record = EventRecord { timestamp_us: now_us(), event };
+    
+    LOG.lock(|log| log.push(record));
+}
+
+/// Returns up to `max` records, starting from record number `from` (or the oldest still held, if it's been
+/// overwritten), along with the number of the first one returned. If there are none, that's the number the
+/// next event will get.
+pub fn read(from: u32, max: usize) -> (u32, Vec<EventRecord>) {
+    LOG.lock(|log| log.read(from, max))
+}
+
+/// Records that the console needed an input before it arrived.
+pub fn record_underrun() {
+    let index = REPLAY_STATE.lock(|state| state.index_cur);
+    
+    record(Event::Underrun { index });
+}
+
+/// Records the console being powered on or off. Must be called periodically from CORE1.
+pub fn check_console() {
+    let detected = replaycore::console_detected();
+    
+    unsafe {
+        if detected != CONSOLE_DETECTED {
+            CONSOLE_DETECTED = detected;
+            record(Event::ConsoleDetected(detected));
+        }
+    }
+}
\ No newline at end of file
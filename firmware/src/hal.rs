@@ -1,5 +1,8 @@
 
+pub mod flash;
 pub mod gpio;
 pub mod interrupts;
 pub mod pio;
+pub mod sync;
+pub mod timer;
 pub mod uart;
\ No newline at end of file
@@ -0,0 +1,202 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+use rp2040_hal::rom_data;
+
+/// Base address of the memory-mapped (XIP) external flash.
+pub const XIP_BASE: u32 = 0x10000000;
+pub const SECTOR_SIZE: usize = 4096;
+pub const PAGE_SIZE: usize = 256;
+
+const SECTOR_ERASE_CMD: u8 = 0x20;
+const READ_UNIQUE_ID_CMD: u8 = 0x4B;
+/// Number of times to check if the other core has parked, before giving up.
+const LOCKOUT_ATTEMPTS: u32 = 1_000_000;
+
+/// Set by the core performing a flash operation, until it's completed.
+static LOCKOUT_REQUESTED: AtomicBool = AtomicBool::new(false);
+/// Set by the other core once it's parked in RAM, and safe from losing access to flash.
+static LOCKOUT_PARKED: AtomicBool = AtomicBool::new(false);
+
+/// Staging buffer, as the data being programmed can't be read from flash mid-operation.
+static mut PAGE_BUFFER: [u8; SECTOR_SIZE] = [0xFF; SECTOR_SIZE];
+/// Copy of the second stage bootloader, used to restore fast XIP after an operation.
+static mut BOOT2_COPY: [u32; 64] = [0; 64];
+
+/// Chip select control for the flash (IO_QSPI GPIO_QSPI_SS_CTRL)
+const QSPI_SS_CTRL: *mut u32 = 0x4001800C as *mut u32;
+const QSPI_SS_OUTOVER_LSB: u32 = 8;
+const QSPI_SS_OUTOVER_LOW: u32 = 2;
+const QSPI_SS_OUTOVER_HIGH: u32 = 3;
+const SSI_SR: *const u32 = 0x18000028 as *const u32;
+const SSI_SR_TFNF: u32 = 1 << 1;
+const SSI_SR_RFNE: u32 = 1 << 3;
+const SSI_DR0: *mut u32 = 0x18000060 as *mut u32;
+
+struct RomFunctions {
+    connect_internal_flash: unsafe extern "C" fn(),
+    flash_exit_xip: unsafe extern "C" fn(),
+    flash_range_erase: unsafe extern "C" fn(u32, usize, u32, u8),
+    flash_range_program: unsafe extern "C" fn(u32, *const u8, usize),
+    flash_flush_cache: unsafe extern "C" fn(),
+}
+
+impl RomFunctions {
+    /// Looks up the flash functions in the bootrom. Must be done while flash is still accessible.
+    fn lookup() -> Self {
+        unsafe { Self {
+            connect_internal_flash: core::mem::transmute(rom_data::connect_internal_flash::ptr()),
+            flash_exit_xip: core::mem::transmute(rom_data::flash_exit_xip::ptr()),
+            flash_range_erase: core::mem::transmute(rom_data::flash_range_erase::ptr()),
+            flash_range_program: core::mem::transmute(rom_data::flash_range_program::ptr()),
+            flash_flush_cache: core::mem::transmute(rom_data::flash_flush_cache::ptr()),
+        }}
+    }
+}
+
+/// Returns a pointer to the memory-mapped contents of flash, at the given offset from the start of flash.
+#[inline(always)]
+pub fn xip_ptr(offset: u32) -> *const u8 {
+    (XIP_BASE + offset) as *const u8
+}
+
+/// Checks if the other core is requesting exclusive access to flash, and if so, parks this core in RAM
+/// until the operation has completed.
+///
+/// Must be called periodically by whichever core isn't performing flash operations.
+pub fn check_lockout() {
+    if LOCKOUT_REQUESTED.load(Ordering::Acquire) {
+        cortex_m::interrupt::free(|_| unsafe { park() });
+    }
+}
+
+#[link_section = ".ram_code"]
+#[inline(never)]
+unsafe fn park() {
+    LOCKOUT_PARKED.store(true, Ordering::Release);
+    while LOCKOUT_REQUESTED.load(Ordering::Acquire) {
+        cortex_m::asm::nop();
+    }
+    LOCKOUT_PARKED.store(false, Ordering::Release);
+}
+
+/// Erases the sector containing `offset` (if `erase` is set) and programs `data` into flash starting at
+/// `offset`.
+///
+/// `offset` must be page aligned, and `data` must not extend beyond the end of the sector. Returns false
+/// if the other core didn't respond to the lockout request (it must be calling [`check_lockout`]).
+pub fn write(offset: u32, data: &[u8], erase: bool) -> bool {
+    let sector = offset & !(SECTOR_SIZE as u32 - 1);
+    let len = (data.len() + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+    
+    unsafe {
+        PAGE_BUFFER[..len].fill(0xFF);
+        PAGE_BUFFER[..data.len()].copy_from_slice(data);
+        
+        copy_boot2();
+        let rom = RomFunctions::lookup();
+        
+        cortex_m::interrupt::free(|_| {
+            LOCKOUT_REQUESTED.store(true, Ordering::Release);
+            
+            let mut attempts = 0u32;
+            while !LOCKOUT_PARKED.load(Ordering::Acquire) {
+                attempts += 1;
+                if attempts >= LOCKOUT_ATTEMPTS {
+                    LOCKOUT_REQUESTED.store(false, Ordering::Release);
+                    return false;
+                }
+            }
+            
+            program(&rom, sector, if erase { SECTOR_SIZE } else { 0 }, offset, PAGE_BUFFER.as_ptr(), len);
+            
+            LOCKOUT_REQUESTED.store(false, Ordering::Release);
+            true
+        })
+    }
+}
+
+/// Performs the actual flash operation. Nothing in here may touch flash, including compiler intrinsics.
+#[link_section = ".ram_code"]
+#[inline(never)]
+unsafe fn program(rom: &RomFunctions, erase_addr: u32, erase_len: usize, addr: u32, data: *const u8, len: usize) {
+    (rom.connect_internal_flash)();
+    (rom.flash_exit_xip)();
+    
+    if erase_len != 0 {
+        (rom.flash_range_erase)(erase_addr, erase_len, SECTOR_SIZE as u32, SECTOR_ERASE_CMD);
+    }
+    if len != 0 {
+        (rom.flash_range_program)(addr, data, len);
+    }
+    
+    (rom.flash_flush_cache)();
+    enter_xip();
+}
+
+/// Reads the 64-bit unique ID of the flash chip, which also serves as a unique ID for the board.
+///
+/// Must be called before the other core is started, as no lockout is performed.
+pub fn unique_id() -> u64 {
+    let mut buf = [0u8; 13];
+    buf[0] = READ_UNIQUE_ID_CMD;
+    
+    unsafe {
+        copy_boot2();
+        let rom = RomFunctions::lookup();
+        
+        cortex_m::interrupt::free(|_| transfer(&rom, buf.as_mut_ptr(), buf.len()));
+    }
+    
+    // The command is followed by 4 dummy bytes, before the ID is shifted out.
+    u64::from_be_bytes(buf[5..13].try_into().unwrap())
+}
+
+/// Sends a raw command to the flash, replacing the contents of `buf` with the bytes received.
+#[link_section = ".ram_code"]
+#[inline(never)]
+unsafe fn transfer(rom: &RomFunctions, buf: *mut u8, len: usize) {
+    (rom.connect_internal_flash)();
+    (rom.flash_exit_xip)();
+    
+    set_chip_select(false);
+    
+    // The SSI FIFOs are 16 entries deep, so limit how many bytes are in flight at once.
+    let mut tx = 0;
+    let mut rx = 0;
+    while rx < len {
+        let status = core::ptr::read_volatile(SSI_SR);
+        if status & SSI_SR_TFNF != 0 && tx < len && tx - rx < 14 {
+            core::ptr::write_volatile(SSI_DR0, *buf.add(tx) as u32);
+            tx += 1;
+        }
+        if status & SSI_SR_RFNE != 0 {
+            *buf.add(rx) = core::ptr::read_volatile(SSI_DR0) as u8;
+            rx += 1;
+        }
+    }
+    
+    set_chip_select(true);
+    
+    (rom.flash_flush_cache)();
+    enter_xip();
+}
+
+#[link_section = ".ram_code"]
+#[inline(always)]
+unsafe fn set_chip_select(high: bool) {
+    let value = if high { QSPI_SS_OUTOVER_HIGH } else { QSPI_SS_OUTOVER_LOW };
+    let ctrl = core::ptr::read_volatile(QSPI_SS_CTRL) & !(0b11 << QSPI_SS_OUTOVER_LSB);
+    core::ptr::write_volatile(QSPI_SS_CTRL, ctrl | (value << QSPI_SS_OUTOVER_LSB));
+}
+
+unsafe fn copy_boot2() {
+    let boot2 = core::slice::from_raw_parts(XIP_BASE as *const u32, BOOT2_COPY.len());
+    BOOT2_COPY.copy_from_slice(boot2);
+}
+
+/// Re-runs the second stage bootloader from RAM, to return to fast XIP mode.
+#[link_section = ".ram_code"]
+#[inline(always)]
+unsafe fn enter_xip() {
+    let boot2: unsafe extern "C" fn() = core::mem::transmute((BOOT2_COPY.as_ptr() as *const u8).offset(1));
+    boot2();
+}
@@ -226,4 +226,13 @@ pub fn set_state(gpio: usize, state: bool) {
     } else {
         set_low(gpio);
     }
+}
+
+/// Pin levels as seen by the SIO, for code written against `veritas_core::hal::Gpio`.
+pub struct Pins;
+impl veritas_core::hal::Gpio for Pins {
+    #[inline(always)]
+    fn levels(&self) -> u32 {
+        unsafe { (*SIO::ptr()).gpio_in.read().bits() }
+    }
 }
\ No newline at end of file
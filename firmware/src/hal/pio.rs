@@ -1,6 +1,7 @@
-use pio::{InstructionOperands, Program, RP2040_MAX_PROGRAM_SIZE};
+use pio::{InstructionOperands, JmpCondition, Program, RP2040_MAX_PROGRAM_SIZE};
 use rp2040_pac::{PIO0, PIO1};
 use num_enum::IntoPrimitive;
+use veritas_core::pio as core_pio;
 
 #[derive(Debug, PartialEq, Eq, Copy, Clone,)]
 pub enum PioSel {
@@ -29,9 +30,9 @@ pub enum PioOption {
     OutEnSel(u8),
     InlineOutEn(bool),
     OutSticky(bool),
-    /// Local program address of `.wrap`. Note: Developer must account for program origin (WrapTop = .wrap + origin)
+    /// Address of `.wrap` in instruction memory. [`install`] sets it for the state machines it claims.
     WrapTop(u8),
-    /// Local program address of `.wrap_target`. Note: Developer must account for program origin (WrapBottom = .wrap_target + origin)
+    /// Address of `.wrap_target` in instruction memory. [`install`] sets it for the state machines it claims.
     WrapBottom(u8),
     MovStatus(MovStatusConfig),
     FJoin(FJoinConfig),
@@ -81,24 +82,82 @@ pub enum FJoinConfig {
     OnlyRx,
 }
 
-/// Writes a PIO program into memory.
-/// 
-/// Use `pio_sel` to specify which PIO core to install the program into: false = PIO0, true = PIO1
-pub fn install_program(program: &Program<{ RP2040_MAX_PROGRAM_SIZE }>, pio_sel: PioSel) {
+/// Instruction memory slots in use in each PIO block, with bit n for address n. Do not use outside of CORE0!
+static mut USED_SLOTS: [u32; 2] = [0; 2];
+/// State machines claimed in each PIO block, as `SmSel` bits. Do not use outside of CORE0!
+static mut USED_SMS: [u8; 2] = [0; 2];
+
+/// A program loaded by [`install`], and the state machines claimed to run it. Give it back to [`uninstall`]
+/// once they're done, so the space can be reused.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ProgramHandle {
+    pio: PioSel,
+    /// Address of the program's first instruction.
+    offset: u8,
+    len: u8,
+    sm_mask: u8,
+}
+impl ProgramHandle {
+    /// Address in instruction memory of a location in the program, such as one of its public defines.
+    pub fn address(&self, local: u8) -> u8 {
+        self.offset + local
+    }
+}
+
+/// Loads a program wherever it fits in a PIO block, and claims state machines to run it. Jumps are moved
+/// along with the program, and each state machine is set to wrap where the program does and left at its first
+/// instruction, so the rest of its configuration is up to the caller. Programs with an `.origin` are only
+/// loaded there.
+///
+/// Returns None if there isn't room for the program, or one of the state machines is already claimed. Must
+/// only be called from CORE0.
+pub fn install(program: &Program<{ RP2040_MAX_PROGRAM_SIZE }>, pio_sel: PioSel, sm_sels: &[SmSel]) -> Option<ProgramHandle> {
+    let block = pio_sel as usize;
+    let sm_mask = sm_sels.iter().fold(0, |mask, sel| mask | u8::from(*sel));
+    let len = program.code.len();
+    
     unsafe {
-        if let Some(origin) = program.origin {
-            if origin > 0 {
-                panic!("PIO max sized program must have origin=0.");
-            }
+        if USED_SMS[block] & sm_mask != 0 {
+            return None;
         }
+        let offset = core_pio::find_offset(USED_SLOTS[block], len, program.origin)?;
+        USED_SLOTS[block] |= core_pio::slot_mask(offset, len);
+        USED_SMS[block] |= sm_mask;
+        
         let pio = match pio_sel {
             PioSel::Zero => &(*PIO0::ptr()),
             PioSel::One => &(*PIO1::ptr()),
         };
+        for (i, instr) in program.code.iter().enumerate() {
+            pio.instr_mem[offset as usize + i].write(|w| w.instr_mem0().bits(core_pio::relocate(*instr, offset)));
+        }
         
-        for i in 0..program.code.len() {
-            pio.instr_mem[i].write(|w| w.instr_mem0().bits(program.code[i]));
+        let handle = ProgramHandle { pio: pio_sel, offset, len: len as u8, sm_mask };
+        for sm in sm_sels {
+            configure(pio_sel, *sm, &[
+                PioOption::WrapBottom(handle.address(program.wrap.target)),
+                PioOption::WrapTop(handle.address(program.wrap.source)),
+            ]);
+            exec(pio_sel, *sm, InstructionOperands::JMP { condition: JmpCondition::Always, address: offset });
         }
+        
+        Some(handle)
+    }
+}
+
+/// Stops a program's state machines, and frees them and the program's instruction memory. Must only be called
+/// from CORE0.
+pub fn uninstall(handle: ProgramHandle) {
+    let block = handle.pio as usize;
+    
+    unsafe {
+        match handle.pio {
+            PioSel::Zero => &(*PIO0::ptr()),
+            PioSel::One => &(*PIO1::ptr()),
+        }.ctrl.modify(|r, w| w.sm_enable().bits(r.sm_enable().bits() & !handle.sm_mask));
+        
+        USED_SLOTS[block] &= !core_pio::slot_mask(handle.offset, handle.len as usize);
+        USED_SMS[block] &= !handle.sm_mask;
     }
 }
 
@@ -224,6 +283,67 @@ pub fn stop_multiple(pio_sel: PioSel, sm_sels: &[SmSel]) {
     }
 }
 
+/// Clears a state machine's internal state (shift counters, delays, stalled instructions). The program
+/// counter and scratch registers are left as they are.
+pub fn restart(pio_sel: PioSel, sm_sel: SmSel) {
+    unsafe {
+        match pio_sel {
+            PioSel::Zero => &(*PIO0::ptr()),
+            PioSel::One => &(*PIO1::ptr()),
+        }.ctrl.modify(|_, w| w.sm_restart().bits(u8::from(sm_sel)));
+    }
+}
+
+/// Discards the contents of both of a state machine's FIFOs.
+pub fn clear_fifos(pio_sel: PioSel, sm_sel: SmSel) {
+    unsafe {
+        let pio = match pio_sel {
+            PioSel::Zero => &(*PIO0::ptr()),
+            PioSel::One => &(*PIO1::ptr()),
+        };
+        let sm = match sm_sel {
+            SmSel::Zero => &pio.sm[0],
+            SmSel::One => &pio.sm[1],
+            SmSel::Two => &pio.sm[2],
+            SmSel::Three => &pio.sm[3],
+        };
+        
+        // Changing FJOIN_RX flushes the FIFOs, so toggling it twice leaves the configuration unchanged.
+        sm.sm_shiftctrl.modify(|r, w| w.fjoin_rx().bit(!r.fjoin_rx().bit()));
+        sm.sm_shiftctrl.modify(|r, w| w.fjoin_rx().bit(!r.fjoin_rx().bit()));
+    }
+}
+
+/// Routes one of the PIO's IRQ flags (0-3) to its first system interrupt (PIOx_IRQ_0).
+pub fn enable_irq0(pio_sel: PioSel, flag: u8) {
+    unsafe {
+        match pio_sel {
+            PioSel::Zero => &(*PIO0::ptr()),
+            PioSel::One => &(*PIO1::ptr()),
+        }.sm_irq[0].irq_inte.modify(|r, w| w.bits(r.bits() | (1 << (8 + flag))));
+    }
+}
+
+pub fn disable_irq0(pio_sel: PioSel, flag: u8) {
+    unsafe {
+        match pio_sel {
+            PioSel::Zero => &(*PIO0::ptr()),
+            PioSel::One => &(*PIO1::ptr()),
+        }.sm_irq[0].irq_inte.modify(|r, w| w.bits(r.bits() & !(1 << (8 + flag))));
+    }
+}
+
+/// Clears one of the PIO's IRQ flags (0-7).
+#[inline(always)]
+pub fn clear_irq(pio_sel: PioSel, flag: u8) {
+    unsafe {
+        match pio_sel {
+            PioSel::Zero => &(*PIO0::ptr()),
+            PioSel::One => &(*PIO1::ptr()),
+        }.irq.write(|w| w.bits(1 << flag));
+    }
+}
+
 #[inline(always)]
 pub fn is_rx_empty(pio_sel: PioSel, sm_sel: SmSel) -> bool {
     unsafe {
@@ -328,4 +448,32 @@ pub fn exec(pio_sel: PioSel, sm_sel: SmSel, instr: InstructionOperands) {
             SmSel::Three => &pio.sm[3],
         }.sm_instr.write(|w| w.bits(instr.encode() as u32))
     }
+}
+
+/// A single state machine, for code written against `veritas_core::hal::StateMachine`.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct StateMachine {
+    pub pio: PioSel,
+    pub sm: SmSel,
+}
+impl veritas_core::hal::StateMachine for StateMachine {
+    #[inline(always)]
+    fn fifo_read(&mut self) -> Option<u32> {
+        fifo_read(self.pio, self.sm)
+    }
+    
+    #[inline(always)]
+    fn fifo_write(&mut self, data: u32) -> bool {
+        fifo_write(self.pio, self.sm, data)
+    }
+    
+    #[inline(always)]
+    fn is_tx_empty(&self) -> bool {
+        is_tx_empty(self.pio, self.sm)
+    }
+    
+    #[inline(always)]
+    fn jump(&mut self, address: u8) {
+        exec(self.pio, self.sm, InstructionOperands::JMP { condition: JmpCondition::Always, address });
+    }
 }
\ No newline at end of file
@@ -0,0 +1,99 @@
+use core::cell::UnsafeCell;
+use core::sync::atomic::{compiler_fence, Ordering};
+use rp2040_pac::SIO;
+
+/// Spinlock used by rp2040-hal's critical section implementation.
+const CRITICAL_SECTION_LOCK: usize = 31;
+
+pub const LOCK_REPLAY_STATE: usize = 0;
+pub const LOCK_CONTROLLER_PAKS: usize = 1;
+pub const LOCK_LOG: usize = 2;
+pub const LOCK_EVENTS: usize = 3;
+
+/// Data shared between both cores, guarded by one of the SIO's hardware spinlocks.
+///
+/// Interrupts are disabled on the locking core for as long as the lock is held, so keep the closure short,
+/// and never lock the same mutex again from inside it.
+pub struct SpinMutex<T> {
+    lock: usize,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for SpinMutex<T> {}
+
+impl<T> SpinMutex<T> {
+    /// Each mutex needs its own spinlock, which no other code may claim.
+    pub const fn new(lock: usize, data: T) -> Self {
+        assert!(lock < CRITICAL_SECTION_LOCK, "spinlock is reserved");
+        
+        Self {
+            lock,
+            data: UnsafeCell::new(data),
+        }
+    }
+    
+    #[inline(always)]
+    pub fn lock<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        cortex_m::interrupt::free(|_| unsafe {
+            let sio = &*SIO::ptr();
+            
+            // Reading a spinlock claims it, returning 0 if it was already claimed
+            while sio.spinlock[self.lock].read().bits() == 0 {}
+            compiler_fence(Ordering::Acquire);
+            
+            let result = f(&mut *self.data.get());
+            
+            compiler_fence(Ordering::Release);
+            sio.spinlock[self.lock].write_with_zero(|w| w.bits(1));
+            
+            result
+        })
+    }
+}
+
+/// Sends a word to the other core, waiting for room in its FIFO if needed.
+#[inline(always)]
+pub fn fifo_push(data: u32) {
+    unsafe {
+        let sio = &*SIO::ptr();
+        
+        while sio.fifo_st.read().rdy().bit_is_clear() {}
+        compiler_fence(Ordering::Release);
+        sio.fifo_wr.write(|w| w.bits(data));
+        cortex_m::asm::sev();
+    }
+}
+
+/// Takes a word sent by the other core, if there is one.
+#[inline(always)]
+pub fn fifo_pop() -> Option<u32> {
+    unsafe {
+        let sio = &*SIO::ptr();
+        
+        if sio.fifo_st.read().vld().bit_is_set() {
+            let data = sio.fifo_rd.read().bits();
+            compiler_fence(Ordering::Acquire);
+            
+            Some(data)
+        } else {
+            None
+        }
+    }
+}
+
+/// Waits for a word from the other core.
+#[inline(always)]
+pub fn fifo_pop_blocking() -> u32 {
+    loop {
+        if let Some(data) = fifo_pop() {
+            return data;
+        }
+    }
+}
+
+/// Clears the error flags of this core's FIFO, which would otherwise keep its interrupt asserted.
+pub fn fifo_clear_errors() {
+    unsafe {
+        (*SIO::ptr()).fifo_st.write(|w| w.roe().set_bit().wof().set_bit());
+    }
+}
\ No newline at end of file
@@ -0,0 +1,10 @@
+use rp2040_pac::TIMER;
+
+/// The system timer's free-running microsecond counter.
+pub struct SystemTimer;
+impl veritas_core::hal::Timer for SystemTimer {
+    #[inline(always)]
+    fn now_us(&self) -> u32 {
+        unsafe { (*TIMER::ptr()).timerawl.read().bits() }
+    }
+}
\ No newline at end of file
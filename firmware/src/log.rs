@@ -0,0 +1,154 @@
+use core::fmt::{Arguments, Write};
+use bincode::{Decode, Encode};
+use heapless::{Deque, String};
+use heapless::spsc::{Consumer, Producer, Queue};
+use crate::hal::sync::{LOCK_LOG, SpinMutex};
+
+/// Longest message relayed to the host. Anything beyond it is cut off.
+pub const MAX_MESSAGE_LEN: usize = 96;
+/// Messages held for the host, after which the oldest are dropped. They're sent along with the response to
+/// the host's next command.
+const QUEUE_LEN: usize = 16;
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Encode, Decode)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+pub struct LogMessage {
+    pub level: LogLevel,
+    pub text: String<MAX_MESSAGE_LEN>,
+}
+
+static QUEUE: SpinMutex<Deque<LogMessage, QUEUE_LEN>> = SpinMutex::new(LOCK_LOG, Deque::new());
+
+/// Messages logged by core0 from interrupts, waiting for core1 to format them.
+static mut DEFERRED_QUEUE: Queue<(Code, [u32; 2]), QUEUE_LEN> = Queue::new();
+/// Producer end of the deferred messages. Do not use outside of CORE0!
+static mut DEFERRED_PRODUCER: Option<Producer<'static, (Code, [u32; 2]), QUEUE_LEN>> = None;
+/// Consumer end of the deferred messages. Do not use outside of CORE1!
+static mut DEFERRED_CONSUMER: Option<Consumer<'static, (Code, [u32; 2]), QUEUE_LEN>> = None;
+
+/// Messages logged where there's no time to format text, or the log's lock can't be taken: interrupts, and
+/// code that holds another lock. Only the code and up to 2 arguments are queued.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Code {
+    /// A transition (by its TASD kind) started, at an input index.
+    TransitionStarted,
+    /// Every input in the movie has been consumed.
+    ReplayEnded,
+}
+impl Code {
+    fn level(self) -> LogLevel {
+        match self {
+            Code::TransitionStarted | Code::ReplayEnded => LogLevel::Info,
+        }
+    }
+    
+    fn write(self, text: &mut String<MAX_MESSAGE_LEN>, args: [u32; 2]) {
+        match self {
+            Code::TransitionStarted => write!(text, "transition {} started at {}", args[0], args[1]),
+            Code::ReplayEnded => write!(text, "Replay ended!"),
+        }.ok();
+    }
+}
+
+/// Splits the deferred messages between the cores. Must be called once, before core1 is started.
+pub unsafe fn split_deferred() {
+    let (producer, consumer) = DEFERRED_QUEUE.split();
+    DEFERRED_PRODUCER = Some(producer);
+    DEFERRED_CONSUMER = Some(consumer);
+}
+
+/// Logs a message through defmt, and queues its code for core1 to relay to the host. Must only be called from
+/// CORE0, but unlike the logging macros, it's safe in interrupts and while holding a lock. Messages are
+/// dropped if core1 has fallen behind.
+#[inline(always)]
+pub fn defer(code: Code, args: [u32; 2]) {
+    match code {
+        Code::TransitionStarted => defmt::info!("transition {} started at {}", args[0], args[1]),
+        Code::ReplayEnded => defmt::info!("Replay ended!"),
+    }
+    
+    // Thread code on core0 can be interrupted by an interrupt that also logs
+    cortex_m::interrupt::free(|_| unsafe {
+        if let Some(producer) = DEFERRED_PRODUCER.as_mut() {
+            producer.enqueue((code, args)).ok();
+        }
+    });
+}
+
+/// Formats messages deferred by core0, and queues them to be relayed. Must be called periodically from CORE1.
+pub fn relay_deferred() {
+    let Some(consumer) = (unsafe { DEFERRED_CONSUMER.as_mut() }) else {
+        return;
+    };
+    
+    while let Some((code, args)) = consumer.dequeue() {
+        let mut text = String::new();
+        code.write(&mut text, args);
+        
+        push(LogMessage { level: code.level(), text });
+    }
+}
+
+/// Queues a message to be relayed to the host. Used by the logging macros, from either core, but not from
+/// interrupts or while holding another lock. Use [`defer`] there instead.
+pub fn relay(level: LogLevel, args: Arguments) {
+    let mut text = String::new();
+    text.write_fmt(args).ok();
+    
+    push(LogMessage { level, text });
+}
+
+fn push(message: LogMessage) {
+    QUEUE.lock(|queue| {
+        if queue.is_full() {
+            queue.pop_front();
+        }
+        queue.push_back(message).ok();
+    });
+}
+
+/// Takes the oldest message that hasn't been relayed yet.
+pub fn take() -> Option<LogMessage> {
+    QUEUE.lock(|queue| queue.pop_front())
+}
+
+// Each of these logs through defmt, and relays the message to the host. The arguments are also formatted
+// with `core::fmt`, so they must implement `Display` or `Debug` as well as `defmt::Format`.
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)*) => {{
+        defmt::error!($($arg)*);
+        $crate::log::relay($crate::log::LogLevel::Error, format_args!($($arg)*));
+    }};
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => {{
+        defmt::warn!($($arg)*);
+        $crate::log::relay($crate::log::LogLevel::Warn, format_args!($($arg)*));
+    }};
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => {{
+        defmt::info!($($arg)*);
+        $crate::log::relay($crate::log::LogLevel::Info, format_args!($($arg)*));
+    }};
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => {{
+        defmt::debug!($($arg)*);
+        $crate::log::relay($crate::log::LogLevel::Debug, format_args!($($arg)*));
+    }};
+}
\ No newline at end of file
@@ -8,7 +8,6 @@
 extern crate alloc;
 extern crate cortex_m_rt;
 
-use defmt::info;
 use defmt_rtt as _;
 use fugit::HertzU32;
 use panic_probe as _;
@@ -25,11 +24,14 @@ use rp2040_hal::rom_data::{memcpy, memcpy44};
 use rp2040_hal::sio::spinlock_reset;
 use usb_device::class_prelude::UsbBusAllocator;
 use crate::allocator::ALLOCATOR;
-use crate::hal::gpio;
-use crate::hal::gpio::{PIN_CNT_18, PIN_CNT_18_DIR};
+use crate::hal::{flash, gpio};
+use crate::hal::gpio::{PIN_CNT_18, PIN_CNT_18_DIR, PIN_CON_RESET, PIN_DETECT};
+use crate::utilcore::displays;
 
 mod allocator;
+mod events;
 mod hal;
+mod log;
 mod replaycore;
 mod systems;
 mod utilcore;
@@ -117,15 +119,36 @@ pub unsafe extern "C" fn main() -> ! {
         &mut pac.RESETS,
     ));
     
+    // The PIO blocks stay in reset until they're explicitly brought out of it
+    pac.RESETS.reset.modify(|_, w| w.pio0().clear_bit().pio1().clear_bit());
+    while pac.RESETS.reset_done.read().pio0().bit_is_clear() || pac.RESETS.reset_done.read().pio1().bit_is_clear() {}
+    
     gpio::set_as_output(PIN_CNT_18, true, false);
     
     gpio::set_as_output(PIN_CNT_18_DIR, true, false);
     gpio::set_low(PIN_CNT_18_DIR);
     
+    // Console reset header (active-low), released before it's driven so the console never sees a reset pulse
+    gpio::set_high(PIN_CON_RESET);
+    gpio::set_as_output(PIN_CON_RESET, true, false);
+    
+    // Console detect (high while the console is powered)
+    gpio::set_as_input(PIN_DETECT, false, true);
+    
+    // Must be read before core1 is running, as flash is briefly inaccessible
+    let board_id = flash::unique_id();
+    
+    // Core1 fills the input buffers and drives the displays, while core0 replays from the buffers and
+    // updates the displays' contents. When spying, core0 reads the controllers and core1 passes them on.
+    let inputs = systems::split_input_buffers();
+    displays::split_queues();
+    systems::spy::split_queue();
+    log::split_deferred();
+    
     let mut mc = Multicore::new(&mut pac.PSM, &mut pac.PPB, &mut sio.fifo);
     let cores = mc.cores();
     let core1 = &mut cores[1];
-    let _ = core1.spawn(unsafe { &mut CORE1_STACK.mem }, move || { utilcore::run(usb_bus) }).unwrap();
+    let _ = core1.spawn(unsafe { &mut CORE1_STACK.mem }, move || { utilcore::run(usb_bus, board_id, inputs) }).unwrap();
     
     // In the event the bus fabric hits a conflict, we want to prioritize core0.
     // Even though it's only a matter of 1 cycle per conflict, if either core is spinning on a 
@@ -1,10 +1,21 @@
 use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU8, Ordering};
 use bincode::{Decode, Encode};
 use cortex_m::asm::nop;
 use cortex_m::delay::Delay;
 use defmt::Format;
 use num_enum::{FromPrimitive, IntoPrimitive};
-use crate::{info, systems};
+use rp2040_pac::Interrupt::SIO_IRQ_PROC0;
+use crate::{events, info, systems};
+use crate::events::Event;
+use crate::hal::{flash, gpio, interrupts, sync};
+use crate::hal::gpio::PIN_DETECT;
+use crate::hal::sync::{LOCK_REPLAY_STATE, SpinMutex};
+use crate::VTABLE0;
+pub use veritas_core::transition::TransitionTiming;
+
+pub mod standalone;
+pub mod transitions;
 
 #[derive(Debug, PartialEq, Eq, Copy, Clone, FromPrimitive, Encode, Decode)]
 #[repr(u8)]
@@ -16,11 +27,82 @@ pub enum VeritasMode {
     ReplayNes = 0x03,
     ReplayA2600 = 0x04,
     ReplayGenesis = 0x05,
+    ReplaySnes = 0x06,
+    SpyNes = 0x07,
+    SpySnes = 0x08,
+    SpyGenesis = 0x09,
 }
 use VeritasMode::*;
 
-pub static mut VERITAS_MODE: VeritasMode = Initial;
-pub static mut REPLAY_STATE: ReplayState = ReplayState::new();
+/// Current mode. Only core0 changes it; core1 sends a [`Message::SetMode`] instead.
+static VERITAS_MODE: AtomicU8 = AtomicU8::new(Initial as u8);
+pub static REPLAY_STATE: SpinMutex<ReplayState> = SpinMutex::new(LOCK_REPLAY_STATE, ReplayState::new());
+
+/// Requests sent from core1 to core0 through the SIO FIFO. Core0 echoes each one back once it's been handled.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Message {
+    SetMode(VeritasMode),
+}
+impl Message {
+    const SET_MODE: u32 = 0x01;
+    
+    fn encode(self) -> u32 {
+        match self {
+            Message::SetMode(mode) => (Self::SET_MODE << 8) | mode as u32,
+        }
+    }
+    
+    fn decode(word: u32) -> Option<Self> {
+        match word >> 8 {
+            Self::SET_MODE => Some(Message::SetMode(VeritasMode::from(word as u8))),
+            _ => None,
+        }
+    }
+}
+
+/// Returns the current mode. Can be used from either core.
+#[inline(always)]
+pub fn mode() -> VeritasMode {
+    VeritasMode::from(VERITAS_MODE.load(Ordering::Acquire))
+}
+
+/// Changes the mode. Must only be called from CORE0, use [`send`] from core1.
+#[inline(always)]
+pub fn set_mode(mode: VeritasMode) {
+    if VERITAS_MODE.load(Ordering::Relaxed) != mode as u8 {
+        events::record(Event::ModeChanged(mode));
+    }
+    VERITAS_MODE.store(mode as u8, Ordering::Release);
+}
+
+/// Sends a message to core0, and waits until it's been handled. Must only be called from CORE1.
+pub fn send(message: Message) {
+    sync::fifo_push(message.encode());
+    while sync::fifo_pop_blocking() != message.encode() {}
+}
+
+#[link_section = ".ram_code"]
+extern "C" fn sio_irq_proc0_handler() {
+    while let Some(word) = sync::fifo_pop() {
+        match Message::decode(word) {
+            // Going idle while already idle abandons a replay that was prepared, but never started
+            Some(Message::SetMode(Idle)) if mode() == Idle => discard_replay(),
+            Some(Message::SetMode(mode)) => set_mode(mode),
+            None => (),
+        }
+        
+        sync::fifo_push(word);
+    }
+    
+    sync::fifo_clear_errors();
+}
+
+/// Drops the inputs and settings of a replay that was never started. Must only be called from CORE0, while idle.
+fn discard_replay() {
+    unsafe { systems::discard_inputs(); }
+    systems::n64::unplug_controller_paks();
+    REPLAY_STATE.lock(|state| state.reset());
+}
 
 #[derive(Debug, Format, PartialEq, Eq, Copy, Clone, FromPrimitive, IntoPrimitive)]
 #[repr(u8)]
@@ -36,11 +118,20 @@ pub enum Transition {
 
 #[derive(Debug)]
 pub struct ReplayState {
+    /// Number of inputs in the movie.
     pub index_len: u32,
+    /// Number of inputs that have been consumed by the console so far.
     pub index_cur: u32,
+    /// Sorted list of transitions, each performed once `index_cur` reaches its index.
     pub transitions: Vec<(u32, Transition)>,
     pub traptr: usize,
     pub use_initial_reset: bool,
+    /// Durations used when performing transitions.
+    pub timing: TransitionTiming,
+    /// If set, the replay doesn't begin until the console is detected as powered on.
+    pub wait_for_console: bool,
+    /// Time in milliseconds without a command from the host, before the replay is stopped. 0 disables it.
+    pub host_timeout_ms: u32,
 }
 impl ReplayState {
     pub const fn new() -> Self { Self {
@@ -49,6 +140,9 @@ impl ReplayState {
         transitions: Vec::new(),
         traptr: 0,
         use_initial_reset: true,
+        timing: TransitionTiming::new(),
+        wait_for_console: false,
+        host_timeout_ms: 0,
     }}
     
     pub fn reset(&mut self) {
@@ -57,6 +151,17 @@ impl ReplayState {
         self.transitions.clear();
         self.traptr = 0;
         self.use_initial_reset = true;
+        self.timing = TransitionTiming::new();
+        self.wait_for_console = false;
+        self.host_timeout_ms = 0;
+    }
+    
+    /// Counts an input as consumed by the console. Returns true once every input in the movie has been consumed.
+    #[inline(always)]
+    pub fn consume_input(&mut self) -> bool {
+        self.index_cur += 1;
+        
+        self.index_cur >= self.index_len
     }
     
     #[inline(always)]
@@ -72,20 +177,59 @@ impl ReplayState {
     }
 }
 
+/// Time in milliseconds the console must remain detected before it's considered powered on.
+const CONSOLE_DETECT_MS: u32 = 100;
+
+/// Returns true if the console is powered on.
+#[inline(always)]
+pub fn console_detected() -> bool {
+    gpio::is_high(PIN_DETECT)
+}
+
+/// Blocks until the console is detected as powered on, or until the device leaves the given mode.
+///
+/// Returns false if the replay was stopped while waiting.
+pub fn wait_for_console(mode: VeritasMode, delay: &mut Delay) -> bool {
+    info!("waiting for console..");
+    
+    while self::mode() == mode {
+        if console_detected() {
+            delay.delay_ms(CONSOLE_DETECT_MS);
+            
+            if console_detected() {
+                info!("console detected");
+                return true;
+            }
+        }
+        
+        nop();
+    }
+    
+    false
+}
+
 pub fn run(mut delay: Delay) -> ! {
     unsafe {
-        REPLAY_STATE.reset();
-        VERITAS_MODE = Idle;
+        REPLAY_STATE.lock(|state| state.reset());
+        
+        VTABLE0.register_handler(SIO_IRQ_PROC0 as usize, sio_irq_proc0_handler);
+        interrupts::enable_nvic(SIO_IRQ_PROC0);
+        
+        set_mode(Idle);
         info!("VeriTAS Ready!");
         
         loop {
-            match VERITAS_MODE {
+            match mode() {
                 Initial => nop(),
-                Idle => nop(),
+                Idle => flash::check_lockout(),
                 ReplayN64 => systems::n64::run(&mut delay),
-                ReplayNes => systems::nes::run(&mut delay),
+                ReplayNes => systems::nes::run(systems::nes::Console::Nes, &mut delay),
+                ReplaySnes => systems::snes::run(&mut delay),
                 ReplayA2600 => nop(),
                 ReplayGenesis => systems::genesis::run(&mut delay),
+                SpyNes => systems::spy::run(systems::spy::Console::Nes),
+                SpySnes => systems::spy::run(systems::spy::Console::Snes),
+                SpyGenesis => systems::spy::run(systems::spy::Console::Genesis),
             }
             
             nop();
@@ -0,0 +1,172 @@
+use core::sync::atomic::Ordering;
+use crate::{info, warn};
+use heapless::spsc::Producer;
+use crate::hal::flash;
+use crate::hal::flash::{PAGE_SIZE, SECTOR_SIZE};
+use crate::replaycore;
+use crate::replaycore::{Message, REPLAY_STATE, VeritasMode};
+use crate::systems;
+use crate::systems::InputProducers;
+use crate::utilcore::comms;
+use crate::utilcore::comms::System;
+use veritas_core::stored;
+use veritas_core::stored::{COMMIT, COMMIT_OFFSET, HEADER_SIZE, INPUTS_OFFSET, REGION_SIZE, StoredMovie};
+
+/// Offset from the start of flash, of the region reserved for a stored movie. Must stay clear of the
+/// firmware image (see memory.x).
+pub const REGION_OFFSET: u32 = 0x100000;
+
+/// Starts as true so that a console which is already on at boot doesn't start a replay.
+static mut CONSOLE_WAS_DETECTED: bool = true;
+/// Inputs of the stored movie which haven't been buffered yet. Do not use outside of CORE1!
+static mut STORED_INPUTS: &[u8] = &[];
+
+/// Invalidates the stored movie by erasing its header.
+pub fn erase() -> bool {
+    flash::write(REGION_OFFSET, &[], true)
+}
+
+/// Writes part of the stored movie, at an offset within the region.
+///
+/// Writes must be page aligned and can't cross a sector boundary. Writing to the start of a sector
+/// erases it first. The inputs can only be written while there's no stored movie, and the header is written
+/// last: only once it's been written in full is the commit marker added, which makes the movie valid.
+pub fn write(offset: u32, data: &[u8]) -> bool {
+    let offset = offset as usize;
+    let sector_offset = offset % SECTOR_SIZE;
+    if offset % PAGE_SIZE != 0 || data.len() > SECTOR_SIZE - sector_offset || offset + data.len() > REGION_SIZE {
+        return false;
+    }
+    
+    if offset < INPUTS_OFFSET {
+        // The header must be written in one go, so the marker is never committed over a partial one
+        if offset != 0 || data.len() > COMMIT_OFFSET {
+            return false;
+        }
+        
+        return flash::write(REGION_OFFSET, data, true)
+            && flash::write(REGION_OFFSET + COMMIT_OFFSET as u32, &COMMIT, false);
+    }
+    
+    if load().is_some() {
+        warn!("stored movie must be erased before its inputs are written");
+        return false;
+    }
+    
+    flash::write(REGION_OFFSET + offset as u32, data, sector_offset == 0)
+}
+
+/// Reads the header of the stored movie, if there is a valid one.
+pub fn load() -> Option<StoredMovie> {
+    let header = unsafe { core::slice::from_raw_parts(flash::xip_ptr(REGION_OFFSET), HEADER_SIZE) };
+    
+    stored::decode_header(header)
+}
+
+/// Prepares and starts a replay of the stored movie. The device must be idle. Must only be called from CORE1,
+/// which feeds the inputs to the replay.
+pub fn start(inputs: &mut InputProducers) -> bool {
+    if replaycore::mode() != VeritasMode::Idle {
+        return false;
+    }
+    
+    let movie = match load() {
+        Some(movie) => movie,
+        None => {
+            warn!("no movie stored in flash");
+            return false;
+        }
+    };
+    
+    let system = System::from(movie.system);
+    let (mode, width) = match system {
+        System::Nes => (VeritasMode::ReplayNes, 2),
+        System::Snes => (VeritasMode::ReplaySnes, 4),
+        System::Genesis => (VeritasMode::ReplayGenesis, 4),
+        _ => {
+            warn!("stored movie is for an unsupported system");
+            return false;
+        }
+    };
+    
+    let size = movie.length as usize * width;
+    if !stored::inputs_fit(size) {
+        return false;
+    }
+    
+    let valid = REPLAY_STATE.lock(|state| {
+        state.reset();
+        state.index_len = movie.length;
+        state.use_initial_reset = movie.use_initial_reset;
+        state.wait_for_console = movie.wait_for_console;
+        state.timing = movie.timing;
+        
+        let valid = comms::load_transitions(state, movie.transitions);
+        if !valid {
+            state.reset();
+        }
+        
+        valid
+    });
+    if !valid {
+        return false;
+    }
+    
+    unsafe {
+        STORED_INPUTS = core::slice::from_raw_parts(flash::xip_ptr(REGION_OFFSET + INPUTS_OFFSET as u32), size);
+    }
+    
+    match system {
+        System::Nes => {
+            systems::nes::LATCH_FILTER_US.store(movie.latch_filter_us, Ordering::Relaxed);
+            fill(&mut inputs.nes);
+        },
+        System::Snes => {
+            systems::nes::LATCH_FILTER_US.store(movie.latch_filter_us, Ordering::Relaxed);
+            fill(&mut inputs.snes);
+        },
+        _ => fill(&mut inputs.genesis),
+    }
+    
+    info!("starting stored replay ({} frames)", movie.length);
+    replaycore::send(Message::SetMode(mode));
+    
+    true
+}
+
+/// Keeps the input buffer of a stored replay topped up. Must be called periodically from CORE1.
+pub fn feed(inputs: &mut InputProducers) {
+    match replaycore::mode() {
+        VeritasMode::ReplayNes => fill(&mut inputs.nes),
+        VeritasMode::ReplaySnes => fill(&mut inputs.snes),
+        VeritasMode::ReplayGenesis => fill(&mut inputs.genesis),
+        // Whatever is left of a stored replay that has ended, or was stopped, must not leak into the next one.
+        _ => unsafe { STORED_INPUTS = &[] },
+    }
+}
+
+/// Moves stored inputs into an input buffer, until either the buffer is full or the inputs run out.
+fn fill<const W: usize, const N: usize>(producer: &mut Producer<'static, [u8; W], N>) {
+    unsafe {
+        while producer.ready() && STORED_INPUTS.len() >= W {
+            let (input, rest) = STORED_INPUTS.split_at(W);
+            producer.enqueue(input.try_into().unwrap()).ok();
+            STORED_INPUTS = rest;
+        }
+    }
+}
+
+/// Starts the stored movie when the console is powered on while the device is idle, if it's set to autostart.
+/// Must be called periodically from CORE1.
+pub fn check_autostart(inputs: &mut InputProducers) {
+    let detected = replaycore::console_detected();
+    
+    unsafe {
+        let idle = replaycore::mode() == VeritasMode::Idle;
+        if detected && !CONSOLE_WAS_DETECTED && idle && load().map_or(false, |movie| movie.autostart) {
+            start(inputs);
+        }
+        
+        CONSOLE_WAS_DETECTED = detected;
+    }
+}
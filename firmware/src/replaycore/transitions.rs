@@ -0,0 +1,149 @@
+use rp2040_pac::Interrupt::TIMER_IRQ_3;
+use crate::{events, log};
+use crate::events::Event;
+use crate::log::Code;
+use crate::hal::{gpio, interrupts};
+use crate::hal::gpio::PIN_CON_RESET;
+use crate::replaycore::{REPLAY_STATE, Transition};
+use crate::VTABLE0;
+
+/// Timer alarm reserved for transition sequencing. Systems must not use it.
+const ALARM: usize = 3;
+
+/// Hooks a system provides so transitions can pause and re-arm its controller emulation.
+pub struct Handler {
+    /// Console reset line (active-high).
+    pub reset_pin: usize,
+    /// Stops responding to the console. Inputs must not be consumed until `resume` is called.
+    pub suspend: fn(),
+    /// Re-arms controller emulation after the transition has completed.
+    pub resume: fn(),
+}
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+enum Phase {
+    Idle,
+    ResetHeld,
+    ResetSettle,
+    HardResetHeld,
+    HardResetBoot,
+}
+
+static mut PHASE: Phase = Phase::Idle;
+static mut HANDLER: Option<&'static Handler> = None;
+
+/// Prepares the transition alarm. Must be called from CORE0, before any transition is started.
+pub fn initialize(handler: &'static Handler) {
+    cortex_m::interrupt::free(|_| unsafe {
+        HANDLER = Some(handler);
+        PHASE = Phase::Idle;
+        
+        VTABLE0.register_handler(TIMER_IRQ_3 as usize, timer_irq_3_handler);
+        
+        interrupts::clear_alarm_intr(ALARM);
+        interrupts::enable_alarm_intr(ALARM);
+        interrupts::enable_nvic(TIMER_IRQ_3);
+    });
+}
+
+/// Suspends the system and starts performing a transition. Further transitions scheduled at the
+/// same index are performed afterwards, then the system is resumed.
+///
+/// Returns immediately; the transition is sequenced by the timer alarm.
+#[link_section = ".ram_code"]
+pub fn begin(tra: Transition) {
+    unsafe {
+        if let Some(handler) = HANDLER {
+            (handler.suspend)();
+            start(handler, tra);
+        }
+    }
+}
+
+/// Stops any transition in progress and releases the reset line and header.
+///
+/// The system is not resumed.
+pub fn abort() {
+    cortex_m::interrupt::free(|_| unsafe {
+        interrupts::disable_nvic(TIMER_IRQ_3);
+        interrupts::disable_alarm_intr(ALARM);
+        interrupts::clear_alarm_intr(ALARM);
+        
+        if let Some(handler) = HANDLER.take() {
+            gpio::set_low(handler.reset_pin);
+        }
+        gpio::set_high(PIN_CON_RESET);
+        
+        PHASE = Phase::Idle;
+    });
+}
+
+/// Arms the transition alarm. Very short durations are lengthened so the alarm can't be set in the past.
+#[inline(always)]
+fn arm(duration_us: u32) {
+    interrupts::arm_alarm(ALARM, duration_us.max(10));
+}
+
+#[link_section = ".ram_code"]
+unsafe fn start(handler: &Handler, tra: Transition) {
+    let (timing, index) = REPLAY_STATE.lock(|state| (state.timing, state.index_cur));
+    
+    match tra {
+        Transition::SoftReset => {
+            gpio::set_high(handler.reset_pin);
+            PHASE = Phase::ResetHeld;
+            arm(timing.reset_hold_us);
+        },
+        // The board can't switch the console's power, so a power reset is performed as a hard reset through
+        // the console's reset header (active-low) instead
+        Transition::PowerReset => {
+            gpio::set_low(PIN_CON_RESET);
+            PHASE = Phase::HardResetHeld;
+            arm(timing.hard_reset_hold_ms.saturating_mul(1000));
+        },
+        _ => finish(handler),
+    }
+    
+    events::record(Event::Transition { kind: tra.into(), index });
+    log::defer(Code::TransitionStarted, [u8::from(tra) as u32, index]);
+}
+
+#[link_section = ".ram_code"]
+unsafe fn finish(handler: &Handler) {
+    PHASE = Phase::Idle;
+    
+    if let Some(tra) = REPLAY_STATE.lock(|state| state.next_transition()) {
+        start(handler, tra);
+    } else {
+        (handler.resume)();
+    }
+}
+
+#[link_section = ".ram_code"]
+extern "C" fn timer_irq_3_handler() {
+    unsafe {
+        interrupts::clear_alarm_intr(ALARM);
+        
+        let handler = match HANDLER {
+            Some(handler) => handler,
+            None => return,
+        };
+        let timing = REPLAY_STATE.lock(|state| state.timing);
+        
+        match PHASE {
+            Phase::Idle => (),
+            Phase::ResetHeld => {
+                gpio::set_low(handler.reset_pin);
+                PHASE = Phase::ResetSettle;
+                arm(timing.reset_settle_us);
+            },
+            Phase::ResetSettle => finish(handler),
+            Phase::HardResetHeld => {
+                gpio::set_high(PIN_CON_RESET);
+                PHASE = Phase::HardResetBoot;
+                arm(timing.hard_reset_boot_ms.saturating_mul(1000));
+            },
+            Phase::HardResetBoot => finish(handler),
+        }
+    }
+}
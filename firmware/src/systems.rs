@@ -1,4 +1,34 @@
+use heapless::spsc::Producer;
 
 pub mod genesis;
 pub mod n64;
-pub mod nes;
\ No newline at end of file
+pub mod nes;
+pub mod snes;
+pub mod spy;
+
+/// Producer ends of every system's input buffer. They belong to core1, which fills them with inputs from
+/// either the host or flash, while each system keeps the consumer end for core0.
+pub struct InputProducers {
+    pub nes: Producer<'static, [u8; 2], 1024>,
+    pub snes: Producer<'static, [u8; 4], 1024>,
+    pub n64: Producer<'static, [u32; 4], 1024>,
+    pub genesis: Producer<'static, [u8; 4], 1024>,
+}
+
+/// Splits every system's input buffer. Must be called once from CORE0, before core1 is started.
+pub unsafe fn split_input_buffers() -> InputProducers {
+    InputProducers {
+        nes: nes::split_input_buffer(),
+        snes: snes::split_input_buffer(),
+        n64: n64::split_input_buffer(),
+        genesis: genesis::split_input_buffer(),
+    }
+}
+
+/// Drops every system's buffered inputs. Must only be called from CORE0, while no replay is running.
+pub unsafe fn discard_inputs() {
+    while nes::dequeue_input().is_some() {}
+    while snes::dequeue_input().is_some() {}
+    while n64::dequeue_input().is_some() {}
+    while genesis::dequeue_input().is_some() {}
+}
\ No newline at end of file
@@ -1,54 +1,147 @@
 use cortex_m::asm::nop;
 use cortex_m::delay::Delay;
-use defmt::{info, warn};
-use heapless::spsc::Queue;
-use rp2040_pac::Interrupt::{IO_IRQ_BANK0, TIMER_IRQ_0};
-use rp2040_pac::{IO_BANK0, SIO};
-use crate::hal::{gpio, interrupts};
-use crate::hal::gpio::{PIN_CNT_1, PIN_CNT_10, PIN_CNT_11, PIN_CNT_12, PIN_CNT_13, PIN_CNT_14, PIN_CNT_16, PIN_CNT_2, PIN_CNT_3, PIN_CNT_4, PIN_CNT_5, PIN_CNT_6, PIN_CNT_7, PIN_CNT_9, PIN_DETECT};
-use crate::hal::interrupts::Edge;
-use crate::replaycore::{REPLAY_STATE, VERITAS_MODE, VeritasMode};
+use crate::{events, info, log};
+use crate::log::Code;
+use heapless::spsc::{Consumer, Producer, Queue};
+use pio::{InstructionOperands, JmpCondition, MovDestination, MovOperation, MovSource, OutDestination};
+use pio_proc::pio_asm;
+use rp2040_pac::Interrupt::PIO0_IRQ_0;
+use rp2040_pac::io_bank0::gpio::gpio_ctrl::FUNCSEL_A;
+use crate::hal::{gpio, interrupts, pio as p};
+use crate::hal::gpio::{PIN_CNT_1, PIN_CNT_10, PIN_CNT_11, PIN_CNT_12, PIN_CNT_13, PIN_CNT_14, PIN_CNT_16, PIN_CNT_18, PIN_CNT_18_DIR, PIN_CNT_2, PIN_CNT_3, PIN_CNT_4, PIN_CNT_5, PIN_CNT_6, PIN_CNT_7, PIN_CNT_9, PIN_DETECT};
+use crate::hal::pio::{PioSel, ProgramHandle, ShiftDirection, SmSel, StateMachine};
+use crate::hal::pio::PioOption::{Autopull, ClockDiv, InBase, JmpPin, OutBase, OutCount, OutShiftdir};
+use crate::replaycore;
+use crate::replaycore::{REPLAY_STATE, VeritasMode};
+use crate::replaycore::transitions;
 use crate::utilcore::displays;
 use crate::utilcore::displays::Port;
 use crate::VTABLE0;
+use veritas_core::genesis;
+use veritas_core::genesis::PinLayout;
 
-pub static mut INPUT_BUFFER: Queue<[u8; 4], 1024> = Queue::new();
+/// Buffered list of controller inputs. Each frame holds 2 bytes for each port.
+static mut INPUT_QUEUE: Queue<[u8; 4], 1024> = Queue::new();
+/// Consumer end of the input buffer. Do not use outside of CORE0!
+static mut INPUT_BUFFER: Option<Consumer<'static, [u8; 4], 1024>> = None;
 pub static mut LATCHED_INPUT: [[u8; 2]; 2] = [[0xFF, 0xFF]; 2];
+/// Responder program in each port's PIO block, while a replay is running. Do not use outside of CORE0!
+static mut PROGRAMS: [Option<ProgramHandle>; 2] = [None, None];
+/// Address of the responder's `refresh` in each port's PIO block.
+static mut REFRESH_ADDR: [u8; 2] = [0; 2];
 
-#[derive(Debug, Copy, Clone, PartialEq)]
-struct NextPins {
-    pub set: u32,
-    pub clr: u32,
+pub(crate) const SELECT: [usize; 2]    = [PIN_CNT_3, PIN_CNT_1]; // CP_18 / CP_24
+pub(crate) const UP: [usize; 2]        = [PIN_CNT_5, PIN_CNT_2]; // CP_8 / CP_25
+pub(crate) const DOWN: [usize; 2]      = [PIN_CNT_7, PIN_CNT_4]; // CP_7 / CP_17
+pub(crate) const LEFT_0: [usize; 2]    = [PIN_CNT_9, PIN_CNT_6]; // CP_6 / CP_16
+pub(crate) const RIGHT_0: [usize; 2]   = [PIN_CNT_11, PIN_CNT_10]; // CP_5 / CP_15
+pub(crate) const B_A: [usize; 2]       = [PIN_CNT_13, PIN_CNT_12]; // CP_4 / CP_14
+pub(crate) const C_START: [usize; 2]   = [PIN_CNT_16, PIN_CNT_14]; // CP_3 / CP_13
+const RST: usize = PIN_CNT_18;
+/// set HIGH to enable
+const RST_EN: usize = PIN_CNT_18_DIR;
+/// Lowest data pin of each port. Pin sets are relative to it.
+pub(super) const DATA_BASE: [usize; 2] = [PIN_CNT_5, PIN_CNT_2];
+pub(super) const LAYOUT: [PinLayout; 2] = [pin_layout(0), pin_layout(1)];
+
+/// The data pins of both ports are interleaved, so each port's responder runs on a separate PIO block. Each
+/// block only controls the pins routed to it, so writing the entire range doesn't affect the other port.
+const PIO: [PioSel; 2] = [PioSel::Zero, PioSel::One];
+const FUNCSEL: [FUNCSEL_A; 2] = [FUNCSEL_A::PIO0, FUNCSEL_A::PIO1];
+const SM: SmSel = SmSel::Zero;
+/// Time without a select edge, after which the controller's step counter resets. The end of port 1's
+/// sequence also marks the end of a frame.
+pub(super) const STEP_TIMEOUT_US: u32 = 1500;
+/// Iterations of the responder's timeout loop (2 cycles each, at the 160MHz system clock).
+const TIMEOUT_LOOPS: u32 = STEP_TIMEOUT_US * 160 / 2;
+
+static TRANSITION_HANDLER: transitions::Handler = transitions::Handler {
+    reset_pin: RST,
+    suspend: disable_interrupts,
+    resume,
+};
+
+/// Splits the input buffer, keeping the consumer end. Must only be called once, from CORE0.
+pub unsafe fn split_input_buffer() -> Producer<'static, [u8; 4], 1024> {
+    let (producer, consumer) = INPUT_QUEUE.split();
+    INPUT_BUFFER = Some(consumer);
+    
+    producer
 }
-impl NextPins {
-    pub const fn new() -> Self {
-        Self {
-            set: 0,
-            clr: 0,
-        }
+
+#[inline(always)]
+pub(super) unsafe fn dequeue_input() -> Option<[u8; 4]> {
+    INPUT_BUFFER.as_mut().and_then(|buffer| buffer.dequeue())
+}
+
+fn initialize() {
+    install_program();
+    configure_pins();
+    
+    unsafe {
+        let inputs = dequeue_input().unwrap_or([0xFF; 4]);
+        LATCHED_INPUT = [[inputs[0], inputs[1]], [inputs[2], inputs[3]]];
+        
+        update_displays();
     }
+}
+
+/// Loads the select-line responder into both PIO blocks.
+fn install_program() {
+    // Each frame is a pair of precomputed pin sets, packed into X: the low half is presented while select
+    // is high, and the high half while it's low. Select is polled, so each edge is answered within a few
+    // cycles. Once the step timeout passes without an edge, IRQ 0 is raised and the responder waits for
+    // the next edge. The CPU loads a new frame by queueing it, then jumping to `refresh`.
+    let program = pio_asm!("
+    public refresh:
+        pull noblock
+        mov x, osr
+        jmp pin present_high    ; JMP pin is this port's select line
+    present_low:
+        mov osr, x
+        out null, 16
+        out pins, 16
+        mov y, isr              ; ISR holds the timeout loop count
+    poll_low:
+        jmp pin present_high
+        jmp y-- poll_low
+        irq nowait 0
+        wait 1 pin 0            ; IN base is also the select line
+    present_high:
+        mov osr, x
+        out pins, 16
+        mov y, isr
+    poll_high:
+        jmp pin still_high
+        jmp present_low
+    still_high:
+        jmp y-- poll_high
+        irq nowait 0
+        wait 0 pin 0
+        jmp present_low
+    ");
     
-    pub fn check(&mut self, input: u8, bit: usize, gpio: usize) {
-        if ((((input >> bit) as u32) & 1) << gpio) > 0 {
-            self.set |= 1 << gpio;
-        } else {
-            self.clr |= 1 << gpio;
+    for port in 0..2 {
+        let handle = p::install(&program.program, PIO[port], &[SM]).expect("responder program didn't fit");
+        unsafe {
+            REFRESH_ADDR[port] = handle.address(program.public_defines.refresh as u8);
+            PROGRAMS[port] = Some(handle);
         }
+        
+        let options = [
+            InBase(SELECT[port] as u8),
+            JmpPin(SELECT[port] as u8),
+            OutBase(DATA_BASE[port] as u8),
+            OutCount(16),
+            OutShiftdir(ShiftDirection::Right),
+            Autopull(false),
+            ClockDiv(1.0),
+        ];
+        p::configure(PIO[port], SM, &options);
     }
 }
 
-static mut NEXT_PINS: [NextPins; 2] = [NextPins::new(); 2];
-static mut STEPS: [usize; 2] = [0, 0];
-
-const SELECT: [usize; 2]    = [PIN_CNT_3, PIN_CNT_1]; // CP_18 / CP_24
-const UP: [usize; 2]        = [PIN_CNT_5, PIN_CNT_2]; // CP_8 / CP_25
-const DOWN: [usize; 2]      = [PIN_CNT_7, PIN_CNT_4]; // CP_7 / CP_17
-const LEFT_0: [usize; 2]    = [PIN_CNT_9, PIN_CNT_6]; // CP_6 / CP_16
-const RIGHT_0: [usize; 2]   = [PIN_CNT_11, PIN_CNT_10]; // CP_5 / CP_15
-const B_A: [usize; 2]       = [PIN_CNT_13, PIN_CNT_12]; // CP_4 / CP_14
-const C_START: [usize; 2]   = [PIN_CNT_16, PIN_CNT_14]; // CP_3 / CP_13
-
-fn initialize() {
+fn configure_pins() {
     gpio::set_low(PIN_DETECT);
     gpio::set_as_input(PIN_DETECT, false, true);
     
@@ -62,278 +155,190 @@ fn initialize() {
         gpio::set_high(*pin);
     }
     
+    gpio::set_as_output(RST, true, false); // Console reset (active-high)
+    gpio::set_low(RST);
+    
+    gpio::set_high(RST_EN);
+}
+
+/// Stops driving the controller lines, so an unpowered console isn't fed through its data inputs.
+fn release_pins() {
+    for pin in [UP, DOWN, LEFT_0, RIGHT_0, B_A, C_START].flatten() {
+        gpio::set_as_input(*pin, false, false);
+    }
+    
+    gpio::set_low(RST);
+}
+
+/// Restarts both responders with the current frame, and hands the data pins over to them.
+fn start_responders() {
     unsafe {
-        let inputs = INPUT_BUFFER.dequeue().unwrap_or([0xFF; 4]);
-        LATCHED_INPUT = [[inputs[0], inputs[1]], [inputs[2], inputs[3]]];
-        
-        STEPS.fill(0);
-        calc_next_edge(0);
-        calc_next_edge(1);
-        
-        let state = calc_state(0, 0, true);
-        (*SIO::ptr()).gpio_out_set.write(|w| w.bits(state.set));
-        (*SIO::ptr()).gpio_out_clr.write(|w| w.bits(state.clr));
-        
-        displays::set_display(Port::Display0, &[LATCHED_INPUT[0][0] ^ 0xFF, LATCHED_INPUT[0][1] ^ 0xFF]);
-        displays::set_display(Port::Display1, &[LATCHED_INPUT[1][0] ^ 0xFF, LATCHED_INPUT[1][1] ^ 0xFF]);
+        for port in 0..2 {
+            let pio = PIO[port];
+            p::stop(pio, SM);
+            p::clear_fifos(pio, SM);
+            p::restart(pio, SM);
+            
+            p::fifo_write(pio, SM, TIMEOUT_LOOPS);
+            p::exec(pio, SM, InstructionOperands::PULL { if_empty: false, block: true });
+            p::exec(pio, SM, InstructionOperands::MOV { destination: MovDestination::ISR, op: MovOperation::None, source: MovSource::OSR });
+            
+            // Pins in the output range which belong to the other port aren't routed to this block
+            p::fifo_write(pio, SM, 0xFFFF);
+            p::exec(pio, SM, InstructionOperands::PULL { if_empty: false, block: true });
+            p::exec(pio, SM, InstructionOperands::OUT { destination: OutDestination::PINDIRS, bit_count: 16 });
+            
+            p::fifo_write(pio, SM, frame_word(port));
+            p::exec(pio, SM, InstructionOperands::JMP { condition: JmpCondition::Always, address: REFRESH_ADDR[port] });
+            p::clear_irq(pio, 0);
+            
+            for pin in [UP, DOWN, LEFT_0, RIGHT_0, B_A, C_START] {
+                gpio::set_function(pin[port], FUNCSEL[port]);
+            }
+            
+            p::start(pio, SM);
+        }
     }
 }
 
 fn enable_interrupts() {
+    start_responders();
+    
     cortex_m::interrupt::free(|_| unsafe {
-        VTABLE0.register_handler(IO_IRQ_BANK0 as usize, io_irq_bank0_handler);
+        VTABLE0.register_handler(PIO0_IRQ_0 as usize, pio0_irq_0_handler);
         
-        for pin in SELECT {
-            interrupts::clear_gpio_intr(pin, Edge::EdgeHigh);
-            interrupts::clear_gpio_intr(pin, Edge::EdgeLow);
-            
-            interrupts::enable_gpio_intr(pin, Edge::EdgeHigh);
-            interrupts::enable_gpio_intr(pin, Edge::EdgeLow);
-        }
-        interrupts::enable_nvic(IO_IRQ_BANK0);
-        
-        
-        VTABLE0.register_handler(TIMER_IRQ_0 as usize, timer_irq_0_handler);
-        
-        interrupts::clear_alarm_intr(0);
-        interrupts::clear_alarm_intr(1);
-        interrupts::enable_alarm_intr(0);
-        interrupts::enable_alarm_intr(1);
-        interrupts::enable_nvic(TIMER_IRQ_0);
+        // Only port 1's timeout is used, to mark the end of a frame
+        p::clear_irq(PIO[0], 0);
+        p::enable_irq0(PIO[0], 0);
+        interrupts::enable_nvic(PIO0_IRQ_0);
     });
 }
 
+/// Re-arms the controllers once a transition has completed.
+fn resume() {
+    configure_pins();
+    
+    enable_interrupts();
+}
+
 fn disable_interrupts() {
-    cortex_m::interrupt::free(|_| unsafe {
-        interrupts::disable_nvic(IO_IRQ_BANK0);
-        interrupts::disable_nvic(TIMER_IRQ_0);
-        
-        for pin in SELECT {
-            interrupts::disable_gpio_intr(pin, Edge::EdgeHigh);
-            interrupts::disable_gpio_intr(pin, Edge::EdgeLow);
-        }
-        
-        interrupts::disable_alarm_intr(0);
-        interrupts::disable_alarm_intr(1);
+    cortex_m::interrupt::free(|_| {
+        interrupts::disable_nvic(PIO0_IRQ_0);
+        p::disable_irq0(PIO[0], 0);
     });
+    
+    for pio in PIO {
+        p::stop(pio, SM);
+    }
 }
 
+const fn pin_layout(port: usize) -> PinLayout { PinLayout {
+    up: (UP[port] - DATA_BASE[port]) as u8,
+    down: (DOWN[port] - DATA_BASE[port]) as u8,
+    left_0: (LEFT_0[port] - DATA_BASE[port]) as u8,
+    right_0: (RIGHT_0[port] - DATA_BASE[port]) as u8,
+    b_a: (B_A[port] - DATA_BASE[port]) as u8,
+    c_start: (C_START[port] - DATA_BASE[port]) as u8,
+}}
 
-pub fn run(_delay: &mut Delay) {
+/// Both of a port's pin sets for the latched input, as loaded into its responder.
+#[inline(always)]
+fn frame_word(port: usize) -> u32 {
+    let input = unsafe { LATCHED_INPUT[port][0] };
+    
+    genesis::frame_word(&LAYOUT[port], input)
+}
+
+#[inline(always)]
+fn update_displays() {
+    unsafe {
+        displays::set_display(Port::Display0, &[LATCHED_INPUT[0][0] ^ 0xFF, LATCHED_INPUT[0][1] ^ 0xFF]);
+        displays::set_display(Port::Display1, &[LATCHED_INPUT[1][0] ^ 0xFF, LATCHED_INPUT[1][1] ^ 0xFF]);
+    }
+}
+
+
+pub fn run(delay: &mut Delay) {
     unsafe {
         initialize();
         
         info!("starting Genesis replay..");
         
-        enable_interrupts();
-        
-        while VERITAS_MODE == VeritasMode::ReplayGenesis {
-            nop();
-        }
-        
-        disable_interrupts();
-        while !INPUT_BUFFER.is_empty() {
-            INPUT_BUFFER.dequeue().unwrap_or_default();
+        if REPLAY_STATE.lock(|state| state.wait_for_console) {
+            release_pins();
+            let detected = replaycore::wait_for_console(VeritasMode::ReplayGenesis, delay);
+            configure_pins();
+            
+            if !detected {
+                stop(delay);
+                return;
+            }
         }
-        REPLAY_STATE.reset();
         
-        displays::set_display(Port::Display0, &[0x00, 0x00]);
-        displays::set_display(Port::Display1, &[0x00, 0x00]);
-        
-        info!("stopped Genesis replay");
-    }
-}
-
-/*#[inline(always)]
-fn update_state(port: usize) {
-    unsafe {
-        match STEPS[port] {
-            0..=4 => {
-                if gpio::is_high(SELECT[port]) {
-                    gpio::set_state(C_START[port],  LATCHED_INPUT[port][0] & (1 << 0) > 0);
-                    gpio::set_state(B_A[port],      LATCHED_INPUT[port][0] & (1 << 1) > 0);
-                    gpio::set_state(RIGHT_0[port],  LATCHED_INPUT[port][0] & (1 << 2) > 0);
-                    gpio::set_state(LEFT_0[port],   LATCHED_INPUT[port][0] & (1 << 3) > 0);
-                    gpio::set_state(DOWN[port],     LATCHED_INPUT[port][0] & (1 << 4) > 0);
-                    gpio::set_state(UP[port],       LATCHED_INPUT[port][0] & (1 << 5) > 0);
-                } else {
-                    gpio::set_state(C_START[port],  LATCHED_INPUT[port][0] & (1 << 6) > 0);
-                    gpio::set_state(B_A[port],      LATCHED_INPUT[port][0] & (1 << 7) > 0);
-                    gpio::set_low(RIGHT_0[port]);
-                    gpio::set_low(LEFT_0[port]);
-                    gpio::set_state(DOWN[port],     LATCHED_INPUT[port][0] & (1 << 4) > 0);
-                    gpio::set_state(UP[port],       LATCHED_INPUT[port][0] & (1 << 5) > 0);
-                }
-            },
-            5..=6 => {
-                warn!("STEPS 5-6 unimplemented!");
-            },
-            _ => warn!("STEPS higher than 6 reached!"),
+        // Transitions scheduled before the first input are performed before the controllers are armed.
+        transitions::initialize(&TRANSITION_HANDLER);
+        if let Some(tra) = REPLAY_STATE.lock(|state| state.next_transition()) {
+            transitions::begin(tra);
+        } else {
+            enable_interrupts();
         }
-    }
-}*/
-
-#[inline(always)]
-fn calc_state(port: usize, step: usize, edge: bool) -> NextPins {
-    unsafe {
-        let mut next = NextPins::new();
         
-        match step {
-            //0..=4 | 7..=8 => {
-            0..=20 => {
-                let latched = LATCHED_INPUT[port][0];
-                if edge {
-                    next.check(latched, 0, C_START[port]);
-                    next.check(latched, 1, B_A[port]);
-                    next.check(latched, 2, RIGHT_0[port]);
-                    next.check(latched, 3, LEFT_0[port]);
-                    next.check(latched, 4, DOWN[port]);
-                    next.check(latched, 5, UP[port]);
-                } else {
-                    next.check(latched, 6, C_START[port]);
-                    next.check(latched, 7, B_A[port]);
-                    next.check(0, 0, RIGHT_0[port]);
-                    next.check(0, 0, LEFT_0[port]);
-                    next.check(latched, 4, DOWN[port]);
-                    next.check(latched, 5, UP[port]);
-                }
-            },
-            /*5..=6 => {
-                let latched = LATCHED_INPUT[port][0];
-                let special = LATCHED_INPUT[port][1];
-                if edge {
-                    next.check(latched, 0, C_START[port]);
-                    next.check(latched, 1, B_A[port]);
-                    next.check(special, 4, RIGHT_0[port]);
-                    next.check(special, 5, LEFT_0[port]);
-                    next.check(special, 6, DOWN[port]);
-                    next.check(special, 7, UP[port]);
-                } else {
-                    next.check(latched, 6, C_START[port]);
-                    next.check(latched, 7, B_A[port]);
-                    next.check(0, 0, RIGHT_0[port]);
-                    next.check(0, 0, LEFT_0[port]);
-                    next.check(0, 0, DOWN[port]);
-                    next.check(0, 0, UP[port]);
-                }
-            },*/
-            _ => {
-                warn!("STEPS higher than 20 reached!");
-            },
+        while replaycore::mode() == VeritasMode::ReplayGenesis {
+            nop();
         }
         
-        next
+        stop(delay);
     }
 }
 
-#[inline(always)]
-pub fn calc_next_edge(port: usize) {
-    unsafe {
-        NEXT_PINS[port] = calc_state(port, STEPS[port] + 1, !gpio::is_high(SELECT[port]));
-    }
-}
-
-const SELECT_EDGE_MASK_0: u32 = (1 << (((SELECT[0] & 0x07) << 2) + Edge::EdgeLow as usize)) | (1 << (((SELECT[0] & 0x07) << 2) + Edge::EdgeHigh as usize));
-//const SELECT_EDGE_MASK_1: u32 = (1 << (((SELECT[1] & 0x07) << 2) + Edge::EdgeLow as usize)) | (1 << (((SELECT[1] & 0x07) << 2) + Edge::EdgeHigh as usize));
-
-#[link_section = ".ram_code"]
-extern "C" fn io_irq_bank0_handler() {
-    //TODO: Change logic to the following:
-    //
-    //  @Alarm: update_state(), and calculate next edge
-    //  
-    //  @GPIO: When IRQ happens, check which port, and immediately apply pre-calculated action
-    //         Then calculate the next edge, increment STEPS[port], update alarm, and clear intr.
-    //
-    //  Use multiple bits to check for both edges at the same time.
-    //  Calculated action should be applied for the entire GPIO range, not individual bits at a time!
+/// Tears down the replay and returns the device to its idle state.
+fn stop(delay: &mut Delay) {
+    transitions::abort();
+    disable_interrupts();
     
-    //gpio::set_high(gpio::PIN_DISPLAY_STROBE3); // DEBUG
     unsafe {
-        if (*IO_BANK0::ptr()).proc0_ints[SELECT[0] >> 3].read().bits() & SELECT_EDGE_MASK_0 > 0 {
-            (*SIO::ptr()).gpio_out_set.write(|w| w.bits(NEXT_PINS[0].set));
-            (*SIO::ptr()).gpio_out_clr.write(|w| w.bits(NEXT_PINS[0].clr));
-            
-            STEPS[0] += 1;
-            
-            calc_next_edge(0);
-            
-            //info!("{:08X}", (*IO_BANK0::ptr()).proc0_ints[SELECT[0] >> 3].read().bits());
-            interrupts::arm_alarm(0, 1500);
-            interrupts::clear_gpio_intr(SELECT[0], Edge::EdgeLow);
-            interrupts::clear_gpio_intr(SELECT[0], Edge::EdgeHigh);
-        } else { // if not the first port, then must be second, no other GPIO interrupts are used
-            (*SIO::ptr()).gpio_out_set.write(|w| w.bits(NEXT_PINS[1].set));
-            (*SIO::ptr()).gpio_out_clr.write(|w| w.bits(NEXT_PINS[1].clr));
-            
-            STEPS[1] += 1;
-            
-            calc_next_edge(1);
-            
-            interrupts::arm_alarm(1, 1500);
-            interrupts::clear_gpio_intr(SELECT[1], Edge::EdgeLow);
-            interrupts::clear_gpio_intr(SELECT[1], Edge::EdgeHigh);
+        for program in PROGRAMS.iter_mut().filter_map(Option::take) {
+            p::uninstall(program);
         }
+        while dequeue_input().is_some() {}
+        REPLAY_STATE.lock(|state| state.reset());
     }
-    //gpio::set_low(gpio::PIN_DISPLAY_STROBE3); // DEBUG
     
+    displays::set_display(Port::Display0, &[0x00, 0x00]);
+    displays::set_display(Port::Display1, &[0x00, 0x00]);
     
-    /*for i in 0..=1 {
-        let pin = SELECT[i];
-        
-        let edge = if interrupts::status_gpio_intr(pin, Edge::EdgeHigh) {
-            Some(Edge::EdgeHigh)
-        } else if interrupts::status_gpio_intr(pin, Edge::EdgeLow) {
-            Some(Edge::EdgeLow)
-        } else {
-            None
-        };
-        
-        if let Some(edge) = edge {
-            unsafe { STEPS[i] += 1; }
-            //delay(80);
-            update_state(i);
-            
-            interrupts::arm_alarm(i, 1500);
-            interrupts::clear_gpio_intr(pin, edge);
-        }
-    }*/
+    release_pins();
+    delay.delay_ms(10);
+    gpio::set_low(RST_EN);
+    
+    info!("stopped Genesis replay");
 }
 
 #[link_section = ".ram_code"]
-extern "C" fn timer_irq_0_handler() {
-    for port in 0..=1 {
-        if interrupts::status_alarm_intr(port) {
-            unsafe {
-                STEPS[port] = 0;
-                
-                if port == 0 {
-                    let inputs = INPUT_BUFFER.dequeue().unwrap_or([0xFF; 4]);
-                    LATCHED_INPUT = [[inputs[0], inputs[1]], [inputs[2], inputs[3]]];
-                    
-                    displays::set_display(Port::Display0, &[swap_bits(LATCHED_INPUT[0][0] ^ 0xFF, 5, 4), LATCHED_INPUT[0][1] ^ 0xFF]);
-                    displays::set_display(Port::Display1, &[swap_bits(LATCHED_INPUT[1][0] ^ 0xFF, 5, 4), LATCHED_INPUT[1][1] ^ 0xFF]);
-                    
-                    //info!("{:02X}", LATCHED_INPUT[0][0]);
-                }
-                
-                //let state = calc_state(0, 0, false);
-                //info!("{}", format!("{state:08X?}").as_str());
-                let state = calc_state(0, 0, true);
-                (*SIO::ptr()).gpio_out_set.write(|w| w.bits(state.set));
-                (*SIO::ptr()).gpio_out_clr.write(|w| w.bits(state.clr));
-                
-                calc_next_edge(port);
+extern "C" fn pio0_irq_0_handler() {
+    unsafe {
+        p::clear_irq(PIO[0], 0);
+        
+        // The input read during this frame has now been consumed.
+        if REPLAY_STATE.lock(|state| state.consume_input()) {
+            replaycore::set_mode(VeritasMode::Idle);
+            log::defer(Code::ReplayEnded, [0; 2]);
+        } else {
+            let inputs = dequeue_input().unwrap_or_else(|| {
+                events::record_underrun();
+                [0xFF; 4]
+            });
+            LATCHED_INPUT = [[inputs[0], inputs[1]], [inputs[2], inputs[3]]];
+            
+            for port in 0..2 {
+                genesis::present(&mut StateMachine { pio: PIO[port], sm: SM }, frame_word(port), REFRESH_ADDR[port]);
             }
             
-            interrupts::clear_alarm_intr(port);
+            update_displays();
+            
+            if let Some(tra) = REPLAY_STATE.lock(|state| state.next_transition()) {
+                transitions::begin(tra);
+            }
         }
     }
-}
-
-#[inline(always)]
-fn swap_bits(data: u8, a: usize, b: usize) -> u8 {
-    let x = ((data >> a) ^ (data >> b)) & 1;
-    
-    data ^ ((x << a) | (x << b))
 }
\ No newline at end of file
@@ -1,50 +1,172 @@
+use core::sync::atomic::{AtomicU8, Ordering};
+use bincode::{Decode, Encode};
+use cortex_m::asm::nop;
 use cortex_m::delay::Delay;
-use defmt::info;
-use heapless::spsc::Queue;
+use defmt::Format;
+use crate::{events, info, log};
+use crate::log::Code;
+use heapless::spsc::{Consumer, Producer, Queue};
 use pio_proc::pio_asm;
-use pio::{InstructionOperands, JmpCondition, SetDestination};
+use pio::{InstructionOperands, SetDestination};
 use rp2040_pac::io_bank0::gpio::gpio_ctrl::FUNCSEL_A;
 use crate::hal::{gpio, pio as p};
-use crate::hal::pio::{PioSel, ShiftDirection, SmSel};
-use crate::hal::pio::PioOption::{Autopull, ClockDiv, InBase, InShiftdir, OutBase, OutCount, PullThresh, SetBase, SetCount, WrapBottom, WrapTop};
-use crate::replaycore::{VERITAS_MODE, VeritasMode};
+use crate::hal::gpio::{PIN_CNT_11, PIN_CNT_12, PIN_CNT_14, PIN_CNT_15, PIN_CNT_17, PIN_CNT_18, PIN_CNT_18_DIR, PIN_CNT_6, PIN_CNT_8, PIN_CNT_9};
+use crate::hal::pio::{PioSel, ProgramHandle, ShiftDirection, SmSel};
+use crate::hal::sync::{LOCK_CONTROLLER_PAKS, SpinMutex};
+use crate::hal::timer::SystemTimer;
+use crate::hal::pio::PioOption::{Autopull, Autopush, ClockDiv, InBase, InShiftdir, OutBase, OutCount, PullThresh, PushThresh, SetBase, SetCount};
+use crate::replaycore;
+use crate::replaycore::{REPLAY_STATE, VeritasMode};
+use crate::replaycore::transitions;
+use crate::utilcore::displays;
+use crate::utilcore::displays::Port;
+use veritas_core::hal::StateMachine;
+use veritas_core::joybus;
+use veritas_core::joybus::{address_crc, data_crc};
 
-/// Buffered list of controller inputs. 
-pub static mut INPUT_BUFFER: Queue<[u32; 4], 1024> = Queue::new();
+/// Buffered list of controller inputs. Each frame holds the state of all 4 ports.
+static mut INPUT_QUEUE: Queue<[u32; 4], 1024> = Queue::new();
+/// Consumer end of the input buffer. Do not use outside of CORE0!
+static mut INPUT_BUFFER: Option<Consumer<'static, [u32; 4], 1024>> = None;
 
-static mut READ_BYTE_VECTOR: u8 = 0;
+/// Data line of each controller port. The pin above each one mirrors the sample points of incoming bits,
+/// for debugging with a logic analyzer.
+pub(crate) const DATA: [usize; 4] = [PIN_CNT_6, PIN_CNT_9, PIN_CNT_12, PIN_CNT_15];
+/// Goes high when the console powers the controller port.
+pub(crate) const DETECT: [usize; 4] = [PIN_CNT_8, PIN_CNT_11, PIN_CNT_14, PIN_CNT_17];
+const PIO: PioSel = PioSel::Zero;
+const SM: [SmSel; 4] = [SmSel::Zero, SmSel::One, SmSel::Two, SmSel::Three];
+const RST: usize = PIN_CNT_18;
+/// set HIGH to enable
+const RST_EN: usize = PIN_CNT_18_DIR;
+
+/// Ports with a controller plugged in, one bit per port. Ports without one never respond to the console.
+pub static CONNECTED_PORTS: AtomicU8 = AtomicU8::new(0b0001);
+/// Inputs of every port for the current frame.
+static mut CURRENT_FRAME: [u32; 4] = [0; 4];
+/// Set once the current frame has been polled, so the next poll of the first port moves on to the next one.
+static mut FRAME_PRESENTED: bool = false;
+
+/// Size of a controller pak's SRAM.
+pub const MEMPAK_SIZE: usize = 0x8000;
+/// Time in microseconds the console may take to send the next byte of a request, before it's abandoned.
+const PAYLOAD_TIMEOUT_US: u32 = 100;
+
+/// Accessory plugged into the emulated controller.
+#[derive(Debug, Format, PartialEq, Eq, Copy, Clone, Encode, Decode)]
+pub enum ControllerPak {
+    None,
+    Mempak,
+    Rumble,
+}
+
+struct ControllerPaks {
+    /// Accessory in each port's controller. Can be changed by the host at any time, like swapping paks by hand.
+    kinds: [ControllerPak; 4],
+    /// Contents of the emulated mempak. Uploaded by the host before the replay, and written by the game during
+    /// it. There's only one, as each image takes 32KB of RAM.
+    mempak: [u8; MEMPAK_SIZE],
+}
+
+static CONTROLLER_PAKS: SpinMutex<ControllerPaks> = SpinMutex::new(LOCK_CONTROLLER_PAKS, ControllerPaks {
+    kinds: [ControllerPak::None; 4],
+    mempak: [0; MEMPAK_SIZE],
+});
+/// Set when the game identifies a rumble pak, by writing 0x80 to 0x8000.
+static mut RUMBLE_ENABLED: [bool; 4] = [false; 4];
+/// State of each rumble pak's motor.
+static mut RUMBLE_ACTIVE: [bool; 4] = [false; 4];
+/// Set when the address of a pak read/write fails its CRC, and reported in the next status response.
+static mut ADDRESS_CRC_ERROR: [bool; 4] = [false; 4];
+
+/// Joybus responder program, while a replay is running. Do not use outside of CORE0!
+static mut PROGRAM: Option<ProgramHandle> = None;
+static mut READ_BYTES_VECTOR: u8 = 0;
 static mut WRITE_BYTES_VECTOR: u8 = 0;
 
-/// Prepares the device to replay a TAS.
-pub fn initialize() {
-    // Data
-    gpio::set_function(14, FUNCSEL_A::PIO0);
-    gpio::set_pull_down_enable(14, false);
-    gpio::set_pull_up_enable(14, false);
-    
-    // Debug
-    gpio::set_function(15, FUNCSEL_A::PIO0);
-    gpio::set_pull_down_enable(15, false);
-    gpio::set_pull_up_enable(15, false);
-    
-    // Detect
-    gpio::set_function(16, FUNCSEL_A::SIO);
-    gpio::set_pull_down_enable(16, false);
-    gpio::set_pull_up_enable(16, false);
-    gpio::set_input_enable(16, true);
-    gpio::set_output_disable(16, true);
+static TRANSITION_HANDLER: transitions::Handler = transitions::Handler {
+    reset_pin: RST,
+    suspend: stop_responders,
+    resume,
+};
+
+/// Plugs a pak into a port's controller. Returns false if the mempak is already plugged into another port.
+pub fn set_controller_pak(port: usize, pak: ControllerPak) -> bool {
+    CONTROLLER_PAKS.lock(|paks| {
+        let in_use = (0..4).any(|other| other != port && paks.kinds[other] == ControllerPak::Mempak);
+        if port >= 4 || (pak == ControllerPak::Mempak && in_use) {
+            return false;
+        }
+        
+        paks.kinds[port] = pak;
+        
+        true
+    })
+}
+
+/// Unplugs every port's pak, so the next replay can plug the mempak into any port.
+pub fn unplug_controller_paks() {
+    CONTROLLER_PAKS.lock(|paks| paks.kinds = [ControllerPak::None; 4]);
+}
+
+/// Copies part of a mempak image into the emulated mempak. Returns false if it doesn't fit.
+pub fn write_mempak(offset: usize, data: &[u8]) -> bool {
+    if offset + data.len() > MEMPAK_SIZE {
+        return false;
+    }
+    
+    CONTROLLER_PAKS.lock(|paks| paks.mempak[offset..(offset + data.len())].copy_from_slice(data));
+    
+    true
+}
+
+/// Splits the input buffer, keeping the consumer end. Must only be called once, from CORE0.
+pub unsafe fn split_input_buffer() -> Producer<'static, [u32; 4], 1024> {
+    let (producer, consumer) = INPUT_QUEUE.split();
+    INPUT_BUFFER = Some(consumer);
+    
+    producer
+}
+
+#[inline(always)]
+pub(super) unsafe fn dequeue_input() -> Option<[u32; 4]> {
+    INPUT_BUFFER.as_mut().and_then(|buffer| buffer.dequeue())
+}
+
+#[inline(always)]
+fn is_connected(port: usize) -> bool {
+    (CONNECTED_PORTS.load(Ordering::Relaxed) & (1 << port)) != 0
+}
+
+/// The console polls ports in order, so the first connected port is polled first in every frame.
+#[inline(always)]
+fn is_first_port(port: usize) -> bool {
+    (0..port).all(|other| !is_connected(other))
+}
+
+fn initialize() {
+    install_program();
+    configure_pins();
     
+    unsafe {
+        CURRENT_FRAME = dequeue_input().unwrap_or_default();
+        FRAME_PRESENTED = false;
+        
+        update_displays();
+    }
+}
+
+/// Loads the joybus responder, and configures a state machine for each connected port.
+fn install_program() {
     let program = { pio_asm!("
-        .origin 0
     	.wrap_target
     idle:
         mov x, x [2]
         jmp idle
         
-    public read_byte:
+    public read_bytes:
         set pindirs, 0b10
         mov isr, null
-        set x, 7
     inagain:
         wait 1 pin 0
         wait 0 pin 0 [31]
@@ -53,9 +175,7 @@ pub fn initialize() {
         set pins, 0b10
         set pins, 0b00
         
-        jmp x--, inagain
-        push noblock
-        jmp idle
+        jmp inagain
         
         
         
@@ -83,28 +203,107 @@ pub fn initialize() {
         
         .wrap
     ")};
-    p::install_program(&program.program, PioSel::Zero);
+    let connected: heapless::Vec<SmSel, 4> = (0..4).filter(|port| is_connected(*port)).map(|port| SM[port]).collect();
+    let handle = p::install(&program.program, PIO, &connected).expect("joybus program didn't fit");
     unsafe {
-        READ_BYTE_VECTOR = program.public_defines.read_byte as u32 as u8;
-        WRITE_BYTES_VECTOR = program.public_defines.write_bytes as u32 as u8;
-    }
-    
-    let options = [
-        InBase(14),
-        OutBase(14),
-        OutCount(1),
-        SetBase(14),
-        SetCount(2),
-        InShiftdir(ShiftDirection::Left),
-        PullThresh(32),
-        Autopull(true),
-        ClockDiv(10.0),
-        WrapBottom(program.program.wrap.target),
-        WrapTop(program.program.wrap.source),
-    ];
-    p::configure(PioSel::Zero, SmSel::Zero, &options);
-    p::exec(PioSel::Zero, SmSel::Zero, InstructionOperands::SET { destination: SetDestination::PINDIRS, data: 0b10 }); // init input
-    p::start(PioSel::Zero, SmSel::Zero);
+        READ_BYTES_VECTOR = handle.address(program.public_defines.read_bytes as u8);
+        WRITE_BYTES_VECTOR = handle.address(program.public_defines.write_bytes as u8);
+        PROGRAM = Some(handle);
+    }
+    
+    for port in (0..4).filter(|port| is_connected(*port)) {
+        let data = DATA[port] as u8;
+        let options = [
+            InBase(data),
+            OutBase(data),
+            OutCount(1),
+            SetBase(data),
+            SetCount(2),
+            InShiftdir(ShiftDirection::Left),
+            PushThresh(8),
+            Autopush(true),
+            PullThresh(32),
+            Autopull(true),
+            ClockDiv(10.0),
+        ];
+        p::configure(PIO, SM[port], &options);
+    }
+}
+
+fn configure_pins() {
+    for port in (0..4).filter(|port| is_connected(*port)) {
+        // Data
+        gpio::set_function(DATA[port], FUNCSEL_A::PIO0);
+        gpio::set_pull_down_enable(DATA[port], false);
+        gpio::set_pull_up_enable(DATA[port], false);
+        
+        // Debug
+        gpio::set_function(DATA[port] + 1, FUNCSEL_A::PIO0);
+        gpio::set_pull_down_enable(DATA[port] + 1, false);
+        gpio::set_pull_up_enable(DATA[port] + 1, false);
+        
+        // Detect
+        gpio::set_function(DETECT[port], FUNCSEL_A::SIO);
+        gpio::set_pull_down_enable(DETECT[port], false);
+        gpio::set_pull_up_enable(DETECT[port], false);
+        gpio::set_input_enable(DETECT[port], true);
+        gpio::set_output_disable(DETECT[port], true);
+    }
+    
+    gpio::set_as_output(RST, true, false); // Console reset (active-high)
+    gpio::set_low(RST);
+    
+    gpio::set_high(RST_EN);
+}
+
+/// Stops driving the data lines, so an unpowered console isn't fed through them.
+pub fn release_pins() {
+    for port in 0..4 {
+        gpio::set_function(DATA[port], FUNCSEL_A::SIO);
+        gpio::set_as_input(DATA[port], false, false);
+        gpio::set_function(DATA[port] + 1, FUNCSEL_A::SIO);
+        gpio::set_as_input(DATA[port] + 1, false, false);
+    }
+    
+    gpio::set_low(RST);
+}
+
+/// Restarts the state machine of each connected port, and starts listening for requests.
+fn start_responders() {
+    let mut connected = heapless::Vec::<SmSel, 4>::new();
+    for port in (0..4).filter(|port| is_connected(*port)) {
+        p::stop(PIO, SM[port]);
+        p::clear_fifos(PIO, SM[port]);
+        p::restart(PIO, SM[port]);
+        p::exec(PIO, SM[port], InstructionOperands::SET { destination: SetDestination::PINDIRS, data: 0b10 }); // init input
+        unsafe { listen(port); }
+        connected.push(SM[port]).unwrap();
+    }
+    
+    p::start_multiple(PIO, &connected);
+}
+
+fn stop_responders() {
+    p::stop_multiple(PIO, &SM);
+}
+
+/// Re-arms the controllers once a transition has completed.
+fn resume() {
+    configure_pins();
+    
+    unsafe {
+        // The frame dequeued before the transition hasn't been polled yet
+        FRAME_PRESENTED = false;
+    }
+    start_responders();
+}
+
+fn update_displays() {
+    unsafe {
+        for port in (0..4).filter(|port| is_connected(*port)) {
+            displays::set_display(Port::from(port as u8), &CURRENT_FRAME[port].to_be_bytes()[..2]);
+        }
+    }
 }
 
 pub fn run(delay: &mut Delay) {
@@ -113,84 +312,249 @@ pub fn run(delay: &mut Delay) {
         
         info!("starting N64 replay..");
         
-        while gpio::is_low(16) {}
+        if REPLAY_STATE.lock(|state| state.wait_for_console) {
+            release_pins();
+            let detected = replaycore::wait_for_console(VeritasMode::ReplayN64, delay);
+            configure_pins();
+            
+            if !detected {
+                stop(delay);
+                return;
+            }
+        }
+        
+        // Wait for the console to power every connected port
+        while replaycore::mode() == VeritasMode::ReplayN64 && (0..4).any(|port| is_connected(port) && gpio::is_low(DETECT[port])) {
+            nop();
+        }
         delay.delay_ms(100);
         
-        while VERITAS_MODE == VeritasMode::ReplayN64 {
-            let cmd = read_blocking();
-            match cmd {
-                0xFF | 0x01 => {
-                    //delay.delay_us(4);
-                    let state = INPUT_BUFFER.dequeue().unwrap_or_default();
-                    write_blocking(&state[0].to_be_bytes());
-                    delay.delay_us(16);
-                },
-                0x00 => {
-                    //delay.delay_us(4);
-                    write_blocking(&[0x05, 0x00, 0x02]);
-                    delay.delay_us(16);
-                },
-                0x02 | 0x03 => {
-                    delay.delay_us(150);
+        // Transitions scheduled before the first input are performed before the controllers are armed.
+        transitions::initialize(&TRANSITION_HANDLER);
+        if let Some(tra) = REPLAY_STATE.lock(|state| state.next_transition()) {
+            transitions::begin(tra);
+        } else {
+            start_responders();
+        }
+        
+        // The console talks to one port at a time, so each request is answered before checking the others.
+        // While a transition is in progress the state machines are stopped, so nothing arrives.
+        while replaycore::mode() == VeritasMode::ReplayN64 {
+            for port in (0..4).filter(|port| is_connected(*port)) {
+                if let Some(cmd) = p::fifo_read(PIO, SM[port]) {
+                    if respond(port, cmd as u8, delay) {
+                        listen(port);
+                    }
                 }
-                _ => ()
             }
         }
+        
+        stop(delay);
     }
 }
 
-#[inline(always)]
-unsafe fn read_blocking() -> u32 {
-    p::exec(PioSel::Zero, SmSel::Zero, InstructionOperands::JMP { condition: JmpCondition::Always, address: READ_BYTE_VECTOR });
+/// Tears down the replay and returns the device to its idle state.
+fn stop(delay: &mut Delay) {
+    transitions::abort();
+    stop_responders();
     
-    loop {
-        match p::fifo_read(PioSel::Zero, SmSel::Zero) {
-            Some(data) => return data,
-            None => ()
+    unsafe {
+        if let Some(program) = PROGRAM.take() {
+            p::uninstall(program);
+        }
+        while dequeue_input().is_some() {}
+        REPLAY_STATE.lock(|state| state.reset());
+        
+        CURRENT_FRAME = [0; 4];
+        for port in 0..4 {
+            reset_controller(port);
+            displays::set_display(Port::from(port as u8), &[0x00, 0x00]);
         }
     }
+    unplug_controller_paks();
+    
+    release_pins();
+    delay.delay_ms(10);
+    gpio::set_low(RST_EN);
+    
+    info!("stopped N64 replay");
 }
 
-#[inline(always)]
-unsafe fn write_blocking(data: &[u8]) {
-    p::exec(PioSel::Zero, SmSel::Zero, InstructionOperands::JMP { condition: JmpCondition::Always, address: WRITE_BYTES_VECTOR });
+/// Moves every port on to the next frame, once the current one has been polled. Returns false if the
+/// replay has ended, or a transition has started.
+unsafe fn next_frame() -> bool {
+    if !FRAME_PRESENTED {
+        FRAME_PRESENTED = true;
+        return true;
+    }
     
-    for byte in data {
-        let encoded = encode_joybus(*byte);
-        
-        loop {
-            if p::fifo_write(PioSel::Zero, SmSel::Zero, encoded) {
-                break;
+    // The input polled during the previous frame has now been consumed.
+    if REPLAY_STATE.lock(|state| state.consume_input()) {
+        replaycore::set_mode(VeritasMode::Idle);
+        log::defer(Code::ReplayEnded, [0; 2]);
+        return false;
+    }
+    
+    CURRENT_FRAME = dequeue_input().unwrap_or_else(|| {
+        events::record_underrun();
+        Default::default()
+    });
+    update_displays();
+    
+    if let Some(tra) = REPLAY_STATE.lock(|state| state.next_transition()) {
+        transitions::begin(tra);
+        return false;
+    }
+    
+    true
+}
+
+/// Answers a request from the console, once its command byte has been received. Returns false if the
+/// responders were stopped, and shouldn't listen for another request.
+unsafe fn respond(port: usize, cmd: u8, delay: &mut Delay) -> bool {
+    match cmd {
+        0x01 => {
+            if is_first_port(port) && !next_frame() {
+                return false;
             }
-        }
+            
+            //delay.delay_us(4);
+            write_blocking(port, &CURRENT_FRAME[port].to_be_bytes());
+            delay.delay_us(16);
+        },
+        0x00 | 0xFF => {
+            if cmd == 0xFF {
+                reset_controller(port);
+            }
+            
+            //delay.delay_us(4);
+            write_blocking(port, &[0x05, 0x00, pak_status(port)]);
+            delay.delay_us(16);
+        },
+        0x02 => {
+            let mut address = [0u8; 2];
+            if !read_payload(port, &mut address) {
+                return true;
+            }
+            
+            let mut response = [0u8; 33];
+            response[32] = pak_read(port, u16::from_be_bytes(address), (&mut response[..32]).try_into().unwrap());
+            write_blocking(port, &response);
+            delay.delay_us(16);
+        },
+        0x03 => {
+            let mut request = [0u8; 34];
+            if !read_payload(port, &mut request) {
+                return true;
+            }
+            
+            let crc = pak_write(port, u16::from_be_bytes([request[0], request[1]]), request[2..].try_into().unwrap());
+            write_blocking(port, &[crc]);
+            delay.delay_us(16);
+        },
+        _ => ()
     }
     
-    loop {
-        if p::fifo_write(PioSel::Zero, SmSel::Zero, 0x3FFFFFFF) { // controller stop bit
-            break;
-        }
+    true
+}
+
+/// Puts the controller back into its power-on state, as requested by command 0xFF.
+unsafe fn reset_controller(port: usize) {
+    RUMBLE_ENABLED[port] = false;
+    RUMBLE_ACTIVE[port] = false;
+    ADDRESS_CRC_ERROR[port] = false;
+}
+
+/// Third byte of the status response: whether a pak is plugged in, and if the last pak address was corrupted.
+unsafe fn pak_status(port: usize) -> u8 {
+    let mut status = if pak_kind(port) == ControllerPak::None { 0x02 } else { 0x01 };
+    if ADDRESS_CRC_ERROR[port] {
+        status |= 0x04;
     }
+    ADDRESS_CRC_ERROR[port] = false;
     
-    loop {
-        if p::is_tx_empty(PioSel::Zero, SmSel::Zero) {
-            break;
-        }
+    status
+}
+
+/// Returns the block address of a pak read/write, or None if its CRC doesn't match.
+unsafe fn check_address(port: usize, address: u16) -> Option<u16> {
+    let block = address & !0x1F;
+    if address_crc(block) == (address & 0x1F) as u8 {
+        Some(block)
+    } else {
+        ADDRESS_CRC_ERROR[port] = true;
+        None
     }
 }
 
-#[inline(always)]
-fn encode_joybus(mut data: u8) -> u32 {
-    let mut out = 0;
-    for _ in 0..8 {
-        out <<= 4;
-        if (data & 0x80) != 0 {
-            out |= 0b0111;
-        } else {
-            out |= 0b0001;
-        }
-        
-        data <<= 1;
+/// Handles command 0x02, filling `data` with the 32 bytes at `address`, and returning the data CRC.
+unsafe fn pak_read(port: usize, address: u16, data: &mut [u8; 32]) -> u8 {
+    if let Some(block) = check_address(port, address) {
+        CONTROLLER_PAKS.lock(|paks| match paks.kinds[port] {
+            ControllerPak::None => (),
+            ControllerPak::Mempak => if (block as usize) < MEMPAK_SIZE {
+                data.copy_from_slice(&paks.mempak[(block as usize)..(block as usize + 32)]);
+            },
+            ControllerPak::Rumble => if (0x8000..0xC000).contains(&block) && RUMBLE_ENABLED[port] {
+                data.fill(0x80);
+            },
+        });
+    }
+    
+    pak_crc(port, data)
+}
+
+/// Handles command 0x03, storing the 32 bytes in `data` at `address`, and returning the data CRC.
+unsafe fn pak_write(port: usize, address: u16, data: &[u8; 32]) -> u8 {
+    if let Some(block) = check_address(port, address) {
+        CONTROLLER_PAKS.lock(|paks| match paks.kinds[port] {
+            ControllerPak::None => (),
+            ControllerPak::Mempak => if (block as usize) < MEMPAK_SIZE {
+                paks.mempak[(block as usize)..(block as usize + 32)].copy_from_slice(data);
+            },
+            ControllerPak::Rumble => match block {
+                0x8000..=0xBFFF => RUMBLE_ENABLED[port] = data[31] == 0x80,
+                0xC000..=0xFFFF if RUMBLE_ENABLED[port] => RUMBLE_ACTIVE[port] = data[31] & 0x01 != 0,
+                _ => (),
+            },
+        });
     }
     
-    out
+    pak_crc(port, data)
+}
+
+/// CRC of a pak data block. Inverted when there's no pak, which is how the console detects an empty slot.
+unsafe fn pak_crc(port: usize, data: &[u8; 32]) -> u8 {
+    let crc = data_crc(data);
+    if pak_kind(port) == ControllerPak::None {
+        !crc
+    } else {
+        crc
+    }
+}
+
+#[inline(always)]
+fn pak_kind(port: usize) -> ControllerPak {
+    CONTROLLER_PAKS.lock(|paks| paks.kinds[port])
+}
+
+/// Starts listening for the next request on a port.
+#[inline(always)]
+unsafe fn listen(port: usize) {
+    state_machine(port).jump(READ_BYTES_VECTOR);
+}
+
+#[inline(always)]
+unsafe fn read_payload(port: usize, buf: &mut [u8]) -> bool {
+    joybus::read_payload(&mut state_machine(port), &SystemTimer, buf, PAYLOAD_TIMEOUT_US)
+}
+
+#[inline(always)]
+unsafe fn write_blocking(port: usize, data: &[u8]) {
+    joybus::write_response(&mut state_machine(port), WRITE_BYTES_VECTOR, data);
+}
+
+#[inline(always)]
+fn state_machine(port: usize) -> p::StateMachine {
+    p::StateMachine { pio: PIO, sm: SM[port] }
 }
\ No newline at end of file
@@ -1,35 +1,158 @@
-use cortex_m::asm::{delay, nop};
+use core::sync::atomic::{AtomicU32, Ordering};
+use cortex_m::asm::nop;
 use cortex_m::delay::Delay;
-use defmt::info;
-use heapless::spsc::Queue;
+use crate::{events, info, log};
+use crate::log::Code;
+use crate::events::Event;
+use heapless::spsc::{Consumer, Producer, Queue};
+use pio::{InstructionOperands, JmpCondition, SetDestination};
+use pio_proc::pio_asm;
 use rp2040_pac::Interrupt::{IO_IRQ_BANK0, TIMER_IRQ_0};
-use rp2040_pac::{IO_BANK0, PPB, TIMER};
-use crate::hal::gpio;
+use rp2040_pac::io_bank0::gpio::gpio_ctrl::FUNCSEL_A;
+use crate::hal::{gpio, interrupts, pio as p};
 use crate::hal::gpio::{PIN_CNT_18, PIN_CNT_18_DIR, PIN_CNT_3, PIN_CNT_4, PIN_CNT_5, PIN_CNT_6, PIN_CNT_7, PIN_DETECT};
-use crate::replaycore::{Transition, VERITAS_MODE, REPLAY_STATE, VeritasMode};
+use crate::hal::interrupts::Edge;
+use crate::hal::pio::{PioSel, ProgramHandle, ShiftDirection, SmSel};
+use crate::hal::pio::PioOption::{Autopull, ClockDiv, InBase, InShiftdir, JmpPin, OutBase, OutCount, OutShiftdir, SetBase, SetCount};
+use crate::replaycore;
+use crate::replaycore::{REPLAY_STATE, VeritasMode};
+use crate::replaycore::transitions;
+use crate::systems::snes;
 use crate::utilcore::displays;
 use crate::utilcore::displays::Port;
 use crate::VTABLE0;
+use crate::hal::pio::StateMachine;
+use crate::hal::timer::SystemTimer;
+use veritas_core::nes::{LatchFilter, nes_frame_word, present, snes_frame_word};
 
-/// Buffered list of controller inputs. 
-pub static mut INPUT_BUFFER: Queue<[u8; 2], 1024> = Queue::new();
+/// Buffered list of controller inputs. Each frame holds 1 byte for each port.
+static mut INPUT_QUEUE: Queue<[u8; 2], 1024> = Queue::new();
+/// Consumer end of the input buffer. Do not use outside of CORE0!
+static mut INPUT_BUFFER: Option<Consumer<'static, [u8; 2], 1024>> = None;
 
-pub static mut LATCH_FILTER_US: u32 = 8000; //TODO: Write a detection procedure to relay to the user what the time between latch and 8th clock is.
+pub static LATCH_FILTER_US: AtomicU32 = AtomicU32::new(8000); //TODO: Write a detection procedure to relay to the user what the time between latch and 8th clock is.
 static mut OVERREAD: u8 = 1;
 
-static mut ALARM_ACTIVATED: bool = false;
-static mut FRAME_INPUT: [u8; 2] = [0xFF, 0xFF];
-static mut WORKING_INPUT: [u8; 2] = [0xFF, 0xFF];
+/// Groups latches into frames, while a replay is running. Do not use outside of CORE0!
+static mut FILTER: LatchFilter = LatchFilter::new(0);
+static mut CONSOLE: Console = Console::Nes;
+/// Data each port's state machine shifts out for the current frame, MSB first, followed by overread bits.
+static mut FRAME_WORDS: [u32; 2] = [u32::MAX; 2];
+/// Shift register program, while a replay is running. Do not use outside of CORE0!
+static mut PROGRAM: Option<ProgramHandle> = None;
+static mut PROGRAM_START: u8 = 0;
 
-const SER: [usize; 2] = [PIN_CNT_5, PIN_CNT_4];
-const CLK: [usize; 2] = [PIN_CNT_7, PIN_CNT_6];
-const LAT: usize = PIN_CNT_3;
+pub(crate) const SER: [usize; 2] = [PIN_CNT_5, PIN_CNT_4];
+pub(crate) const CLK: [usize; 2] = [PIN_CNT_7, PIN_CNT_6];
+pub(crate) const LAT: usize = PIN_CNT_3;
 const RST: usize = PIN_CNT_18;
 /// set HIGH to enable
 const RST_EN: usize = PIN_CNT_18_DIR;
 
+const PIO: PioSel = PioSel::Zero;
+/// State machine emulating each port's shift register.
+const SM: [SmSel; 2] = [SmSel::Zero, SmSel::One];
+
+static TRANSITION_HANDLER: transitions::Handler = transitions::Handler {
+    reset_pin: RST,
+    suspend: disable_interrupts,
+    resume,
+};
+
+/// Consoles whose controllers are read through a latched shift register.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Console {
+    Nes,
+    /// Same protocol as the NES, but each controller shifts out 16 bits.
+    Snes,
+}
+impl Console {
+    fn mode(self) -> VeritasMode {
+        match self {
+            Console::Nes => VeritasMode::ReplayNes,
+            Console::Snes => VeritasMode::ReplaySnes,
+        }
+    }
+    
+    /// Number of bytes of input for each port, per frame.
+    fn port_bytes(self) -> usize {
+        match self {
+            Console::Nes => 1,
+            Console::Snes => 2,
+        }
+    }
+}
+
 /// Prepares the device to replay a TAS.
 pub fn initialize() {
+    install_program();
+    configure_pins();
+    
+    unsafe {
+        FRAME_WORDS = next_frame().unwrap_or([u32::MAX; 2]);
+        update_displays();
+        
+        FILTER = LatchFilter::new(LATCH_FILTER_US.load(Ordering::Relaxed));
+    }
+}
+
+/// Loads the shift register program, and configures a state machine for each port.
+fn install_program() {
+    // The state machine presents the first bit when the console latches, then shifts out another bit after
+    // each falling clock edge. The latch is polled while waiting for the clock, so a read which is cut short
+    // can't leave the shift register out of step. If no new frame has been queued when the console latches,
+    // the current frame (kept in X) is presented again.
+    let program = pio_asm!("
+        .wrap_target
+    public start:
+        jmp pin latched         ; JMP pin is the shared latch
+        jmp start
+    latched:
+        pull noblock
+        mov x, osr
+        out pins, 1
+    latch_high:
+        jmp pin latch_high
+    wait_clock:
+        jmp pin latched
+        mov isr, null
+        in pins, 1              ; IN base is this port's clock
+        mov y, isr
+        jmp !y clock_low
+        jmp wait_clock
+    clock_low:
+        nop [31]                ; clock filter, ~1.2us
+        nop [31]
+        nop [31]
+        out pins, 1
+        wait 1 pin 0
+        jmp wait_clock
+        .wrap
+    ");
+    let handle = p::install(&program.program, PIO, &SM).expect("shift register program didn't fit");
+    unsafe {
+        PROGRAM_START = handle.address(program.public_defines.start as u8);
+        PROGRAM = Some(handle);
+    }
+    
+    for port in 0..2 {
+        let options = [
+            InBase(CLK[port] as u8),
+            OutBase(SER[port] as u8),
+            OutCount(1),
+            SetBase(SER[port] as u8),
+            SetCount(1),
+            JmpPin(LAT as u8),
+            OutShiftdir(ShiftDirection::Left),
+            InShiftdir(ShiftDirection::Left),
+            Autopull(false),
+            ClockDiv(2.0),
+        ];
+        p::configure(PIO, SM[port], &options);
+    }
+}
+
+fn configure_pins() {
     gpio::set_low(PIN_DETECT);
     gpio::set_as_input(PIN_DETECT, false, true);
     
@@ -48,66 +171,139 @@ pub fn initialize() {
     gpio::set_low(RST);
     
     gpio::set_high(RST_EN);
+}
+
+/// Stops driving the controller lines, so an unpowered console isn't fed through its serial inputs.
+fn release_pins() {
+    for pin in SER {
+        gpio::set_as_input(pin, false, false);
+    }
     
+    gpio::set_low(RST);
+}
+
+/// Restarts both state machines from the top of the program, with the current frame queued, and hands the
+/// serial pins over to them.
+fn start_shift_registers() {
     unsafe {
-        FRAME_INPUT = INPUT_BUFFER.dequeue().unwrap_or([0xFF, 0xFF]);
+        p::stop_multiple(PIO, &SM);
         
-        displays::set_display(Port::Display0, &[FRAME_INPUT[0] ^ 0xFF]);
-        displays::set_display(Port::Display1, &[FRAME_INPUT[1] ^ 0xFF]);
+        for port in 0..2 {
+            p::clear_fifos(PIO, SM[port]);
+            p::restart(PIO, SM[port]);
+            p::exec(PIO, SM[port], InstructionOperands::SET { destination: SetDestination::PINS, data: 1 });
+            p::exec(PIO, SM[port], InstructionOperands::SET { destination: SetDestination::PINDIRS, data: 1 });
+            p::exec(PIO, SM[port], InstructionOperands::JMP { condition: JmpCondition::Always, address: PROGRAM_START });
+            p::fifo_write(PIO, SM[port], FRAME_WORDS[port]);
+            
+            gpio::set_function(SER[port], FUNCSEL_A::PIO0);
+        }
         
-        ALARM_ACTIVATED = false;
+        p::start_multiple(PIO, &SM);
     }
 }
 
 fn enable_interrupts() {
+    start_shift_registers();
+    
     cortex_m::interrupt::free(|_| unsafe {
         VTABLE0.register_handler(IO_IRQ_BANK0 as usize, io_irq_bank0_handler);
         
-        (*IO_BANK0::ptr()).intr[1].write(|w| w.gpio7_edge_low().bit(true));
-        (*IO_BANK0::ptr()).intr[1].write(|w| w.gpio6_edge_low().bit(true));
-        (*IO_BANK0::ptr()).intr[1].write(|w| w.gpio3_edge_high().bit(true));
-        
-        (*IO_BANK0::ptr()).proc0_inte[1].modify(|_, w| w.gpio7_edge_low().bit(true)); // CLK[0]
-        (*IO_BANK0::ptr()).proc0_inte[1].modify(|_, w| w.gpio6_edge_low().bit(true)); // CLK[1]
-        (*IO_BANK0::ptr()).proc0_inte[1].modify(|_, w| w.gpio3_edge_high().bit(true)); // LAT
-        (*PPB::ptr()).nvic_iser.write(|w| w.bits(1 << (IO_IRQ_BANK0 as u32)));
+        interrupts::clear_gpio_intr(LAT, Edge::EdgeHigh);
+        interrupts::enable_gpio_intr(LAT, Edge::EdgeHigh);
+        interrupts::enable_nvic(IO_IRQ_BANK0);
         
         
         VTABLE0.register_handler(TIMER_IRQ_0 as usize, timer_irq_0_handler);
         
-        (*TIMER::ptr()).intr.write(|w| w.alarm_0().bit(true));
-        
-        (*TIMER::ptr()).inte.modify(|_, w| w.alarm_0().bit(true));
-        (*PPB::ptr()).nvic_iser.write(|w| w.bits(1 << (TIMER_IRQ_0 as u32)));
+        interrupts::clear_alarm_intr(0);
+        interrupts::enable_alarm_intr(0);
+        interrupts::enable_nvic(TIMER_IRQ_0);
     });
 }
 
+/// Re-arms the controllers once a transition has completed.
+fn resume() {
+    configure_pins();
+    
+    unsafe { FILTER.end(); }
+    enable_interrupts();
+}
+
 fn disable_interrupts() {
-    cortex_m::interrupt::free(|_| unsafe {
-        (*PPB::ptr()).nvic_icer.write(|w| w.bits(1 << (IO_IRQ_BANK0 as u32)));
-        (*PPB::ptr()).nvic_icer.write(|w| w.bits(1 << (TIMER_IRQ_0 as u32)));
-        
-        (*IO_BANK0::ptr()).proc0_inte[1].modify(|_, w| w.gpio7_edge_low().bit(false)); // CLK[0]
-        (*IO_BANK0::ptr()).proc0_inte[1].modify(|_, w| w.gpio6_edge_low().bit(false)); // CLK[1]
-        (*IO_BANK0::ptr()).proc0_inte[1].modify(|_, w| w.gpio3_edge_high().bit(false)); // LAT
+    cortex_m::interrupt::free(|_| {
+        interrupts::disable_nvic(IO_IRQ_BANK0);
+        interrupts::disable_nvic(TIMER_IRQ_0);
         
-        (*TIMER::ptr()).inte.modify(|r, w| w.bits(r.bits() & 0b1110));
+        interrupts::disable_gpio_intr(LAT, Edge::EdgeHigh);
+        interrupts::disable_alarm_intr(0);
     });
+    
+    p::stop_multiple(PIO, &SM);
 }
 
+/// Splits the input buffer, keeping the consumer end. Must only be called once, from CORE0.
+pub unsafe fn split_input_buffer() -> Producer<'static, [u8; 2], 1024> {
+    let (producer, consumer) = INPUT_QUEUE.split();
+    INPUT_BUFFER = Some(consumer);
+    
+    producer
+}
 
-pub fn run(delay: &mut Delay) {
+#[inline(always)]
+pub(super) unsafe fn dequeue_input() -> Option<[u8; 2]> {
+    INPUT_BUFFER.as_mut().and_then(|buffer| buffer.dequeue())
+}
+
+/// Takes the next frame from the input buffer, as the data shifted out by each port.
+#[link_section = ".ram_code"]
+#[inline(always)]
+unsafe fn next_frame() -> Option<[u32; 2]> {
+    let overread = OVERREAD != 0;
+    
+    match CONSOLE {
+        Console::Nes => dequeue_input()
+            .map(|input| [0, 1].map(|port| nes_frame_word(input[port], overread))),
+        Console::Snes => snes::dequeue_input()
+            .map(|input| [0, 1].map(|port| snes_frame_word(u16::from_be_bytes([input[port * 2], input[port * 2 + 1]]), overread))),
+    }
+}
+
+#[link_section = ".ram_code"]
+#[inline(always)]
+unsafe fn update_displays() {
+    let len = CONSOLE.port_bytes();
+    
+    for (port, display) in [Port::Display0, Port::Display1].into_iter().enumerate() {
+        let data = FRAME_WORDS[port].to_be_bytes().map(|byte| byte ^ 0xFF);
+        displays::set_display(display, &data[..len]);
+    }
+}
+
+pub fn run(console: Console, delay: &mut Delay) {
     unsafe {
+        CONSOLE = console;
+        let mode = console.mode();
+        
         initialize();
         
-        info!("trans: {}", REPLAY_STATE.transitions.len());
-        info!("first trans: {:?}", REPLAY_STATE.transitions.first());
-        let first = INPUT_BUFFER.peek().unwrap_or(&[0xAA, 0x55]);
-        info!("first input: {:02X} {:02X}", first[0], first[1]);
+        let (wait_for_console, use_initial_reset) = REPLAY_STATE.lock(|state| (state.wait_for_console, state.use_initial_reset));
         
-        info!("starting NES replay..");
+        info!("starting NES/SNES replay..");
         
-        if REPLAY_STATE.use_initial_reset {
+        if wait_for_console {
+            release_pins();
+            let detected = replaycore::wait_for_console(mode, delay);
+            configure_pins();
+            
+            if !detected {
+                stop(delay);
+                return;
+            }
+        }
+        
+        if use_initial_reset {
+            events::record(Event::InitialReset);
             gpio::set_high(RST);
             delay.delay_ms(50);
             gpio::set_low(RST);
@@ -115,81 +311,56 @@ pub fn run(delay: &mut Delay) {
         
         delay.delay_ms(5);
         
-        enable_interrupts();
-        
-        while VERITAS_MODE == VeritasMode::ReplayNes {
-            nop();
+        // Transitions scheduled before the first input are performed before the controllers are armed.
+        transitions::initialize(&TRANSITION_HANDLER);
+        if let Some(tra) = REPLAY_STATE.lock(|state| state.next_transition()) {
+            transitions::begin(tra);
+        } else {
+            enable_interrupts();
         }
         
-        disable_interrupts();
-        while !INPUT_BUFFER.is_empty() {
-            INPUT_BUFFER.dequeue().unwrap_or_default();
+        while replaycore::mode() == mode {
+            nop();
         }
-        REPLAY_STATE.reset();
         
-        displays::set_display(Port::Display0, &[0x00]);
-        displays::set_display(Port::Display1, &[0x00]);
-        
-        gpio::set_low(RST);
-        delay.delay_ms(10);
-        gpio::set_low(RST_EN);
-        
-        info!("stopped NES replay");
+        stop(delay);
     }
 }
 
-#[link_section = ".ram_code"]
-#[inline(always)]
-unsafe fn latch() {
-    if !ALARM_ACTIVATED {
-        ALARM_ACTIVATED = true;
-        (*TIMER::ptr()).alarm0.write(|w| w.bits((*TIMER::ptr()).timerawl.read().bits().wrapping_add(LATCH_FILTER_US)));
-    }
-    
-    WORKING_INPUT = FRAME_INPUT;
+/// Tears down the replay and returns the device to its idle state.
+fn stop(delay: &mut Delay) {
+    transitions::abort();
+    disable_interrupts();
     
-    // set first bit's state
-    for i in 0..2 {
-        if WORKING_INPUT[i] & 0x80 != 0 {
-            gpio::set_high(SER[i]);
-        } else {
-            gpio::set_low(SER[i]);
+    unsafe {
+        if let Some(program) = PROGRAM.take() {
+            p::uninstall(program);
         }
+        while dequeue_input().is_some() {}
+        while snes::dequeue_input().is_some() {}
+        REPLAY_STATE.lock(|state| state.reset());
     }
-}
-
-#[link_section = ".ram_code"]
-#[inline(always)]
-unsafe fn clock(cnt: usize) {
-    WORKING_INPUT[cnt] <<= 1;
-    WORKING_INPUT[cnt] |= OVERREAD;
     
-    delay(200); // CLOCK FILTER
+    displays::set_display(Port::Display0, &[0x00, 0x00]);
+    displays::set_display(Port::Display1, &[0x00, 0x00]);
     
-    if WORKING_INPUT[cnt] & 0x80 != 0 {
-        gpio::set_high(SER[cnt]);
-    } else {
-        gpio::set_low(SER[cnt]);
-    }
+    release_pins();
+    delay.delay_ms(10);
+    gpio::set_low(RST_EN);
+    
+    info!("stopped NES/SNES replay");
 }
 
 #[link_section = ".ram_code"]
 extern "C" fn io_irq_bank0_handler() {
     unsafe {
-        let io_bank0 = &(*IO_BANK0::ptr());
-        
-        if io_bank0.proc0_ints[1].read().gpio3_edge_high().bits() { // LAT
-            latch();
-            
-            io_bank0.intr[1].write(|w| w.gpio3_edge_high().bit(true));
-        } else if io_bank0.proc0_ints[1].read().gpio7_edge_low().bits() { // CLK[0]
-            clock(0);
-            
-            io_bank0.intr[1].write(|w| w.gpio7_edge_low().bit(true));
-        } else if io_bank0.proc0_ints[1].read().gpio6_edge_low().bits() { // CLK[1]
-            clock(1);
+        if interrupts::status_gpio_intr(LAT, Edge::EdgeHigh) {
+            // The first latch of a frame starts the filter, after which the next frame is queued.
+            if FILTER.latch(&SystemTimer) {
+                interrupts::arm_alarm(0, FILTER.filter_us());
+            }
             
-            io_bank0.intr[1].write(|w| w.gpio6_edge_low().bit(true));
+            interrupts::clear_gpio_intr(LAT, Edge::EdgeHigh);
         }
     }
 }
@@ -197,36 +368,26 @@ extern "C" fn io_irq_bank0_handler() {
 #[link_section = ".ram_code"]
 extern "C" fn timer_irq_0_handler() {
     unsafe {
-        ALARM_ACTIVATED = false;
-        
-        if let Some(tra) = REPLAY_STATE.next_transition() {
-            match tra {
-                Transition::SoftReset => cortex_m::interrupt::free(|_| {
-                    disable_interrupts();
-                    
-                    gpio::set_high(RST);
-                    delay(5332558);
-                    gpio::set_low(RST);
-                    delay(10665);
-                    
-                    enable_interrupts();
-                }),
-                _ => (),
-            }
+        FILTER.end();
+        
+        // The input latched during this frame has now been consumed.
+        if REPLAY_STATE.lock(|state| state.consume_input()) {
+            replaycore::set_mode(VeritasMode::Idle);
+            log::defer(Code::ReplayEnded, [0; 2]);
         } else {
-            FRAME_INPUT = INPUT_BUFFER.dequeue().unwrap_or([0xFF, 0xFF]);
+            FRAME_WORDS = next_frame().unwrap_or_else(|| {
+                events::record_underrun();
+                [u32::MAX; 2]
+            });
+            present(&mut SM.map(|sm| StateMachine { pio: PIO, sm }), FRAME_WORDS);
             
-            displays::set_display(Port::Display0, &[FRAME_INPUT[0] ^ 0xFF]);
-            displays::set_display(Port::Display1, &[FRAME_INPUT[1] ^ 0xFF]);
+            update_displays();
             
-            if REPLAY_STATE.index_cur == REPLAY_STATE.index_len {
-                VERITAS_MODE = VeritasMode::Idle;
-                info!("Replay ended!");
-            } else {
-                REPLAY_STATE.index_cur += 1;
+            if let Some(tra) = REPLAY_STATE.lock(|state| state.next_transition()) {
+                transitions::begin(tra);
             }
         }
         
-        (*TIMER::ptr()).intr.write(|w| w.alarm_0().bit(true));
+        interrupts::clear_alarm_intr(0);
     }
-}
\ No newline at end of file
+}
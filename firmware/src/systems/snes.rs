@@ -0,0 +1,28 @@
+use cortex_m::delay::Delay;
+use heapless::spsc::{Consumer, Producer, Queue};
+use crate::systems::nes;
+use crate::systems::nes::Console;
+
+/// Buffered list of controller inputs. Each frame holds 2 bytes for each port.
+static mut INPUT_QUEUE: Queue<[u8; 4], 1024> = Queue::new();
+/// Consumer end of the input buffer. Do not use outside of CORE0!
+static mut INPUT_BUFFER: Option<Consumer<'static, [u8; 4], 1024>> = None;
+
+/// Splits the input buffer, keeping the consumer end. Must only be called once, from CORE0.
+pub unsafe fn split_input_buffer() -> Producer<'static, [u8; 4], 1024> {
+    let (producer, consumer) = INPUT_QUEUE.split();
+    INPUT_BUFFER = Some(consumer);
+    
+    producer
+}
+
+/// Takes the next frame from the input buffer. Only used by [`nes`], which runs the replay.
+#[inline(always)]
+pub unsafe fn dequeue_input() -> Option<[u8; 4]> {
+    INPUT_BUFFER.as_mut().and_then(|buffer| buffer.dequeue())
+}
+
+/// The SNES reads its controllers the same way as the NES, so the replay is handled by [`nes`].
+pub fn run(delay: &mut Delay) {
+    nes::run(Console::Snes, delay);
+}
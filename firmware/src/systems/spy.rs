@@ -0,0 +1,224 @@
+use alloc::vec::Vec;
+use core::sync::atomic::Ordering;
+use heapless::spsc::{Consumer, Producer, Queue};
+use pio::{InstructionOperands, JmpCondition};
+use pio_proc::pio_asm;
+use crate::info;
+use crate::hal::{gpio, pio as p};
+use crate::hal::gpio::Pins;
+use crate::hal::pio::{PioSel, ProgramHandle, ShiftDirection, SmSel};
+use crate::hal::pio::PioOption::{Autopull, Autopush, ClockDiv, InBase, InShiftdir, JmpPin, OutShiftdir, PushThresh};
+use crate::replaycore;
+use crate::replaycore::VeritasMode;
+use crate::systems::{genesis, nes};
+use crate::utilcore::displays;
+use crate::utilcore::displays::Port;
+use crate::hal::timer::SystemTimer;
+use veritas_core::genesis::{PortPins, Spy};
+use veritas_core::hal::Gpio;
+use veritas_core::nes::LatchFilter;
+
+/// Frames read from the controllers. Each holds 2 bytes for each port, with a NES or Genesis controller's
+/// input in the first byte and 0xFF in the second, so every console shares the SNES layout.
+static mut SPY_QUEUE: Queue<[u8; 4], 256> = Queue::new();
+/// Producer end of the spied frames. Do not use outside of CORE0!
+static mut PRODUCER: Option<Producer<'static, [u8; 4], 256>> = None;
+/// Consumer end of the spied frames. Do not use outside of CORE1!
+static mut CONSUMER: Option<Consumer<'static, [u8; 4], 256>> = None;
+
+const PIO: PioSel = PioSel::Zero;
+/// State machine sampling each port's serial line.
+const SM: [SmSel; 2] = [SmSel::Zero, SmSel::One];
+// The sampler reads each port's clock 2 pins above its serial line, which is its IN base
+const _: () = assert!(nes::CLK[0] == nes::SER[0] + 2 && nes::CLK[1] == nes::SER[1] + 2);
+
+/// Consoles whose controllers can be read while they're plugged through the device.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Console {
+    Nes,
+    Snes,
+    Genesis,
+}
+impl Console {
+    fn mode(self) -> VeritasMode {
+        match self {
+            Console::Nes => VeritasMode::SpyNes,
+            Console::Snes => VeritasMode::SpySnes,
+            Console::Genesis => VeritasMode::SpyGenesis,
+        }
+    }
+    
+    /// Number of bytes of input for each port, per frame.
+    fn port_bytes(self) -> usize {
+        match self {
+            Console::Nes => 1,
+            Console::Snes | Console::Genesis => 2,
+        }
+    }
+}
+
+/// Splits the spied frames between the cores. Must be called once, before core1 is started.
+pub unsafe fn split_queue() {
+    let (producer, consumer) = SPY_QUEUE.split();
+    PRODUCER = Some(producer);
+    CONSUMER = Some(consumer);
+}
+
+/// Takes up to `max_frames` of the oldest spied frames, 4 bytes each. Must only be called from CORE1.
+pub fn take_inputs(max_frames: usize) -> Vec<u8> {
+    let mut inputs = Vec::new();
+    
+    if let Some(consumer) = unsafe { CONSUMER.as_mut() } {
+        for frame in core::iter::from_fn(|| consumer.dequeue()).take(max_frames) {
+            inputs.extend_from_slice(&frame);
+        }
+    }
+    
+    inputs
+}
+
+/// Drops any frames left over from an earlier spy, so they aren't mistaken for the next one's. Must only be
+/// called from CORE1.
+pub fn discard_inputs() {
+    if let Some(consumer) = unsafe { CONSUMER.as_mut() } {
+        while consumer.dequeue().is_some() {}
+    }
+}
+
+/// Passes a frame on to core1, and shows it on the displays.
+fn record(console: Console, frame: [u8; 4]) {
+    unsafe {
+        if let Some(producer) = PRODUCER.as_mut() {
+            producer.enqueue(frame).ok();
+        }
+    }
+    
+    let len = console.port_bytes();
+    displays::set_display(Port::Display0, &[frame[0] ^ 0xFF, frame[1] ^ 0xFF][..len]);
+    displays::set_display(Port::Display1, &[frame[2] ^ 0xFF, frame[3] ^ 0xFF][..len]);
+}
+
+/// Loads the serial line sampler, and configures a state machine for each port to push `bits` at a time.
+/// Returns the program, and the address to start it from.
+fn install_sampler(bits: u8) -> (ProgramHandle, u8) {
+    // The console reads each bit just before it pulses the clock, and the controller only shifts on the
+    // rising edge, so the serial line is sampled as the clock falls. Bits left over from a read that was cut
+    // short, or from reading past the end of the controller's data, are dropped when the console latches.
+    let program = pio_asm!("
+        .wrap_target
+    public start:
+        jmp pin latched         ; JMP pin is the shared latch
+        jmp start
+    latched:
+        mov isr, null
+    latch_high:
+        jmp pin latch_high
+    wait_clock:
+        jmp pin latched
+        mov osr, pins           ; IN base is this port's serial line
+        out null, 2
+        out y, 1                ; which puts the clock in bit 2
+        jmp !y clock_low
+        jmp wait_clock
+    clock_low:
+        in pins, 1
+        wait 1 pin 2
+        jmp wait_clock
+        .wrap
+    ");
+    let handle = p::install(&program.program, PIO, &SM).expect("sampler program didn't fit");
+    
+    for port in 0..2 {
+        let options = [
+            InBase(nes::SER[port] as u8),
+            JmpPin(nes::LAT as u8),
+            InShiftdir(ShiftDirection::Left),
+            OutShiftdir(ShiftDirection::Right),
+            Autopush(true),
+            PushThresh(bits),
+            Autopull(false),
+            ClockDiv(1.0),
+        ];
+        p::configure(PIO, SM[port], &options);
+    }
+    
+    let start = handle.address(program.public_defines.start as u8);
+    (handle, start)
+}
+
+/// Reads NES or SNES controllers, a frame per latch. Like a replay, latches within the latch filter of the
+/// first are treated as rereads of the same frame.
+fn spy_shift_registers(console: Console) {
+    let bits = if console == Console::Snes { 16 } else { 8 };
+    let (program, start) = install_sampler(bits);
+    
+    for pin in nes::SER.into_iter().chain(nes::CLK).chain([nes::LAT]) {
+        gpio::set_as_input(pin, false, false);
+    }
+    
+    p::stop_multiple(PIO, &SM);
+    for sm in SM {
+        p::clear_fifos(PIO, sm);
+        p::restart(PIO, sm);
+        p::exec(PIO, sm, InstructionOperands::JMP { condition: JmpCondition::Always, address: start });
+    }
+    p::start_multiple(PIO, &SM);
+    
+    let mut filter = LatchFilter::new(nes::LATCH_FILTER_US.load(Ordering::Relaxed));
+    while replaycore::mode() == console.mode() {
+        if filter.expired(&SystemTimer) {
+            // The first word is this frame's read, and any others are rereads. A port that wasn't read is
+            // recorded as released.
+            let words = SM.map(|sm| p::fifo_read(PIO, sm).unwrap_or(u32::MAX));
+            let [p1, p2] = words.map(|word| match console {
+                Console::Snes => (word as u16).to_be_bytes(),
+                _ => [word as u8, 0xFF],
+            });
+            record(console, [p1[0], p1[1], p2[0], p2[1]]);
+        } else if Pins.is_high(nes::LAT) && filter.latch(&SystemTimer) {
+            // Anything still queued was read after the previous frame was recorded
+            for sm in SM {
+                while p::fifo_read(PIO, sm).is_some() {}
+            }
+        }
+    }
+    
+    p::uninstall(program);
+}
+
+/// Reads 3-button Genesis controllers by watching each port's select line, and keeping the last pin set seen
+/// at each level. A frame ends once port 1's select has been still for the step timeout, like a replay.
+fn spy_genesis() {
+    for pin in [genesis::SELECT, genesis::UP, genesis::DOWN, genesis::LEFT_0, genesis::RIGHT_0, genesis::B_A, genesis::C_START].flatten() {
+        gpio::set_as_input(*pin, false, false);
+    }
+    
+    let ports = [0, 1].map(|port| PortPins {
+        select: genesis::SELECT[port],
+        data_base: genesis::DATA_BASE[port],
+        layout: genesis::LAYOUT[port],
+    });
+    let mut spy = Spy::new(ports, genesis::STEP_TIMEOUT_US, &Pins, &SystemTimer);
+    
+    while replaycore::mode() == VeritasMode::SpyGenesis {
+        if let Some([p1, p2]) = spy.poll(&Pins, &SystemTimer) {
+            record(Console::Genesis, [p1, 0xFF, p2, 0xFF]);
+        }
+    }
+}
+
+/// Records the inputs of controllers plugged through the device, until it leaves the console's spy mode. Every
+/// line is left as an input, so the console and controllers talk to each other undisturbed.
+pub fn run(console: Console) {
+    info!("starting spy..");
+    
+    match console {
+        Console::Nes | Console::Snes => spy_shift_registers(console),
+        Console::Genesis => spy_genesis(),
+    }
+    
+    displays::set_display(Port::Display0, &[0x00, 0x00]);
+    displays::set_display(Port::Display1, &[0x00, 0x00]);
+    
+    info!("stopped spy");
+}
\ No newline at end of file
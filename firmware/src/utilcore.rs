@@ -1,18 +1,21 @@
-use defmt::info;
+use crate::{events, info, log};
 use rp2040_hal::usb::UsbBus;
 use rp2040_hal::vector_table::VectorTable;
 use rp2040_hal::pac::Peripherals;
 use rp2040_pac::Interrupt::USBCTRL_IRQ;
 use usb_device::class_prelude::UsbBusAllocator;
+use crate::replaycore::standalone;
+use crate::systems::InputProducers;
 
 pub mod comms;
+pub mod diag;
 pub mod displays;
 
 /// Do not use outside of CORE1!
 pub static mut VTABLE1: VectorTable = VectorTable::new();
 
 #[link_section = ".ram_code"]
-pub fn run(usb_bus: UsbBusAllocator<UsbBus>) -> ! {
+pub fn run(usb_bus: UsbBusAllocator<UsbBus>, board_id: u64, mut inputs: InputProducers) -> ! {
     unsafe {
         // VTABLE1 uses the same PAC, but the Cortex processor handles the underlying addresses
         // differently, because they are being accessed from within core1, instead of core0.
@@ -23,7 +26,7 @@ pub fn run(usb_bus: UsbBusAllocator<UsbBus>) -> ! {
         displays::initialize();
         
         info!("Initializing usb...");
-        comms::init_usb(usb_bus);
+        comms::init_usb(usb_bus, board_id);
         
         VTABLE1.register_handler(USBCTRL_IRQ as usize, usbctrl_irq_handler);
         pac.PPB.nvic_iser.write(|w| w.bits(1 << (USBCTRL_IRQ as u32)));
@@ -31,6 +34,14 @@ pub fn run(usb_bus: UsbBusAllocator<UsbBus>) -> ! {
         
         loop {
             displays::check_displays();
+            comms::check_commands(&mut inputs);
+            comms::check_measurement();
+            standalone::check_autostart(&mut inputs);
+            standalone::feed(&mut inputs);
+            comms::check_reboot();
+            comms::check_failsafe();
+            events::check_console();
+            log::relay_deferred();
         }
     }
 }
@@ -1,19 +1,77 @@
-use alloc::{format, vec};
+use alloc::format;
+use alloc::boxed::Box;
 use alloc::string::String;
 use alloc::vec::Vec;
+use core::sync::atomic::Ordering;
 use bincode::config::Configuration;
 use bincode::{Decode, Encode};
 use num_enum::{IntoPrimitive, FromPrimitive};
+use rp2040_hal::rom_data;
 use rp2040_hal::usb::UsbBus;
+use rp2040_pac::TIMER;
 use usb_device::class_prelude::UsbBusAllocator;
-use usb_device::prelude::{UsbDevice, UsbDeviceBuilder, UsbVidPid};
+use usb_device::prelude::{UsbDevice, UsbDeviceBuilder, UsbDeviceState, UsbVidPid};
 use usbd_serial::SerialPort;
-use defmt::info;
-use crate::replaycore::{VERITAS_MODE, REPLAY_STATE, VeritasMode};
+use veritas_core::display::{BitLayout, LAYOUT_BITS};
+use veritas_core::packet::{Frame, PacketAssembler};
+pub use veritas_core::transition::TransitionData;
+use defmt::Format;
+use crate::{events, info, warn};
+use crate::events::{Event, EventRecord};
+use crate::log::{self, LogLevel};
+use crate::replaycore;
+use crate::replaycore::standalone;
+use crate::replaycore::{Message, REPLAY_STATE, ReplayState, Transition, TransitionTiming, VeritasMode};
 use crate::systems;
+use crate::systems::InputProducers;
+use crate::systems::n64::{self, ControllerPak};
+use crate::systems::spy;
+use crate::utilcore::{diag, displays};
+use crate::utilcore::diag::PortLine;
 
 const BINCODE_CONFIG: Configuration = bincode::config::standard();
 
+/// Largest command payload accepted from the host. The largest commands carry 1KiB chunks of data.
+const MAX_COMMAND_SIZE: usize = 2048;
+/// Largest response that can be sent, including its length.
+const MAX_RESPONSE_SIZE: usize = 256;
+/// Most spied frames sent in a single response, so it stays within the response size.
+const MAX_SPY_FRAMES: usize = 48;
+/// Most event log records sent in a single response, so it stays within the response size.
+const MAX_EVENTS: usize = 12;
+/// Largest relayed log message, including its length.
+const MAX_LOG_FRAME_SIZE: usize = log::MAX_MESSAGE_LEN + 8;
+/// Room for a response, and the log messages sent ahead of it.
+const TX_BUFFER_SIZE: usize = 1024;
+/// Max packet size of the serial data endpoints.
+const USB_PACKET_SIZE: usize = 64;
+
+/// Firmware version, reported to the host. The build ID is the commit the firmware was built from, so the host
+/// can confirm which image is running after flashing it.
+pub const VERSION: &str = concat!(env!("CARGO_PKG_VERSION"), "+", env!("VERITAS_BUILD_ID"));
+
+/// Embedded in the image, so the host can tell which version a firmware file contains before flashing it.
+#[used]
+static VERSION_MARKER: &str = concat!("VERITAS_FIRMWARE_VERSION=", env!("CARGO_PKG_VERSION"), "+", env!("VERITAS_BUILD_ID"), "\0");
+
+/// Time in microseconds between acknowledging a reboot request and rebooting, so the response can be sent.
+const REBOOT_DELAY_US: u32 = 100000;
+static mut REBOOT_AT: Option<u32> = None;
+
+/// Why the failsafe stopped a replay.
+#[derive(Debug, Format, PartialEq, Eq, Copy, Clone, Encode, Decode)]
+pub enum FailsafeReason {
+    /// No command was received from the host within the host timeout.
+    HostTimeout,
+    /// The USB bus was suspended, or the device was disconnected.
+    UsbSuspended,
+}
+
+/// Timer value when the last command was received.
+static mut LAST_COMMAND_AT: u32 = 0;
+/// Reason the failsafe last stopped a replay, cleared once it's been reported by `GetStatus`.
+static mut FAILSAFE_REASON: Option<FailsafeReason> = None;
+
 #[derive(Debug, PartialEq, Eq, Clone, Encode, Decode)]
 pub enum Command {
     ProvideInput(System, Vec<u8>),
@@ -22,8 +80,53 @@ pub enum Command {
     SetReplayLength(u64),
     SetLatchFilter(u32),
     UseInitialReset(bool),
+    SetTransitionTiming(TransitionTiming),
+    WaitForConsole(bool),
+    SetHostTimeout(u32),
+    SetControllerPak {
+        port: u8,
+        pak: ControllerPak,
+    },
+    SetN64Ports(u8),
+    WriteMempak {
+        offset: u16,
+        data: Vec<u8>,
+    },
+    EraseStoredMovie,
+    WriteStoredMovie {
+        offset: u32,
+        data: Vec<u8>,
+    },
+    StartStoredMovie,
+    RebootToBootloader,
+    GetVersion,
     GetStatus,
     Ping,
+    ReadPins,
+    TestOutputs,
+    /// Counts edges on the controller port lines for the given number of milliseconds, before responding.
+    MeasureEdges(u32),
+    TestDisplays,
+    /// Shows bytes on one of the displays, until the replay or another command replaces them.
+    SetDisplay {
+        port: u8,
+        data: Vec<u8>,
+    },
+    /// Changes which input bit each display bit shows, for one console. Lasts until the device restarts.
+    SetDisplayLayout {
+        system: System,
+        layout: [u8; LAYOUT_BITS],
+    },
+    /// Display brightness in percent, where 0 blanks them.
+    SetDisplayBrightness(u8),
+    GetProgress,
+    /// Takes the oldest frames read from the controllers while spying.
+    GetSpyInputs,
+    /// Reads the event log, starting from the given record number. Passing `u32::MAX` returns no records, just
+    /// the number the next event will get.
+    GetEventLog(u32),
+    /// Lines of each of a console's controller ports.
+    GetPinMap(System),
 }
 
 #[derive(Debug, PartialEq, Eq, Clone, Encode, Decode)]
@@ -36,14 +139,42 @@ pub enum Response {
     },
     Pong,
     Err,
+    Version(String),
+    /// A message logged by the firmware, sent ahead of the response to a command.
+    Log {
+        level: LogLevel,
+        message: String,
+    },
+    /// Levels of the controller port lines (bit n is `PIN_CNT_n`), and whether the console is powered.
+    Pins {
+        levels: u32,
+        console: bool,
+    },
+    /// Controller port lines that didn't read back as driven, with the same layout as `Pins`.
+    OutputFaults(u32),
+    /// Edges counted on each controller port line, indexed by `PIN_CNT_n`.
+    Edges(Vec<u32>),
+    /// Current mode, and how many of the movie's inputs the console has consumed.
+    Progress {
+        mode: VeritasMode,
+        index_cur: u32,
+        index_len: u32,
+    },
+    /// Frames read from the controllers while spying, oldest first. Each is 4 bytes: 2 for each port, with a
+    /// NES or Genesis controller's input in the first, and 0xFF in the second.
+    SpyInputs(Vec<u8>),
+    /// Records from the event log, oldest first, and the number of the first. If it's after the number asked
+    /// for, the records in between were overwritten.
+    EventLog {
+        first: u32,
+        events: Vec<EventRecord>,
+    },
+    /// Lines of each controller port, by port.
+    PinMap(Vec<Vec<PortLine>>),
 }
 
-#[derive(Debug, PartialEq, Eq, Clone, Encode, Decode)]
-pub struct TransitionData {
-    index: u64,
-    index_kind: u8,
-    transition_kind: u8,
-}
+/// The only index kind accepted from the host: the number of inputs consumed before the transition.
+const INDEX_KIND_FRAME: u8 = 0x01;
 
 
 #[derive(Debug, PartialEq, Eq, Clone, Encode, Decode, IntoPrimitive, FromPrimitive)]
@@ -62,268 +193,526 @@ pub enum System {
 pub struct UsbController<'a> {
     pub usb_bus: Option<UsbBusAllocator<UsbBus>>,
     pub usb_dev: Option<UsbDevice<'a, UsbBus>>,
-    pub serial: Option<SerialPort<'a, UsbBus>>
+    pub serial: Option<SerialPort<'a, UsbBus>>,
+    rx: PacketAssembler<MAX_COMMAND_SIZE>,
+    tx: [u8; TX_BUFFER_SIZE],
+    tx_len: usize,
+    tx_ptr: usize,
 }
 impl<'a> UsbController<'a> {
     pub const fn empty() -> Self { Self {
         usb_bus: None,
         usb_dev: None,
-        serial: None
+        serial: None,
+        rx: PacketAssembler::new(),
+        tx: [0; TX_BUFFER_SIZE],
+        tx_len: 0,
+        tx_ptr: 0,
     }}
     
+    /// Moves whatever the host has sent into the packet assembler, without reading past the end of a command.
     #[link_section = ".ram_code"]
-    pub fn try_recv_command(&mut self) -> Option<Command> {
-        if let Some(len) = self.read_four() {
-            let len = u32::from_be_bytes(len);
-            let mut buf = vec![0u8; len as usize];
-            self.read_blocking(&mut buf);
-            
-            bincode::decode_from_slice(&buf, BINCODE_CONFIG)
-                .ok()
-                .map(|(command, _)| command)
-        } else {
-            None
+    pub fn receive(&mut self) {
+        if let Some(serial) = self.serial.as_mut() {
+            let mut buf = [0u8; USB_PACKET_SIZE];
+            loop {
+                let wanted = self.rx.wanted().min(USB_PACKET_SIZE);
+                if wanted == 0 {
+                    break;
+                }
+                
+                match serial.read(&mut buf[..wanted]) {
+                    Ok(count) if count > 0 => { self.rx.push(&buf[..count]); },
+                    _ => break,
+                }
+            }
         }
     }
     
-    #[link_section = ".ram_code"]
-    pub fn send_response(&mut self, resp: Response) {
-        let payload = if let Ok(payload) = bincode::encode_to_vec(resp, BINCODE_CONFIG) {
-            payload
-        } else {
-            return;
+    /// Decodes a command, once one has been fully received. Commands that are too large or can't be decoded
+    /// are answered with an error here, since the host waits for a response to every command.
+    pub fn try_recv_command(&mut self) -> Option<Command> {
+        let command = match self.rx.frame()? {
+            Frame::Complete(payload) => bincode::decode_from_slice(payload, BINCODE_CONFIG)
+                .ok()
+                .map(|(command, _)| command),
+            Frame::Oversized => None,
         };
+        self.rx.release();
         
-        let mut data = (payload.len() as u32).to_be_bytes().to_vec();
-        data.extend_from_slice(&payload);
+        if command.is_none() {
+            warn!("discarded a command that couldn't be decoded");
+            events::record(Event::BadCommand);
+            self.send_response(Response::Err);
+        }
         
-        self.write_blocking(&data);
+        command
     }
     
-    #[inline(always)]
-    pub fn poll(&mut self) -> bool {
-        if let Some(usb_dev) = self.usb_dev.as_mut() {
-            if let Some(serial) = self.serial.as_mut() {
-                return usb_dev.poll(&mut [serial]);
-            }
+    /// Queues a response, along with any pending log messages, and starts sending it. Anything that doesn't fit
+    /// in the serial buffer yet is sent from the USB interrupt, as the host reads it.
+    pub fn send_response(&mut self, resp: Response) {
+        let mut len = 0;
+        while len + MAX_LOG_FRAME_SIZE <= TX_BUFFER_SIZE - MAX_RESPONSE_SIZE {
+            let Some(message) = log::take() else {
+                break;
+            };
+            let resp = Response::Log { level: message.level, message: message.text.as_str().into() };
+            len += Self::encode_frame(&mut self.tx[len..(len + MAX_LOG_FRAME_SIZE)], resp).unwrap_or(0);
         }
         
-        false
+        let encoded = Self::encode_frame(&mut self.tx[len..(len + MAX_RESPONSE_SIZE)], resp);
+        self.tx_len = len + encoded.unwrap_or(0);
+        self.tx_ptr = 0;
+        if encoded.is_none() {
+            warn!("response too large to send");
+            events::record(Event::ResponseTooLarge);
+        }
+        
+        self.flush();
     }
     
-    #[allow(unused)]
-    #[inline(always)]
-    pub fn read_one(&mut self) -> Option<u8> {
-        if let Some(serial) = self.serial.as_mut() {
-            let mut buf = [0u8];
-            match serial.read(&mut buf) {
-                Ok(count) if count == 1 => return Some(buf[0]),
-                _ => ()
-            }
-        }
+    /// Encodes a response into `buf`, after its length. Returns the size of the whole frame.
+    fn encode_frame(buf: &mut [u8], resp: Response) -> Option<usize> {
+        let len = bincode::encode_into_slice(resp, &mut buf[4..], BINCODE_CONFIG).ok()?;
+        buf[..4].copy_from_slice(&(len as u32).to_be_bytes());
         
-        None
+        Some(len + 4)
     }
     
-    pub fn read_four(&mut self) -> Option<[u8; 4]> {
+    /// Writes as much of the pending response as the serial buffer will take, a full packet at a time.
+    #[link_section = ".ram_code"]
+    pub fn flush(&mut self) {
         if let Some(serial) = self.serial.as_mut() {
-            let mut buf = [0u8; 4];
-            
-            match serial.read(&mut buf) {
-                Ok(count) if count == 0 => return None,
-                Ok(count) if count < 4 => {
-                    for i in count..4 {
-                        buf[i] = self.read_one_blocking();
-                    }
-                    
-                    return Some(buf);
-                },
-                Ok(_) => return Some(buf),
-                _ => ()
+            while self.tx_ptr < self.tx_len {
+                let end = (self.tx_ptr + USB_PACKET_SIZE).min(self.tx_len);
+                match serial.write(&self.tx[self.tx_ptr..end]) {
+                    Ok(count) if count > 0 => self.tx_ptr += count,
+                    _ => break,
+                }
             }
         }
-        
-        None
     }
     
-    #[inline(always)]
-    pub fn read_one_blocking(&mut self) -> u8 {
-        let serial = self.serial.as_mut().expect("USB serial not initialized");
-        let mut buf = [0u8];
-        loop {
-            match serial.read(&mut buf) {
-                Ok(count) if count == 1 => return buf[0],
-                _ => (),
-            }
-        }
+    /// Returns true if the host has configured the device, and the bus isn't suspended.
+    pub fn is_configured(&self) -> bool {
+        self.usb_dev.as_ref().map_or(false, |usb_dev| usb_dev.state() == UsbDeviceState::Configured)
     }
     
     #[inline(always)]
-    pub fn read_blocking(&mut self, buf: &mut [u8]) {
-        let serial = self.serial.as_mut().expect("USB serial not initialized");
-        let mut ptr = 0usize;
-        while ptr < buf.len() {
-            let mut data = [0u8];
-            match serial.read(&mut data) {
-                Ok(count) if count == 1 => {
-                    buf[ptr] = data[0];
-                    ptr += 1;
-                },
-                _ => ()
+    pub fn poll(&mut self) -> bool {
+        if let Some(usb_dev) = self.usb_dev.as_mut() {
+            if let Some(serial) = self.serial.as_mut() {
+                return usb_dev.poll(&mut [serial]);
             }
         }
-    }
-    
-    #[allow(unused)]
-    #[inline(always)]
-    pub fn write_one_blocking(&mut self, buf: u8) {
-        let serial = self.serial.as_mut().expect("USB serial not initialized");
         
-        loop {
-            match serial.write(&[buf]) {
-                Ok(count) if count == 1 => break,
-                _ => ()
+        false
+    }
+}
+
+pub static mut USB: UsbController = UsbController::empty();
+
+/// Reboots into the RP2040's USB bootloader once a requested reboot is due. Must be called periodically.
+pub fn check_reboot() {
+    unsafe {
+        if let Some(at) = REBOOT_AT {
+            if (*TIMER::ptr()).timerawl.read().bits().wrapping_sub(at) as i32 >= 0 {
+                info!("rebooting to bootloader");
+                rom_data::reset_to_usb_boot(0, 0);
             }
         }
     }
-    
-    #[inline(always)]
-    pub fn write_blocking(&mut self, buf: &[u8]) {
-        let serial = self.serial.as_mut().expect("USB serial not initialized");
-        let mut ptr = 0usize;
-        
-        while ptr < buf.len() {
-            match serial.write(&[buf[ptr]]) {
-                Ok(count) if count == 1 => ptr += 1,
-                _ => ()
-            }
+}
+
+/// Stops a replay streamed from the host if the host appears to have gone away, either because the USB bus
+/// was suspended, or no command has been received within the host timeout. Must be called periodically.
+///
+/// Only applies while a host timeout is set, so stored movies and manual control are unaffected.
+pub fn check_failsafe() {
+    unsafe {
+        let timeout_ms = REPLAY_STATE.lock(|state| state.host_timeout_ms);
+        let mode = replaycore::mode();
+        if timeout_ms == 0 || mode == VeritasMode::Idle || mode == VeritasMode::Initial {
+            return;
         }
+        
+        let (configured, last_command_at) = cortex_m::interrupt::free(|_| (USB.is_configured(), LAST_COMMAND_AT));
+        let elapsed = (*TIMER::ptr()).timerawl.read().bits().wrapping_sub(last_command_at);
+        
+        let reason = if !configured {
+            FailsafeReason::UsbSuspended
+        } else if elapsed > timeout_ms.saturating_mul(1000) {
+            FailsafeReason::HostTimeout
+        } else {
+            return;
+        };
+        
+        warn!("failsafe triggered: {:?}", reason);
+        events::record(Event::Failsafe(reason));
+        FAILSAFE_REASON = Some(reason);
+        replaycore::send(Message::SetMode(VeritasMode::Idle));
     }
 }
 
-pub static mut USB: UsbController = UsbController::empty();
+/// Adds transitions to the replay, if they're all valid for the current replay length.
+pub fn load_transitions(state: &mut ReplayState, transitions: Vec<TransitionData>) -> bool {
+    let valid = transitions.iter().all(|tra| {
+        tra.index_kind() == INDEX_KIND_FRAME
+            && tra.index() < state.index_len as u64
+            && Transition::from(tra.transition_kind()) != Transition::Unsupported
+    });
+    
+    if valid {
+        state.transitions.extend(
+            transitions.into_iter()
+                .map(|tra| (tra.index() as u32, tra.transition_kind().into()))
+        );
+        state.transitions.sort_by_key(|(index, _)| *index);
+    }
+    
+    valid
+}
 
-pub fn init_usb(usb_bus: UsbBusAllocator<UsbBus>) {
+/// Initializes the USB serial device. The serial number identifies the board, and stays the same across firmware
+/// updates so the OS keeps treating it as the same device.
+pub fn init_usb(usb_bus: UsbBusAllocator<UsbBus>, board_id: u64) {
+    let serial_number: &'static str = Box::leak(format!("VeriTAS-{board_id:016X}").into_boxed_str());
+    
     unsafe {
         USB.usb_bus = Some(usb_bus);
         USB.serial = Some(SerialPort::new(USB.usb_bus.as_ref().unwrap()));
         USB.usb_dev = Some(UsbDeviceBuilder::new(USB.usb_bus.as_ref().unwrap(), UsbVidPid(0x16C0, 0x27DD))
             .manufacturer("Bigbass")
             .product("VeriTAS")
-            .serial_number("VeriTAS") //TODO: provide version number
+            .serial_number(serial_number)
             .device_class(2)
             .self_powered(true)
             .build());
     }
 }
 
+/// Services the USB device, collecting command bytes and sending queued response bytes. Called from the USB
+/// interrupt, so commands themselves are handled by [`check_commands`].
 #[link_section = ".ram_code"]
 pub fn check_usb() {
     unsafe {
-        if !USB.poll() {
-            return;
+        if USB.poll() {
+            USB.receive();
         }
-        
-        if let Some(cmd) = USB.try_recv_command() {
+        USB.flush();
+    }
+}
+
+/// Sends the result of an edge measurement, once it's over. Must be called periodically.
+pub fn check_measurement() {
+    if let Some(edges) = diag::check_measurement() {
+        respond(Response::Edges(edges.to_vec()));
+    }
+}
+
+/// Queues a response from outside the USB interrupt.
+fn respond(resp: Response) {
+    cortex_m::interrupt::free(|_| unsafe { USB.send_response(resp) });
+}
+
+/// Number of bytes in a single frame of input for the system, as sent by the host.
+fn input_size(system: &System) -> usize {
+    match system {
+        System::Nes => 2,
+        System::Snes | System::Genesis => 4,
+        System::N64 => 16,
+        System::A2600 | System::Unknown => 1,
+    }
+}
+
+/// Handles a command, if the host has finished sending one. Must be called periodically.
+pub fn check_commands(inputs: &mut InputProducers) {
+    unsafe {
+        if let Some(cmd) = cortex_m::interrupt::free(|_| USB.try_recv_command()) {
+            LAST_COMMAND_AT = (*TIMER::ptr()).timerawl.read().bits();
+            
             match cmd {
-                Command::ProvideInput(system, inputs) => {
+                Command::ProvideInput(system, data) if data.len() % input_size(&system) != 0 => {
+                    // Part of a frame would misalign every input after it
+                    respond(Response::Err);
+                },
+                Command::ProvideInput(system, data) => {
                     match system {
                         System::Nes => {
-                            use crate::systems::nes::INPUT_BUFFER;
+                            let buffer = &mut inputs.nes;
+                            
                             let mut ptr = 0usize;
-                            while !INPUT_BUFFER.is_full() && ptr <= inputs.len() - 2 && ptr < (u16::MAX - 1) as usize {
-                                let input = [inputs[ptr], inputs[ptr + 1]];
-                                INPUT_BUFFER.enqueue(input).unwrap();
+                            while buffer.ready() && ptr + 2 <= data.len() && ptr < (u16::MAX - 1) as usize {
+                                let input = [data[ptr], data[ptr + 1]];
+                                buffer.enqueue(input).unwrap();
                                 
                                 ptr += 2;
                             }
                             
-                            USB.send_response(Response::BufferStatus {
+                            respond(Response::BufferStatus {
                                 written: ptr as u16,
-                                remaining_space: ((INPUT_BUFFER.capacity() - INPUT_BUFFER.len()) * 2) as u16,
+                                remaining_space: ((buffer.capacity() - buffer.len()) * 2) as u16,
                             });
                         },
                         System::Snes => {
-                            USB.send_response(Response::Err);
+                            let buffer = &mut inputs.snes;
+                            
+                            let mut ptr = 0usize;
+                            while buffer.ready() && ptr + 4 <= data.len() && ptr < (u16::MAX - 1) as usize {
+                                let input = data[ptr..(ptr + 4)].try_into().unwrap();
+                                buffer.enqueue(input).unwrap();
+                                
+                                ptr += 4;
+                            }
+                            
+                            respond(Response::BufferStatus {
+                                written: ptr as u16,
+                                remaining_space: ((buffer.capacity() - buffer.len()) * 4) as u16,
+                            });
                         },
                         System::N64 => {
-                            /*use crate::systems::n64::INPUT_BUFFER;
+                            let buffer = &mut inputs.n64;
                             
-                            let mut input = [0u8; 16];
-                            USB.read_blocking(&mut input);
-                            let status = if !INPUT_BUFFER.is_full() {
-                                let mut inputs = [0u32; 4];
-                                for i in 0..4 {
-                                    inputs[i] = u32::from_be_bytes(input[(i * 4)..((i + 1) * 4)].try_into().unwrap());
+                            let mut ptr = 0usize;
+                            while buffer.ready() && ptr + 16 <= data.len() && ptr < (u16::MAX - 1) as usize {
+                                let mut input = [0u32; 4];
+                                for (port, state) in input.iter_mut().enumerate() {
+                                    *state = u32::from_be_bytes(data[(ptr + port * 4)..(ptr + port * 4 + 4)].try_into().unwrap());
                                 }
+                                buffer.enqueue(input).unwrap();
                                 
-                                INPUT_BUFFER.enqueue(inputs).unwrap();
-                                
-                                Response::Ok
-                            } else {
-                                Response::BufferFull
-                            };*/
+                                ptr += 16;
+                            }
                             
-                            USB.send_response(Response::Err);
+                            respond(Response::BufferStatus {
+                                written: ptr as u16,
+                                remaining_space: ((buffer.capacity() - buffer.len()) * 16) as u16,
+                            });
                         },
                         System::Genesis => {
-                            use crate::systems::genesis::INPUT_BUFFER;
+                            let buffer = &mut inputs.genesis;
                             
                             let mut ptr = 0usize;
-                            while !INPUT_BUFFER.is_full() && ptr <= inputs.len() - 4 && ptr < (u16::MAX - 1) as usize {
-                                let input = inputs[ptr..(ptr + 4)].try_into().unwrap();
-                                INPUT_BUFFER.enqueue(input).unwrap();
+                            while buffer.ready() && ptr + 4 <= data.len() && ptr < (u16::MAX - 1) as usize {
+                                let input = data[ptr..(ptr + 4)].try_into().unwrap();
+                                buffer.enqueue(input).unwrap();
                                 
                                 ptr += 4;
                             }
                             
-                            USB.send_response(Response::BufferStatus {
+                            respond(Response::BufferStatus {
                                 written: ptr as u16,
-                                remaining_space: ((INPUT_BUFFER.capacity() - INPUT_BUFFER.len()) * 4) as u16,
+                                remaining_space: ((buffer.capacity() - buffer.len()) * 4) as u16,
                             });
                         },
                         System::A2600 => {
-                            USB.send_response(Response::Err);
+                            respond(Response::Err);
                         },
                         System::Unknown => {
-                            USB.send_response(Response::Err);
+                            respond(Response::Err);
                         },
                     }
                 },
                 Command::ProvideTransitions(transitions) => {
-                    REPLAY_STATE.transitions.extend(
-                        transitions.into_iter()
-                            .map(|tra| (tra.index as u32, tra.transition_kind.into()))
-                            .inspect(|(index, tra)| { info!("Added {:02X} at {}", u8::from(*tra), index); })
-                    );
-                    
-                    USB.send_response(Response::Ok);
+                    let count = transitions.len();
+                    if REPLAY_STATE.lock(|state| load_transitions(state, transitions)) {
+                        info!("added {} transitions", count);
+                        respond(Response::Ok);
+                    } else {
+                        respond(Response::Err);
+                    }
                 },
                 Command::SetReplayMode(mode) => {
-                    VERITAS_MODE = mode;
+                    if matches!(mode, VeritasMode::SpyNes | VeritasMode::SpySnes | VeritasMode::SpyGenesis) {
+                        spy::discard_inputs();
+                    }
+                    
+                    replaycore::send(Message::SetMode(mode));
                     
-                    USB.send_response(Response::Ok);
+                    respond(Response::Ok);
                 },
                 Command::SetReplayLength(length) => {
-                    REPLAY_STATE.index_len = length as u32; //TODO handle what happens if length > u32
-                    
-                    USB.send_response(Response::Ok);
+                    if let Ok(length) = u32::try_from(length) {
+                        REPLAY_STATE.lock(|state| state.index_len = length);
+                        
+                        respond(Response::Ok);
+                    } else {
+                        respond(Response::Err);
+                    }
                 },
                 Command::SetLatchFilter(time) => {
-                    systems::nes::LATCH_FILTER_US = time;
+                    systems::nes::LATCH_FILTER_US.store(time, Ordering::Relaxed);
                     
-                    USB.send_response(Response::Ok);
+                    respond(Response::Ok);
                 },
                 Command::UseInitialReset(use_reset) => {
-                    REPLAY_STATE.use_initial_reset = use_reset;
+                    REPLAY_STATE.lock(|state| state.use_initial_reset = use_reset);
                     
-                    USB.send_response(Response::Ok);
+                    respond(Response::Ok);
+                },
+                Command::SetTransitionTiming(timing) => {
+                    REPLAY_STATE.lock(|state| state.timing = timing);
+                    
+                    respond(Response::Ok);
+                },
+                Command::WaitForConsole(wait) => {
+                    REPLAY_STATE.lock(|state| state.wait_for_console = wait);
+                    
+                    respond(Response::Ok);
+                },
+                Command::SetHostTimeout(timeout_ms) => {
+                    REPLAY_STATE.lock(|state| state.host_timeout_ms = timeout_ms);
+                    
+                    respond(Response::Ok);
+                },
+                Command::SetControllerPak { port, pak } => {
+                    if n64::set_controller_pak(port as usize, pak) {
+                        respond(Response::Ok);
+                    } else {
+                        respond(Response::Err);
+                    }
+                },
+                Command::SetN64Ports(ports) => {
+                    if replaycore::mode() == VeritasMode::Idle && ports != 0 && ports < 0x10 {
+                        n64::CONNECTED_PORTS.store(ports, Ordering::Relaxed);
+                        
+                        respond(Response::Ok);
+                    } else {
+                        respond(Response::Err);
+                    }
+                },
+                Command::WriteMempak { offset, data } => {
+                    if replaycore::mode() == VeritasMode::Idle && n64::write_mempak(offset as usize, &data) {
+                        respond(Response::Ok);
+                    } else {
+                        respond(Response::Err);
+                    }
+                },
+                Command::EraseStoredMovie => {
+                    if replaycore::mode() == VeritasMode::Idle && standalone::erase() {
+                        respond(Response::Ok);
+                    } else {
+                        respond(Response::Err);
+                    }
+                },
+                Command::WriteStoredMovie { offset, data } => {
+                    if replaycore::mode() == VeritasMode::Idle && standalone::write(offset, &data) {
+                        respond(Response::Ok);
+                    } else {
+                        respond(Response::Err);
+                    }
+                },
+                Command::StartStoredMovie => {
+                    if standalone::start(inputs) {
+                        respond(Response::Ok);
+                    } else {
+                        respond(Response::Err);
+                    }
+                },
+                Command::RebootToBootloader => {
+                    if replaycore::mode() == VeritasMode::Idle {
+                        REBOOT_AT = Some((*TIMER::ptr()).timerawl.read().bits().wrapping_add(REBOOT_DELAY_US));
+                        
+                        respond(Response::Ok);
+                    } else {
+                        respond(Response::Err);
+                    }
+                },
+                Command::GetVersion => {
+                    respond(Response::Version(VERSION.into()));
                 },
                 Command::GetStatus => {
-                    USB.send_response(Response::DeviceStatus(format!("Mode: {:?}, Index: {}/{}", VERITAS_MODE, REPLAY_STATE.index_cur, REPLAY_STATE.index_len)));
+                    let console = if replaycore::console_detected() { "On" } else { "Off" };
+                    let (index_cur, index_len) = REPLAY_STATE.lock(|state| (state.index_cur, state.index_len));
+                    let mut status = format!("Mode: {:?}, Index: {}/{}, Console: {}", replaycore::mode(), index_cur, index_len, console);
+                    if let Some(reason) = FAILSAFE_REASON.take() {
+                        status.push_str(&format!(", Failsafe: {:?}", reason));
+                    }
+                    
+                    respond(Response::DeviceStatus(status));
                 },
                 Command::Ping => {
-                    USB.send_response(Response::Pong);
+                    respond(Response::Pong);
+                },
+                Command::ReadPins => {
+                    respond(Response::Pins { levels: diag::read_pins(), console: replaycore::console_detected() });
+                },
+                Command::TestOutputs => {
+                    if replaycore::mode() == VeritasMode::Idle && !replaycore::console_detected() {
+                        respond(Response::OutputFaults(diag::test_outputs()));
+                    } else {
+                        respond(Response::Err);
+                    }
+                },
+                Command::MeasureEdges(duration_ms) => {
+                    // Answered by `check_measurement` once the time is up
+                    if replaycore::mode() == VeritasMode::Idle && duration_ms <= diag::MAX_MEASURE_MS {
+                        diag::start_measurement(duration_ms);
+                    } else {
+                        respond(Response::Err);
+                    }
+                },
+                Command::SetDisplay { port, data } => {
+                    if port < 4 && data.len() <= 8 {
+                        displays::show(port.into(), &data);
+                        
+                        respond(Response::Ok);
+                    } else {
+                        respond(Response::Err);
+                    }
+                },
+                Command::SetDisplayLayout { system, layout } => {
+                    let console = match system {
+                        System::Nes => Some(displays::Console::Nes),
+                        System::Snes => Some(displays::Console::Snes),
+                        System::N64 => Some(displays::Console::N64),
+                        System::Genesis => Some(displays::Console::Genesis),
+                        _ => None,
+                    };
+                    
+                    match (console, BitLayout::new(layout)) {
+                        (Some(console), Some(layout)) => {
+                            displays::set_layout(console, layout);
+                            
+                            respond(Response::Ok);
+                        },
+                        _ => respond(Response::Err),
+                    }
+                },
+                Command::SetDisplayBrightness(percent) => {
+                    if percent <= 100 {
+                        displays::set_brightness(percent);
+                        
+                        respond(Response::Ok);
+                    } else {
+                        respond(Response::Err);
+                    }
+                },
+                Command::GetProgress => {
+                    let (index_cur, index_len) = REPLAY_STATE.lock(|state| (state.index_cur, state.index_len));
+                    
+                    respond(Response::Progress { mode: replaycore::mode(), index_cur, index_len });
+                },
+                Command::GetSpyInputs => {
+                    respond(Response::SpyInputs(spy::take_inputs(MAX_SPY_FRAMES)));
+                },
+                Command::GetEventLog(from) => {
+                    let (first, events) = events::read(from, MAX_EVENTS);
+                    
+                    respond(Response::EventLog { first, events });
+                },
+                Command::GetPinMap(system) => {
+                    respond(Response::PinMap(diag::pin_map(system)));
+                },
+                Command::TestDisplays => {
+                    if replaycore::mode() == VeritasMode::Idle {
+                        displays::start_test_pattern();
+                        
+                        respond(Response::Ok);
+                    } else {
+                        respond(Response::Err);
+                    }
                 },
             }
         }
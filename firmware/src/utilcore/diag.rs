@@ -0,0 +1,213 @@
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ops::RangeInclusive;
+use bincode::{Decode, Encode};
+use cortex_m::asm::delay;
+use rp2040_pac::{SIO, TIMER};
+use crate::hal::gpio;
+use crate::hal::gpio::{PIN_CNT_0, PIN_CNT_16};
+use crate::systems::{genesis, n64, nes};
+use crate::utilcore::comms::System;
+
+/// Number of controller port lines, `PIN_CNT_0` through `PIN_CNT_18`.
+pub const CNT_PINS: usize = 19;
+/// Longest edge measurement accepted, so the response arrives before the host gives up waiting for it.
+pub const MAX_MEASURE_MS: u32 = 5000;
+
+/// Lines driven by the output test. `PIN_CNT_17` and `PIN_CNT_18` sit behind direction-controlled buffers, so
+/// they're left alone.
+const OUTPUT_PINS: RangeInclusive<usize> = PIN_CNT_0..=PIN_CNT_16;
+/// Time in cycles given to the outputs to settle before they're read back.
+const SETTLE_CYCLES: u32 = 1000;
+/// Longest the edge measurement polls the lines for in one go, before letting the rest of core1's loop run.
+const MEASURE_BURST_US: u32 = 1000;
+
+/// Edge measurement in progress. Do not use outside of CORE1!
+static mut MEASUREMENT: Option<Measurement> = None;
+
+struct Measurement {
+    started_at: u32,
+    duration_us: u32,
+    last: u32,
+    edges: [u32; CNT_PINS],
+}
+
+/// What a controller port line carries.
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Encode, Decode)]
+pub enum LineKind {
+    Data,
+    Clock,
+    Latch,
+    Detect,
+    Select,
+    Up,
+    Down,
+    Left,
+    Right,
+    BA,
+    CStart,
+}
+
+/// How a controller port line is driven, which decides how it can be checked.
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Encode, Decode)]
+pub enum LineRole {
+    /// Driven by the device.
+    Output,
+    /// Driven by the console (latch, clock or select).
+    Input,
+    /// Driven by both, like the N64's data line.
+    Bidirectional,
+    /// Held high by the console while it's powered.
+    Sense,
+}
+
+/// A controller port line, numbered as `PIN_CNT_n`.
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Encode, Decode)]
+pub struct PortLine {
+    pub kind: LineKind,
+    pub pin: u8,
+    pub role: LineRole,
+}
+impl PortLine {
+    fn new(kind: LineKind, gpio: usize, role: LineRole) -> Self { Self {
+        kind,
+        pin: (gpio - PIN_CNT_0) as u8,
+        role,
+    }}
+}
+
+#[inline(always)]
+fn line(pin: usize) -> u32 {
+    1 << (pin - PIN_CNT_0)
+}
+
+#[inline(always)]
+fn levels() -> u32 {
+    unsafe {
+        ((*SIO::ptr()).gpio_in.read().bits() >> PIN_CNT_0) & ((1 << CNT_PINS) - 1)
+    }
+}
+
+#[inline(always)]
+fn now_us() -> u32 {
+    unsafe {
+        (*TIMER::ptr()).timerawl.read().bits()
+    }
+}
+
+/// Levels of the controller port lines, with bit n holding `PIN_CNT_n`.
+pub fn read_pins() -> u32 {
+    levels()
+}
+
+/// Lines of each of a console's controller ports, as the systems drive them. Empty for consoles the device
+/// can't replay.
+pub fn pin_map(system: System) -> Vec<Vec<PortLine>> {
+    use LineKind::*;
+    use LineRole::*;
+    
+    match system {
+        System::Nes | System::Snes => (0..2)
+            .map(|port| vec![
+                PortLine::new(Data, nes::SER[port], Output),
+                PortLine::new(Clock, nes::CLK[port], Input),
+                PortLine::new(Latch, nes::LAT, Input),
+            ])
+            .collect(),
+        System::N64 => (0..4)
+            .map(|port| vec![
+                PortLine::new(Data, n64::DATA[port], Bidirectional),
+                PortLine::new(Detect, n64::DETECT[port], Sense),
+            ])
+            .collect(),
+        System::Genesis => (0..2)
+            .map(|port| vec![
+                PortLine::new(Select, genesis::SELECT[port], Input),
+                PortLine::new(Up, genesis::UP[port], Output),
+                PortLine::new(Down, genesis::DOWN[port], Output),
+                PortLine::new(Left, genesis::LEFT_0[port], Output),
+                PortLine::new(Right, genesis::RIGHT_0[port], Output),
+                PortLine::new(BA, genesis::B_A[port], Output),
+                PortLine::new(CStart, genesis::C_START[port], Output),
+            ])
+            .collect(),
+        _ => vec![],
+    }
+}
+
+/// Drives each output line high in turn while the rest are held low, then low in turn while the rest are held
+/// high, and checks that every line reads back as driven. Returns the lines that didn't, as a mask like
+/// [`read_pins`]. A line that's stuck, or shorted to another, shows up in both patterns.
+///
+/// The lines are read back from the RP2040's own pads, so this only finds faults on the device's side of the
+/// level shifters. A dead shifter or a broken connector pin still passes.
+///
+/// The lines are driven against whatever is connected to them, so only run this with the console powered off,
+/// and while the device is idle. They're left as floating inputs afterwards.
+pub fn test_outputs() -> u32 {
+    let mask = OUTPUT_PINS.fold(0, |mask, pin| mask | line(pin));
+    let mut faults = 0;
+    
+    for pin in OUTPUT_PINS {
+        gpio::set_low(pin);
+        gpio::set_as_output(pin, false, false);
+        gpio::set_input_enable(pin, true);
+    }
+    
+    for level in [true, false] {
+        for pin in OUTPUT_PINS {
+            for other in OUTPUT_PINS {
+                gpio::set_state(other, (other == pin) == level);
+            }
+            delay(SETTLE_CYCLES);
+            
+            let expected = if level { line(pin) } else { mask & !line(pin) };
+            faults |= (levels() ^ expected) & mask;
+        }
+    }
+    
+    for pin in OUTPUT_PINS {
+        gpio::set_as_input(pin, false, false);
+    }
+    
+    faults
+}
+
+/// Starts counting the edges seen on each controller port line over the given time. The lines are polled by
+/// [`check_measurement`], so this returns straight away. Must only be called from CORE1.
+pub fn start_measurement(duration_ms: u32) {
+    unsafe {
+        MEASUREMENT = Some(Measurement {
+            started_at: now_us(),
+            duration_us: duration_ms.min(MAX_MEASURE_MS) * 1000,
+            last: levels(),
+            edges: [0; CNT_PINS],
+        });
+    }
+}
+
+/// Polls the lines for a short burst, if a measurement is running. Returns the edges counted on each line,
+/// indexed by `PIN_CNT_n`, once it's over. Must be called periodically from CORE1.
+///
+/// Pulses shorter than a few hundred nanoseconds can be missed, as can any while the rest of core1's loop runs
+/// between bursts. That's still enough to tell a line that's toggling from one that's dead.
+pub fn check_measurement() -> Option<[u32; CNT_PINS]> {
+    let measurement = unsafe { MEASUREMENT.as_mut()? };
+    
+    let burst_at = now_us();
+    while now_us().wrapping_sub(burst_at) < MEASURE_BURST_US {
+        let current = levels();
+        let mut changed = current ^ measurement.last;
+        while changed != 0 {
+            measurement.edges[changed.trailing_zeros() as usize] += 1;
+            changed &= changed - 1;
+        }
+        measurement.last = current;
+    }
+    
+    if now_us().wrapping_sub(measurement.started_at) < measurement.duration_us {
+        return None;
+    }
+    
+    unsafe { MEASUREMENT.take().map(|measurement| measurement.edges) }
+}
\ No newline at end of file
@@ -1,9 +1,13 @@
 use cortex_m::asm::{delay, nop};
-use heapless::spsc::Queue;
+use heapless::spsc::{Consumer, Producer, Queue};
 use heapless::Vec;
 use num_enum::{FromPrimitive, IntoPrimitive};
+use rp2040_pac::TIMER;
+use veritas_core::display::BitLayout;
 use crate::hal::gpio;
 use crate::hal::gpio::{PIN_DISPLAY_CLK, PIN_DISPLAY_SER, PIN_DISPLAY_STROBE0, PIN_DISPLAY_STROBE1, PIN_DISPLAY_STROBE2, PIN_DISPLAY_STROBE3};
+use crate::replaycore;
+use crate::replaycore::VeritasMode;
 
 const STROBE_PINS: [usize; 4] = [
     PIN_DISPLAY_STROBE0,
@@ -14,6 +18,36 @@ const STROBE_PINS: [usize; 4] = [
 
 const BLANK: [u8; 8] = [0, 0, 0, 0, 0, 0, 0, 0];
 const STARTUP: [u8; 6] = [0xAA, 0x55, 0x11, 0x33, 0xDD, 0xFF];
+/// Shown on every display at once by the test pattern, after each display has been lit on its own.
+const TEST_PATTERN: [u8; 2] = [0xAA, 0x55];
+/// Steps of the test pattern: each display lit on its own, then each of `TEST_PATTERN` on all of them.
+const TEST_STEPS: usize = 4 + TEST_PATTERN.len();
+/// Time each step of the test pattern is shown for, in microseconds.
+const TEST_STEP_US: u32 = 250000;
+
+/// Period of the software PWM that dims the displays, in microseconds. A power of 2, so the timer wrapping
+/// around doesn't cut a period short.
+const PWM_PERIOD_US: u32 = 4096;
+
+/// Consoles with their own display layout.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Console {
+    Nes = 0,
+    Snes = 1,
+    N64 = 2,
+    Genesis = 3,
+}
+impl Console {
+    fn of(mode: VeritasMode) -> Option<Self> {
+        match mode {
+            VeritasMode::ReplayNes | VeritasMode::SpyNes => Some(Console::Nes),
+            VeritasMode::ReplaySnes | VeritasMode::SpySnes => Some(Console::Snes),
+            VeritasMode::ReplayN64 => Some(Console::N64),
+            VeritasMode::ReplayGenesis | VeritasMode::SpyGenesis => Some(Console::Genesis),
+            _ => None,
+        }
+    }
+}
 
 #[derive(Debug, PartialEq, Copy, Clone, IntoPrimitive, FromPrimitive)]
 #[repr(u8)]
@@ -28,7 +62,36 @@ pub enum Port {
 }
 
 static mut PORT_QUEUES: [Queue<Vec<u8, 8>, 4>; 4] = [Queue::new(), Queue::new(), Queue::new(), Queue::new()];
+/// Producer ends of the port queues, which the systems fill as the inputs change. Do not use outside of CORE0!
+static mut PRODUCERS: Option<[Producer<'static, Vec<u8, 8>, 4>; 4]> = None;
+/// Consumer ends of the port queues. Do not use outside of CORE1!
+static mut CONSUMERS: Option<[Consumer<'static, Vec<u8, 8>, 4>; 4]> = None;
 
+/// Layout applied to each console's inputs, indexed by [`Console`]. The Genesis input has two of its bits in
+/// the opposite order to the displays. Do not use outside of CORE1!
+static mut LAYOUTS: [BitLayout; 4] = [BitLayout::IDENTITY, BitLayout::IDENTITY, BitLayout::IDENTITY, BitLayout::swapped(2, 3)];
+/// What each display is showing, so it can be redrawn after being blanked. Do not use outside of CORE1!
+static mut SHOWN: [Vec<u8, 8>; 4] = [Vec::new(), Vec::new(), Vec::new(), Vec::new()];
+/// Brightness in percent, where 0 keeps the displays blank. Do not use outside of CORE1!
+static mut BRIGHTNESS: u8 = 100;
+/// Whether the displays are lit at this point of the PWM period. Do not use outside of CORE1!
+static mut LIT: bool = true;
+/// Step of the test pattern being shown, and the time it was shown at. Do not use outside of CORE1!
+static mut TEST_STEP: Option<(usize, u32)> = None;
+
+/// Splits the port queues between the cores. Must be called once, before core1 is started.
+pub unsafe fn split_queues() {
+    let [q0, q1, q2, q3] = &mut PORT_QUEUES;
+    let (p0, c0) = q0.split();
+    let (p1, c1) = q1.split();
+    let (p2, c2) = q2.split();
+    let (p3, c3) = q3.split();
+    
+    PRODUCERS = Some([p0, p1, p2, p3]);
+    CONSUMERS = Some([c0, c1, c2, c3]);
+}
+
+/// Queues data to be shown on a port's display. Must only be called from CORE0.
 #[link_section = ".ram_code"]
 pub fn set_display(port: Port, data: &[u8]) {
     if port == Port::Err {
@@ -38,15 +101,77 @@ pub fn set_display(port: Port, data: &[u8]) {
     let data = Vec::from_slice(data).unwrap();
     
     unsafe {
-        PORT_QUEUES[port as usize].enqueue(data).unwrap_or_default();
+        if let Some(producers) = PRODUCERS.as_mut() {
+            producers[port as usize].enqueue(data).unwrap_or_default();
+        }
     }
 }
 
+/// Shows whatever the systems have queued, arranged by the current console's layout, and keeps the displays
+/// dimmed. Must be called periodically from CORE1.
 #[link_section = ".ram_code"]
 pub fn check_displays() {
-    for i in 0..4 {
-        if let Some(data) = unsafe { PORT_QUEUES[i].dequeue() } {
-            write((i as u8).into(), &data);
+    if let Some(consumers) = unsafe { CONSUMERS.as_mut() } {
+        let console = Console::of(replaycore::mode());
+        
+        for (i, consumer) in consumers.iter_mut().enumerate() {
+            if let Some(mut data) = consumer.dequeue() {
+                if let Some(console) = console {
+                    unsafe { LAYOUTS[console as usize].apply(&mut data); }
+                }
+                show((i as u8).into(), &data);
+            }
+        }
+    }
+    
+    check_test_pattern();
+    update_pwm();
+}
+
+/// Shows data on a port's display as is, until something else is shown. Must only be called from CORE1.
+pub fn show(port: Port, data: &[u8]) {
+    if port == Port::Err {
+        return;
+    }
+    
+    unsafe {
+        SHOWN[port as usize] = Vec::from_slice(data).unwrap_or_default();
+        if LIT {
+            write(port, data);
+        }
+    }
+}
+
+/// Changes how a console's inputs are arranged on the displays. Must only be called from CORE1.
+pub fn set_layout(console: Console, layout: BitLayout) {
+    unsafe { LAYOUTS[console as usize] = layout; }
+}
+
+/// Sets the brightness of every display, in percent. 0 blanks them until it's raised again. Must only be
+/// called from CORE1.
+pub fn set_brightness(percent: u8) {
+    unsafe { BRIGHTNESS = percent.min(100); }
+    update_pwm();
+}
+
+/// Blanks or redraws the displays as the PWM period passes. Only runs from core1's loop, so the displays are
+/// left lit or blank for as long as core1 is held up elsewhere.
+#[link_section = ".ram_code"]
+fn update_pwm() {
+    unsafe {
+        let lit = match BRIGHTNESS {
+            _ if TEST_STEP.is_some() => true,
+            0 => false,
+            100.. => true,
+            percent => (*TIMER::ptr()).timerawl.read().bits() % PWM_PERIOD_US < PWM_PERIOD_US * percent as u32 / 100,
+        };
+        if lit == LIT {
+            return;
+        }
+        
+        LIT = lit;
+        for port in 0..4 {
+            write((port as u8).into(), if lit { &SHOWN[port] } else { &BLANK });
         }
     }
 }
@@ -75,6 +200,49 @@ pub fn initialize() {
     }
 }
 
+/// Lights every segment of each display in turn, then alternating segments on all of them, so dead segments
+/// and swapped strobe lines stand out. The pattern is stepped through by [`check_displays`] over a couple of
+/// seconds, at full brightness, so this returns straight away. Must only be called from CORE1.
+pub fn start_test_pattern() {
+    unsafe { TEST_STEP = Some((0, (*TIMER::ptr()).timerawl.read().bits())); }
+    show_test_step(0);
+}
+
+/// Moves the test pattern on once a step has been shown for long enough. It's abandoned if a replay starts.
+fn check_test_pattern() {
+    unsafe {
+        let Some((step, shown_at)) = TEST_STEP else {
+            return;
+        };
+        
+        let now = (*TIMER::ptr()).timerawl.read().bits();
+        if replaycore::mode() == VeritasMode::Idle && now.wrapping_sub(shown_at) < TEST_STEP_US {
+            return;
+        }
+        
+        if replaycore::mode() == VeritasMode::Idle && step + 1 < TEST_STEPS {
+            TEST_STEP = Some((step + 1, now));
+            show_test_step(step + 1);
+        } else {
+            TEST_STEP = None;
+            for port in 0..4 {
+                show(port.into(), &BLANK);
+            }
+        }
+    }
+}
+
+fn show_test_step(step: usize) {
+    for port in 0..4 {
+        let data = match step.checked_sub(4) {
+            None if port == step => [0xFF; 8],
+            None => BLANK,
+            Some(byte) => [TEST_PATTERN[byte]; 8],
+        };
+        show((port as u8).into(), &data);
+    }
+}
+
 fn write(port: Port, data: &[u8]) {
     for byte in data {
         for i in 0..8 {
@@ -1,3 +1,4 @@
+use std::collections::BTreeMap;
 use camino::{Utf8Path, Utf8PathBuf};
 use serde::{Deserialize, Serialize};
 use serde::de::DeserializeOwned;
@@ -36,16 +37,53 @@ pub struct DumperSection {
     pub gens_emu: Utf8PathBuf,
 }
 
+/// Names given to replay devices.
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+pub struct DevicesSection {
+    /// Maps an alias to the board ID of a device (the unique part of its USB serial number).
+    pub aliases: BTreeMap<String, String>,
+}
+impl DevicesSection {
+    /// Returns the alias given to a board, if it has one.
+    pub fn alias_of(&self, board_id: &str) -> Option<&str> {
+        self.aliases.iter()
+            .find(|(_, id)| *id == board_id)
+            .map(|(alias, _)| alias.as_str())
+    }
+}
+
+/// Where files written during replays are kept.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct LogsSection {
+    /// Directory that devices' event logs are saved to. A relative path is from the working directory.
+    pub events_path: Utf8PathBuf,
+}
+impl Default for LogsSection {
+    fn default() -> Self { Self {
+        events_path: "logs".into(),
+    }}
+}
+
 #[derive(Debug, Default, Clone, Deserialize, Serialize)]
 pub struct VeritasConfig {
     /// HTTP User-Agent string used in all queries to the TASVideos API.
     pub useragent: String,
     pub dumper: DumperSection,
+    #[serde(default)]
+    pub devices: DevicesSection,
+    #[serde(default)]
+    pub logs: LogsSection,
 }
 impl SaveLoad for VeritasConfig {}
 impl VeritasConfig {
+    const PATH: &'static str = "veritas.toml";
+    
     pub fn load() -> Self {
-        SaveLoad::load("veritas.toml")
+        SaveLoad::load(Self::PATH)
+    }
+    
+    pub fn save(&self) {
+        SaveLoad::save(self, Self::PATH)
     }
 }
 
@@ -0,0 +1,182 @@
+use clap::ValueEnum;
+use log::{debug, error, info, warn};
+use crate::config::DevicesSection;
+use crate::replay;
+use crate::replay::comms::{Command, LineKind, LineRole, PortLine, Response, System};
+use crate::DiagArgs;
+
+/// Console the device is wired to, which decides what each controller port line is checked for.
+#[derive(Debug, PartialEq, Eq, Copy, Clone, ValueEnum)]
+pub enum Console {
+    Nes,
+    Snes,
+    N64,
+    Genesis,
+}
+impl From<Console> for System {
+    fn from(console: Console) -> Self {
+        match console {
+            Console::Nes => System::Nes,
+            Console::Snes => System::Snes,
+            Console::N64 => System::N64,
+            Console::Genesis => System::Genesis,
+        }
+    }
+}
+
+fn name(kind: LineKind) -> &'static str {
+    match kind {
+        LineKind::Data => "data",
+        LineKind::Clock => "clock",
+        LineKind::Latch => "latch",
+        LineKind::Detect => "detect",
+        LineKind::Select => "select",
+        LineKind::Up => "up",
+        LineKind::Down => "down",
+        LineKind::Left => "left",
+        LineKind::Right => "right",
+        LineKind::BA => "b/a",
+        LineKind::CStart => "c/start",
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+enum Check {
+    Pass,
+    /// Read back as driven, but only at the RP2040's pins, so the level shifters and connector weren't checked.
+    PadOnly,
+    Fail(String),
+    Skipped,
+}
+
+/// Everything measured by the device.
+struct Measurements {
+    levels: u32,
+    console: bool,
+    output_faults: Option<u32>,
+    edges: Option<Vec<u32>>,
+}
+impl Measurements {
+    fn check(&self, line: &PortLine) -> Check {
+        let bit = 1 << line.pin;
+        let name = name(line.kind);
+        
+        let output = || match self.output_faults {
+            Some(faults) if faults & bit != 0 => Check::Fail(format!("{name} (CNT_{}) doesn't read back as driven", line.pin)),
+            Some(_) => Check::PadOnly,
+            None => Check::Skipped,
+        };
+        let input = || match &self.edges {
+            Some(edges) if edges.get(line.pin as usize).copied().unwrap_or(0) == 0 => Check::Fail(format!("{name} (CNT_{}) never changed", line.pin)),
+            Some(_) => Check::Pass,
+            None => Check::Skipped,
+        };
+        
+        match line.role {
+            LineRole::Output => output(),
+            LineRole::Input => input(),
+            LineRole::Bidirectional => match (output(), input()) {
+                (fail @ Check::Fail(_), _) | (_, fail @ Check::Fail(_)) => fail,
+                (Check::Skipped, Check::Skipped) => Check::Skipped,
+                (Check::PadOnly, _) => Check::PadOnly,
+                _ => Check::Pass,
+            },
+            LineRole::Sense if !self.console => Check::Skipped,
+            LineRole::Sense if self.levels & bit == 0 => Check::Fail(format!("{name} (CNT_{}) is low with the console powered", line.pin)),
+            LineRole::Sense => Check::Pass,
+        }
+    }
+}
+
+pub fn handle(args: DiagArgs, devices: &DevicesSection) {
+    let mut dev = replay::connect(args.device.as_deref(), devices);
+    
+    let ports = match dev.send_command(Command::GetPinMap(args.console.into())) {
+        Response::PinMap(ports) => ports,
+        resp => {
+            error!("Failed to get the controller port lines: {resp:?}");
+            return;
+        }
+    };
+    
+    let (levels, console) = match dev.send_command(Command::ReadPins) {
+        Response::Pins { levels, console } => (levels, console),
+        resp => {
+            error!("Failed to read pins: {resp:?}");
+            return;
+        }
+    };
+    info!("Console is powered {}", if console { "on" } else { "off" });
+    debug!("Line levels: {levels:019b}");
+    
+    // Driving the outputs against a powered console could damage it, and its signals can only be measured
+    // while it's running, so each run only does one of the two.
+    let output_faults = if console {
+        warn!("Skipping the output test, run again with the console powered off to include it");
+        None
+    } else {
+        info!("Testing outputs (read back at the device's pins, so the level shifters and connector aren't covered)...");
+        match dev.send_command(Command::TestOutputs) {
+            Response::OutputFaults(faults) => Some(faults),
+            resp => {
+                error!("Failed to test outputs! Is a replay running? ({resp:?})");
+                return;
+            }
+        }
+    };
+    
+    let edges = if console {
+        info!("Measuring the console's signals for {}ms...", args.duration);
+        match dev.send_command(Command::MeasureEdges(args.duration)) {
+            Response::Edges(edges) => Some(edges),
+            resp => {
+                error!("Failed to measure signals! Is a replay running? ({resp:?})");
+                return;
+            }
+        }
+    } else {
+        warn!("Skipping the signal measurement, run again with a game running to include it");
+        None
+    };
+    if let Some(edges) = &edges {
+        debug!("Edges per line: {edges:?}");
+    }
+    
+    if !args.skip_displays {
+        info!("Showing the display test pattern: each display lights up fully in turn, then all show alternating segments");
+        if dev.send_command(Command::TestDisplays).is_not_ok() {
+            error!("Failed to show the display test pattern!");
+        }
+    }
+    
+    let measurements = Measurements { levels, console, output_faults, edges };
+    for (i, lines) in ports.iter().enumerate() {
+        let checks: Vec<(&PortLine, Check)> = lines.iter().map(|line| (line, measurements.check(line))).collect();
+        
+        let failures: Vec<&str> = checks.iter().filter_map(|(_, check)| match check {
+            Check::Fail(reason) => Some(reason.as_str()),
+            _ => None,
+        }).collect();
+        let with = |wanted: Check| checks.iter().filter(|(_, check)| *check == wanted).map(|(line, _)| name(line.kind)).collect::<Vec<_>>();
+        let (pad_only, skipped) = (with(Check::PadOnly), with(Check::Skipped));
+        
+        let mut notes = vec![];
+        if !pad_only.is_empty() {
+            notes.push(format!("pad only: {}", pad_only.join(", ")));
+        }
+        if !skipped.is_empty() {
+            notes.push(format!("not tested: {}", skipped.join(", ")));
+        }
+        
+        let port = i + 1;
+        if !failures.is_empty() {
+            error!("Port {port}: FAIL - {}", failures.join(", "));
+        } else if skipped.len() == checks.len() {
+            warn!("Port {port}: UNTESTED");
+        } else if !notes.is_empty() {
+            info!("Port {port}: PASS ({})", notes.join("; "));
+        } else {
+            info!("Port {port}: PASS");
+        }
+    }
+}
\ No newline at end of file
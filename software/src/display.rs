@@ -0,0 +1,67 @@
+use log::{error, info};
+use crate::config::DevicesSection;
+use crate::replay;
+use crate::replay::comms::Command;
+use crate::DisplayArgs;
+
+/// Largest number of bytes a display can show.
+const MAX_DISPLAY_BYTES: usize = 8;
+
+/// Parses `PORT=HEX`, e.g. `1=FF00`, into a zero-based port and the bytes to show on it.
+pub fn parse_show(arg: &str) -> Result<(u8, Vec<u8>), String> {
+    let (port, data) = arg.split_once('=').ok_or("expected PORT=HEX")?;
+    
+    let port = match port.parse::<u8>() {
+        Ok(port @ 1..=4) => port - 1,
+        _ => return Err(format!("invalid display port '{port}', expected 1 to 4")),
+    };
+    let data = hex::decode(data).map_err(|err| format!("invalid hex '{data}': {err}"))?;
+    if data.len() > MAX_DISPLAY_BYTES {
+        return Err(format!("a display shows at most {MAX_DISPLAY_BYTES} bytes"));
+    }
+    
+    Ok((port, data))
+}
+
+pub fn handle(args: DisplayArgs, devices: &DevicesSection) {
+    let mut dev = replay::connect(args.device.as_deref(), devices);
+    
+    if let (Some(console), Some(layout)) = (args.console, args.layout) {
+        let layout: [u8; 16] = match layout.try_into() {
+            Ok(layout) => layout,
+            Err(_) => {
+                error!("A layout needs exactly 16 bits");
+                return;
+            }
+        };
+        
+        if dev.send_command(Command::SetDisplayLayout { system: console.into(), layout }).is_not_ok() {
+            error!("Failed to set the display layout! Each bit must be between 0 and 15");
+            return;
+        }
+        info!("Set the display layout for {console:?}");
+    }
+    
+    if let Some(brightness) = args.brightness {
+        if dev.send_command(Command::SetDisplayBrightness(brightness)).is_not_ok() {
+            error!("Failed to set the display brightness!");
+            return;
+        }
+        info!("Set the display brightness to {brightness}%");
+    }
+    
+    if args.test_pattern {
+        info!("Showing the display test pattern...");
+        if dev.send_command(Command::TestDisplays).is_not_ok() {
+            error!("Failed to show the display test pattern! Is a replay running?");
+            return;
+        }
+    }
+    
+    for (port, data) in args.show {
+        if dev.send_command(Command::SetDisplay { port, data }).is_not_ok() {
+            error!("Failed to write to display {}!", port + 1);
+            return;
+        }
+    }
+}
\ No newline at end of file
@@ -0,0 +1,378 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use camino::Utf8Path;
+use log::{error, info, warn};
+use crate::config::DevicesSection;
+use crate::replay;
+use crate::replay::DeviceInfo;
+use crate::replay::comms::{Command, Response};
+use crate::{FirmwareArgs, FirmwareCommand, FlashArgs};
+
+const UF2_MAGIC_START0: u32 = 0x0A324655;
+const UF2_MAGIC_START1: u32 = 0x9E5D5157;
+const UF2_MAGIC_END: u32 = 0x0AB16F30;
+const UF2_FLAG_FAMILY_ID: u32 = 0x00002000;
+const UF2_BLOCK_SIZE: usize = 512;
+const UF2_PAYLOAD_SIZE: usize = 256;
+const RP2040_FAMILY_ID: u32 = 0xE48BFF56;
+
+const FLASH_START: u32 = 0x10000000;
+const FLASH_END: u32 = 0x11000000;
+
+/// Prefix of the version string embedded in every firmware image.
+const VERSION_MARKER: &[u8] = b"VERITAS_FIRMWARE_VERSION=";
+
+pub fn handle(args: FirmwareArgs, devices: &DevicesSection) {
+    match args.command {
+        FirmwareCommand::Flash(args) => flash(args, devices),
+        FirmwareCommand::Version { device } => {
+            let mut dev = replay::connect(device.as_deref(), devices);
+            match dev.send_command(Command::GetVersion) {
+                Response::Version(version) => info!("Firmware version: {version}"),
+                resp => error!("Failed to get firmware version: {resp:?}"),
+            }
+        },
+    }
+}
+
+fn flash(args: FlashArgs, devices: &DevicesSection) {
+    let image = match Image::load(&args.file) {
+        Ok(image) => image,
+        Err(err) => {
+            error!("{err}");
+            return;
+        }
+    };
+    let version = image.version();
+    match &version {
+        Some(version) => info!("Image contains firmware version {version}"),
+        None => warn!("Image doesn't contain a firmware version, it won't be verified after flashing"),
+    }
+    let timeout = Duration::from_secs(args.timeout);
+    
+    let board_id = match replay::find_device(args.device.as_deref(), devices) {
+        Ok(info) => info.board_id().to_owned(),
+        Err(_) if args.mount.is_some() => replay::SERIAL_PREFIX.to_owned(),
+        Err(err) => {
+            error!("{err}");
+            return;
+        }
+    };
+    
+    let drive = match args.mount {
+        Some(mount) => mount.into_std_path_buf(),
+        None => {
+            let mut dev = replay::connect(args.device.as_deref(), devices);
+            if dev.send_command(Command::RebootToBootloader).is_not_ok() {
+                error!("Device refused to reboot! Is a replay running?");
+                return;
+            }
+            drop(dev);
+            
+            info!("Waiting for the bootloader drive...");
+            match wait_for(timeout, find_bootloader_drive) {
+                Some(drive) => drive,
+                None => {
+                    error!("Bootloader drive not found! If it wasn't mounted automatically, mount it and use --mount");
+                    return;
+                }
+            }
+        },
+    };
+    
+    info!("Writing firmware to {}", drive.display());
+    if let Err(err) = std::fs::write(drive.join("firmware.uf2"), image.to_uf2()) {
+        error!("Failed to write firmware: {err}");
+        return;
+    }
+    
+    info!("Waiting for the device to restart...");
+    // Firmware which predates board IDs can't be told apart from other devices
+    let same_board = |dev: &DeviceInfo| dev.board_id() == board_id || board_id == replay::SERIAL_PREFIX;
+    let device = match wait_for(timeout, || replay::list_devices().into_iter().find(same_board)) {
+        Some(device) => device,
+        None => {
+            error!("Device didn't return after flashing!");
+            return;
+        }
+    };
+    sleep(Duration::from_millis(500));
+    
+    let mut dev = replay::connect(Some(&device.path), devices);
+    match (dev.send_command(Command::GetVersion), version) {
+        (Response::Version(actual), Some(expected)) if actual == expected => info!("Firmware {actual} flashed successfully."),
+        (Response::Version(actual), Some(expected)) => error!("Device reports firmware {actual}, but {expected} was flashed!"),
+        (Response::Version(actual), None) => info!("Device reports firmware {actual}."),
+        (resp, _) => error!("Failed to get firmware version: {resp:?}"),
+    }
+}
+
+/// Repeatedly calls `f` until it returns something, or the timeout has passed.
+fn wait_for<T, F: FnMut() -> Option<T>>(timeout: Duration, mut f: F) -> Option<T> {
+    let start = Instant::now();
+    while start.elapsed() < timeout {
+        if let Some(found) = f() {
+            return Some(found);
+        }
+        
+        sleep(Duration::from_millis(250));
+    }
+    
+    None
+}
+
+/// Searches the usual mount locations for the RP2040 bootloader's mass storage drive.
+fn find_bootloader_drive() -> Option<PathBuf> {
+    let mut candidates = vec![];
+    
+    if cfg!(windows) {
+        candidates.extend((b'D'..=b'Z').map(|letter| PathBuf::from(format!("{}:\\", letter as char))));
+    } else {
+        let mut roots = vec![PathBuf::from("/Volumes"), PathBuf::from("/media"), PathBuf::from("/run/media")];
+        if let Ok(user) = std::env::var("USER") {
+            roots.push(Path::new("/media").join(&user));
+            roots.push(Path::new("/run/media").join(&user));
+        }
+        
+        for root in roots {
+            if let Ok(entries) = std::fs::read_dir(root) {
+                candidates.extend(entries.filter_map(|entry| entry.ok()).map(|entry| entry.path()));
+            }
+        }
+    }
+    
+    candidates.into_iter().find(|path| {
+        std::fs::read_to_string(path.join("INFO_UF2.TXT"))
+            .map(|info| info.contains("RPI-RP2"))
+            .unwrap_or(false)
+    })
+}
+
+/// Data to be written to flash, by address.
+struct Image {
+    segments: Vec<(u32, Vec<u8>)>,
+}
+impl Image {
+    pub fn load(path: &Utf8Path) -> Result<Self, String> {
+        let data = std::fs::read(path).map_err(|err| format!("Failed to read {path}: {err}"))?;
+        
+        if data.starts_with(b"\x7FELF") {
+            Self::from_elf(&data)
+        } else if data.len() >= 4 && read_u32(&data, 0) == UF2_MAGIC_START0 {
+            Self::from_uf2(&data)
+        } else {
+            Err(format!("{path} is neither an ELF nor a UF2 file"))
+        }
+    }
+    
+    fn from_elf(data: &[u8]) -> Result<Self, String> {
+        if data.len() < 0x34 || data[4] != 1 || data[5] != 1 {
+            return Err("Only little-endian 32-bit ELF files are supported".into());
+        }
+        
+        let phoff = read_u32(data, 0x1C) as usize;
+        let phentsize = read_u16(data, 0x2A) as usize;
+        let phnum = read_u16(data, 0x2C) as usize;
+        
+        let mut segments = vec![];
+        for i in 0..phnum {
+            let ph = phoff + i * phentsize;
+            if ph + 0x20 > data.len() {
+                return Err("ELF program header is truncated".into());
+            }
+            
+            let kind = read_u32(data, ph);
+            let offset = read_u32(data, ph + 0x04) as usize;
+            let paddr = read_u32(data, ph + 0x0C);
+            let filesz = read_u32(data, ph + 0x10) as usize;
+            
+            // Only loadable segments stored in flash; RAM contents are copied out of flash at boot.
+            if kind != 1 || filesz == 0 || !(FLASH_START..FLASH_END).contains(&paddr) {
+                continue;
+            }
+            let contents = data.get(offset..(offset + filesz)).ok_or("ELF segment is truncated")?;
+            
+            segments.push((paddr, contents.to_vec()));
+        }
+        
+        if segments.is_empty() {
+            return Err("ELF file doesn't contain anything to write to flash".into());
+        }
+        
+        Ok(Self { segments })
+    }
+    
+    fn from_uf2(data: &[u8]) -> Result<Self, String> {
+        let mut segments = vec![];
+        for block in data.chunks(UF2_BLOCK_SIZE) {
+            if block.len() != UF2_BLOCK_SIZE || read_u32(block, 0) != UF2_MAGIC_START0 || read_u32(block, 4) != UF2_MAGIC_START1 || read_u32(block, 508) != UF2_MAGIC_END {
+                return Err("UF2 file contains an invalid block".into());
+            }
+            if read_u32(block, 8) & UF2_FLAG_FAMILY_ID != 0 && read_u32(block, 28) != RP2040_FAMILY_ID {
+                return Err("UF2 file isn't for the RP2040".into());
+            }
+            
+            let addr = read_u32(block, 12);
+            let size = (read_u32(block, 16) as usize).min(476);
+            segments.push((addr, block[32..(32 + size)].to_vec()));
+        }
+        
+        Ok(Self { segments })
+    }
+    
+    /// Encodes the image as UF2 blocks, padding partially used pages with zeros.
+    pub fn to_uf2(&self) -> Vec<u8> {
+        let mut pages: BTreeMap<u32, [u8; UF2_PAYLOAD_SIZE]> = BTreeMap::new();
+        for (addr, data) in &self.segments {
+            for (i, byte) in data.iter().enumerate() {
+                let addr = addr + i as u32;
+                let page = pages.entry(addr & !(UF2_PAYLOAD_SIZE as u32 - 1)).or_insert([0; UF2_PAYLOAD_SIZE]);
+                page[addr as usize % UF2_PAYLOAD_SIZE] = *byte;
+            }
+        }
+        
+        let mut uf2 = Vec::with_capacity(pages.len() * UF2_BLOCK_SIZE);
+        for (i, (addr, page)) in pages.iter().enumerate() {
+            let mut block = vec![];
+            for word in [UF2_MAGIC_START0, UF2_MAGIC_START1, UF2_FLAG_FAMILY_ID, *addr, UF2_PAYLOAD_SIZE as u32, i as u32, pages.len() as u32, RP2040_FAMILY_ID] {
+                block.extend_from_slice(&word.to_le_bytes());
+            }
+            block.extend_from_slice(page);
+            block.resize(UF2_BLOCK_SIZE - 4, 0);
+            block.extend_from_slice(&UF2_MAGIC_END.to_le_bytes());
+            
+            uf2.extend_from_slice(&block);
+        }
+        
+        uf2
+    }
+    
+    /// Finds the firmware version embedded in the image.
+    pub fn version(&self) -> Option<String> {
+        // UF2 images are split into small blocks, so the marker may span more than one segment
+        let mut segments: Vec<&(u32, Vec<u8>)> = self.segments.iter().collect();
+        segments.sort_by_key(|(addr, _)| *addr);
+        let data: Vec<u8> = segments.into_iter().flat_map(|(_, data)| data.iter().copied()).collect();
+        
+        let start = data.windows(VERSION_MARKER.len()).position(|window| window == VERSION_MARKER)? + VERSION_MARKER.len();
+        let len = data[start..].iter().position(|byte| *byte == 0)?;
+        
+        String::from_utf8(data[start..(start + len)].to_vec()).ok()
+    }
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..(offset + 4)].try_into().unwrap())
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..(offset + 2)].try_into().unwrap())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    /// Every byte the image writes, by address.
+    fn bytes(image: &Image) -> BTreeMap<u32, u8> {
+        image.segments.iter()
+            .flat_map(|(addr, data)| data.iter().enumerate().map(move |(i, byte)| (addr + i as u32, *byte)))
+            .collect()
+    }
+    
+    /// A little-endian 32-bit ELF file with a loadable segment for each of `segments`.
+    fn elf(segments: &[(u32, &[u8])]) -> Vec<u8> {
+        let phoff = 0x34;
+        let mut data = vec![0; phoff + segments.len() * 0x20];
+        data[..6].copy_from_slice(b"\x7FELF\x01\x01");
+        data[0x1C..0x20].copy_from_slice(&(phoff as u32).to_le_bytes());
+        data[0x2A..0x2C].copy_from_slice(&0x20u16.to_le_bytes());
+        data[0x2C..0x2E].copy_from_slice(&(segments.len() as u16).to_le_bytes());
+        
+        for (i, (paddr, contents)) in segments.iter().enumerate() {
+            let ph = phoff + i * 0x20;
+            let offset = data.len() as u32;
+            for (at, word) in [(0x00, 1), (0x04, offset), (0x08, *paddr), (0x0C, *paddr), (0x10, contents.len() as u32)] {
+                data[(ph + at)..(ph + at + 4)].copy_from_slice(&word.to_le_bytes());
+            }
+            data.extend_from_slice(contents);
+        }
+        
+        data
+    }
+    
+    #[test]
+    fn uf2_blocks_follow_the_layout() {
+        let image = Image { segments: vec![(FLASH_START, vec![0xAA; 300])] };
+        let uf2 = image.to_uf2();
+        
+        assert_eq!(uf2.len(), 2 * UF2_BLOCK_SIZE);
+        for (i, block) in uf2.chunks(UF2_BLOCK_SIZE).enumerate() {
+            let header: Vec<u32> = (0..8).map(|n| read_u32(block, n * 4)).collect();
+            let addr = FLASH_START + (i * UF2_PAYLOAD_SIZE) as u32;
+            
+            assert_eq!(header, [UF2_MAGIC_START0, UF2_MAGIC_START1, UF2_FLAG_FAMILY_ID, addr, 256, i as u32, 2, RP2040_FAMILY_ID]);
+            assert_eq!(read_u32(block, 508), UF2_MAGIC_END);
+            assert!(block[(32 + 256)..508].iter().all(|byte| *byte == 0));
+        }
+        
+        // The rest of the second page is padded with zeros
+        let second = &uf2[UF2_BLOCK_SIZE..];
+        assert!(second[32..(32 + 44)].iter().all(|byte| *byte == 0xAA));
+        assert!(second[(32 + 44)..(32 + 256)].iter().all(|byte| *byte == 0));
+    }
+    
+    #[test]
+    fn segments_in_the_same_page_share_a_block() {
+        let image = Image { segments: vec![(FLASH_START + 0x10, vec![1; 16]), (FLASH_START + 0x80, vec![2; 4])] };
+        let uf2 = image.to_uf2();
+        
+        assert_eq!(uf2.len(), UF2_BLOCK_SIZE);
+        assert_eq!(&uf2[(32 + 0x10)..(32 + 0x20)], &[1; 16]);
+        assert_eq!(&uf2[(32 + 0x80)..(32 + 0x84)], &[2; 4]);
+    }
+    
+    #[test]
+    fn uf2_round_trips() {
+        let data: Vec<u8> = (0..1000u32).map(|i| (i * 7) as u8).collect();
+        let image = Image { segments: vec![(FLASH_START + 0x40, data.clone()), (FLASH_START + 0x10000, data)] };
+        
+        let (written, decoded) = (bytes(&image), bytes(&Image::from_uf2(&image.to_uf2()).unwrap()));
+        
+        // Decoded pages include their padding, which was written as zeros
+        assert!(written.iter().all(|(addr, byte)| decoded.get(addr) == Some(byte)));
+        assert!(decoded.iter().filter(|(addr, _)| !written.contains_key(addr)).all(|(_, byte)| *byte == 0));
+    }
+    
+    #[test]
+    fn elf_loads_only_flash_segments() {
+        let file = elf(&[(FLASH_START, &[1, 2, 3, 4]), (0x20000000, &[5, 6, 7, 8])]);
+        
+        let image = Image::from_elf(&file).unwrap();
+        
+        assert_eq!(image.segments, [(FLASH_START, vec![1, 2, 3, 4])]);
+    }
+    
+    #[test]
+    fn uf2_from_elf_keeps_the_version() {
+        // The marker straddles a page boundary, so it's split between two blocks
+        let mut contents = vec![0xFF; UF2_PAYLOAD_SIZE - 10];
+        contents.extend_from_slice(b"VERITAS_FIRMWARE_VERSION=0.1.0+abc1234-dirty\0");
+        let image = Image::from_elf(&elf(&[(FLASH_START, &contents)])).unwrap();
+        
+        let decoded = Image::from_uf2(&image.to_uf2()).unwrap();
+        
+        assert_eq!(decoded.version().as_deref(), Some("0.1.0+abc1234-dirty"));
+    }
+    
+    #[test]
+    fn uf2_for_another_family_is_rejected() {
+        let mut uf2 = Image { segments: vec![(FLASH_START, vec![0; 4])] }.to_uf2();
+        uf2[28..32].copy_from_slice(&0x12345678u32.to_le_bytes());
+        
+        assert!(Image::from_uf2(&uf2).is_err());
+    }
+}
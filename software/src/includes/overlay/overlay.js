@@ -0,0 +1,79 @@
+// Shared by every overlay skin. A skin calls `veritasOverlay` with a description of one controller:
+//
+//   buttons:   [label, byte, bit] for each button, where bit 7 is the MSB of that byte of the port's input
+//   activeLow: whether a pressed button reads as 0, as on the NES, SNES and Genesis
+//   stick:     optional [x byte, y byte] of a signed analog stick, as on the N64
+//
+// Each port gets a `.port` element holding a `.button` per button (with `.pressed` while it's held), and a
+// `.stick` with a `.dot`. The skin's own CSS decides how they look.
+function veritasOverlay(skin) {
+    const root = document.getElementById("ports");
+    const frame = document.getElementById("frame");
+    let ports = [];
+
+    function build(count) {
+        root.textContent = "";
+        ports = [];
+        for (let i = 0; i < count; i++) {
+            const port = document.createElement("div");
+            port.className = "port";
+
+            const label = document.createElement("div");
+            label.className = "label";
+            label.textContent = "P" + (i + 1);
+            port.appendChild(label);
+
+            const buttons = skin.buttons.map(([name]) => {
+                const button = document.createElement("span");
+                button.className = "button button-" + name.toLowerCase();
+                button.textContent = name;
+                port.appendChild(button);
+                return button;
+            });
+
+            let dot = null;
+            if (skin.stick) {
+                const stick = document.createElement("div");
+                stick.className = "stick";
+                dot = document.createElement("div");
+                dot.className = "dot";
+                stick.appendChild(dot);
+                port.appendChild(stick);
+            }
+
+            root.appendChild(port);
+            ports.push({ buttons, dot });
+        }
+    }
+
+    function render(state) {
+        if (ports.length !== state.ports.length) {
+            build(state.ports.length);
+        }
+        if (frame) {
+            frame.textContent = state.frame + " / " + state.length;
+        }
+
+        state.ports.forEach((input, i) => {
+            const port = ports[i];
+            skin.buttons.forEach(([, byte, bit], j) => {
+                const set = input.length > byte && (input[byte] & (1 << bit)) !== 0;
+                port.buttons[j].classList.toggle("pressed", input.length > byte && set !== skin.activeLow);
+            });
+
+            if (port.dot) {
+                const axis = (byte) => input.length > byte ? ((input[byte] << 24) >> 24) / 128 : 0;
+                port.dot.style.left = (50 + axis(skin.stick[0]) * 50) + "%";
+                port.dot.style.top = (50 - axis(skin.stick[1]) * 50) + "%";
+            }
+        });
+    }
+
+    function connect() {
+        const socket = new WebSocket("ws://" + location.host + "/ws");
+        socket.onmessage = (event) => render(JSON.parse(event.data));
+        socket.onclose = () => setTimeout(connect, 1000);
+    }
+
+    connect();
+}
@@ -1,13 +1,19 @@
+use std::net::SocketAddr;
 use camino::Utf8PathBuf;
 use clap::{Parser, Subcommand};
 use log::LevelFilter;
 use crate::config::{SaveLoad, VeritasConfig};
+use crate::replay::comms::ControllerPak;
 
 mod config;
+mod diag;
+mod display;
 mod dumping;
 mod encode;
+mod firmware;
 mod logger;
 mod replay;
+mod spy;
 
 #[derive(Debug, Parser)]
 #[command(author, version, about)]
@@ -27,6 +33,14 @@ pub enum Command {
     Encode(EncodeArgs),
     Dump(DumpArgs),
     Replay(ReplayArgs),
+    Upload(UploadArgs),
+    Firmware(FirmwareArgs),
+    /// Check the device's controller port lines and input displays, and report which ports look faulty.
+    Diag(DiagArgs),
+    /// Control the input displays: show bytes on them, rearrange their bits, or change their brightness.
+    Display(DisplayArgs),
+    /// Record the inputs of real controllers plugged through the device, to a TASD or an input overlay.
+    Spy(SpyArgs),
 }
 
 #[derive(Debug, Parser)]
@@ -60,12 +74,18 @@ pub struct ReplayArgs {
     #[arg(long, short)]
     pub movie: Option<Utf8PathBuf>,
     
+    /// Device to use, selected by its path, serial number, board ID, or alias. Can be given more than
+    /// once, to replay on several devices in lockstep.
     #[arg(long, short)]
-    pub device: Option<String>,
+    pub device: Vec<String>,
     
     #[arg(long)]
     pub list_devices: bool,
     
+    /// Save an alias in veritas.toml for the selected device.
+    #[arg(long, value_name = "ALIAS")]
+    pub name_device: Option<String>,
+    
     #[arg(long)]
     pub manual: Option<String>,
     
@@ -74,6 +94,191 @@ pub struct ReplayArgs {
     
     #[arg(long)]
     pub disable_reset: bool,
+    
+    /// Don't begin the replay until the console is powered on.
+    #[arg(long)]
+    pub wait_for_console: bool,
+    
+    /// Time in milliseconds the device waits to hear from this program, before it gives up and stops the
+    /// replay. 0 disables it.
+    #[arg(long, default_value_t = 5000, value_name = "MS")]
+    pub host_timeout: u32,
+    
+    /// Accessory plugged into each emulated N64 controller, starting at port 1. Port 1 defaults to a mempak
+    /// if its contents are provided by the movie or --mempak.
+    #[arg(long, value_enum, num_args = 1..=4)]
+    pub controller_pak: Vec<ControllerPak>,
+    
+    /// N64 ports with a controller plugged in. Defaults to every port with inputs in the movie.
+    #[arg(long, num_args = 1..=4, value_name = "PORT", value_parser = clap::value_parser!(u8).range(1..=4))]
+    pub n64_ports: Vec<u8>,
+    
+    /// Initial contents of the emulated N64 mempak, as a 32KB image.
+    #[arg(long, value_name = "FILE")]
+    pub mempak: Option<Utf8PathBuf>,
+    
+    /// Serve an input overlay for a browser source on this address (e.g. 127.0.0.1:8080), mirroring the
+    /// inputs the console is seeing.
+    #[arg(long, value_name = "ADDR")]
+    pub overlay: Option<SocketAddr>,
+    
+    #[command(flatten)]
+    pub timing: TimingArgs,
+}
+
+#[derive(Debug, Parser)]
+pub struct UploadArgs {
+    /// Movie to store on the device. If omitted, the movie already stored is left in place.
+    #[arg(long, short)]
+    pub movie: Option<Utf8PathBuf>,
+    
+    #[arg(long, short)]
+    pub device: Option<String>,
+    
+    #[arg(long)]
+    pub latch_filter: Option<u32>,
+    
+    #[arg(long)]
+    pub disable_reset: bool,
+    
+    /// Don't begin the replay until the console is powered on.
+    #[arg(long)]
+    pub wait_for_console: bool,
+    
+    /// Start the stored movie whenever the console is powered on, without a host attached.
+    #[arg(long)]
+    pub autostart: bool,
+    
+    /// Start replaying the stored movie once the upload has finished.
+    #[arg(long)]
+    pub start: bool,
+    
+    #[command(flatten)]
+    pub timing: TimingArgs,
+}
+
+#[derive(Debug, Parser)]
+pub struct FirmwareArgs {
+    #[command(subcommand)]
+    pub command: FirmwareCommand,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum FirmwareCommand {
+    /// Reboot the device into its bootloader, write a new firmware image, and verify the new version.
+    Flash(FlashArgs),
+    /// Print the version of the device's firmware.
+    Version {
+        #[arg(long, short)]
+        device: Option<String>,
+    },
+}
+
+#[derive(Debug, Parser)]
+pub struct FlashArgs {
+    /// Firmware image to write (.uf2 or .elf).
+    pub file: Utf8PathBuf,
+    
+    #[arg(long, short)]
+    pub device: Option<String>,
+    
+    /// Where the bootloader's drive (RPI-RP2) gets mounted. Searched for if omitted.
+    #[arg(long)]
+    pub mount: Option<Utf8PathBuf>,
+    
+    /// Seconds to wait for the bootloader's drive to appear, and for the device to return afterwards.
+    #[arg(long, default_value_t = 30)]
+    pub timeout: u64,
+}
+
+#[derive(Debug, Parser)]
+pub struct DiagArgs {
+    #[arg(long, short)]
+    pub device: Option<String>,
+    
+    /// Console the device is connected to. Run once with it powered off to test the outputs, and once with a
+    /// game running to measure its signals.
+    #[arg(long, short, value_enum)]
+    pub console: diag::Console,
+    
+    /// Milliseconds to measure the console's signals for.
+    #[arg(long, default_value_t = 3000, value_name = "MS", value_parser = clap::value_parser!(u32).range(1..=5000))]
+    pub duration: u32,
+    
+    /// Don't run the input displays through their test pattern.
+    #[arg(long)]
+    pub skip_displays: bool,
+}
+
+#[derive(Debug, Parser)]
+pub struct DisplayArgs {
+    #[arg(long, short)]
+    pub device: Option<String>,
+    
+    /// Bytes (in hex) to show on a display port, e.g. `1=FF00`. Can be given more than once.
+    #[arg(long, value_name = "PORT=HEX", value_parser = display::parse_show)]
+    pub show: Vec<(u8, Vec<u8>)>,
+    
+    /// Run every display through a test pattern.
+    #[arg(long)]
+    pub test_pattern: bool,
+    
+    /// Brightness of the displays in percent. 0 blanks them.
+    #[arg(long, value_parser = clap::value_parser!(u8).range(0..=100))]
+    pub brightness: Option<u8>,
+    
+    /// Console whose display layout is changed by --layout.
+    #[arg(long, value_enum, requires = "layout")]
+    pub console: Option<diag::Console>,
+    
+    /// Input bit shown by each display bit, as 16 comma-separated bit numbers counted from the MSB of the
+    /// first byte. Applies to replays of the --console, until the device restarts.
+    #[arg(long, value_delimiter = ',', requires = "console")]
+    pub layout: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Parser)]
+pub struct SpyArgs {
+    #[arg(long, short)]
+    pub device: Option<String>,
+    
+    /// Console the controllers are plugged into.
+    #[arg(long, short, value_enum)]
+    pub console: spy::Console,
+    
+    /// TASD file to save the recorded inputs to, once stopped.
+    #[arg(long, short)]
+    pub output: Option<Utf8PathBuf>,
+    
+    /// Serve an input overlay for a browser source on this address (e.g. 127.0.0.1:8080), showing the inputs
+    /// as they're played.
+    #[arg(long, value_name = "ADDR")]
+    pub overlay: Option<SocketAddr>,
+    
+    /// Microseconds after a NES/SNES latch during which further latches count as the same frame.
+    #[arg(long)]
+    pub latch_filter: Option<u32>,
+}
+
+/// Durations used by the device when performing transitions. Each one set here overrides the movie's.
+#[derive(Debug, Parser)]
+pub struct TimingArgs {
+    /// Microseconds the console's reset line is held during a soft reset transition. [default: the movie's, or 33333]
+    #[arg(long)]
+    pub reset_hold_us: Option<u32>,
+    
+    /// Microseconds to wait after a soft reset is released, before inputs resume. [default: the movie's, or 67]
+    #[arg(long)]
+    pub reset_settle_us: Option<u32>,
+    
+    /// Milliseconds the console's reset header is held during a hard reset, which is how power reset transitions
+    /// are performed. [default: the movie's, or 500]
+    #[arg(long)]
+    pub hard_reset_hold_ms: Option<u32>,
+    
+    /// Milliseconds the console is given to boot after a hard reset, before inputs resume. [default: the movie's, or 100]
+    #[arg(long)]
+    pub hard_reset_boot_ms: Option<u32>,
 }
 
 fn main() {
@@ -94,6 +299,11 @@ fn main() {
     match args.command {
         Command::Encode(args) => encode::handle(args),
         Command::Dump(args) => dumping::handle(args, config.dumper),
-        Command::Replay(args) => replay::handle(args),
+        Command::Replay(args) => replay::handle(args, config),
+        Command::Upload(args) => replay::upload::handle(args, &config.devices),
+        Command::Firmware(args) => firmware::handle(args, &config.devices),
+        Command::Diag(args) => diag::handle(args, &config.devices),
+        Command::Display(args) => display::handle(args, &config.devices),
+        Command::Spy(args) => spy::handle(args, &config.devices),
     }
 }
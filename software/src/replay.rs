@@ -1,4 +1,3 @@
-use std::cmp::{max, min};
 use std::io::stdout;
 use std::path::PathBuf;
 use std::sync::Arc;
@@ -6,44 +5,65 @@ use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::Duration;
 use crossterm::{event, terminal};
 use crossterm::event::{Event, KeyCode};
-use log::{debug, error, info, warn};
+use log::{error, info, warn};
 use serialport::{ClearBuffer, SerialPortType};
-use tasd::spec::{ConsoleType, InputChunk, KEY_CONSOLE_TYPE, KEY_INPUT_CHUNK, KEY_TRANSITION, TasdMovie, Transition};
-use crate::replay::comms::{Command, Device, Response, System, TransitionData, VeritasMode};
-use crate::replay::comms::Command::{GetStatus, ProvideInput, ProvideTransitions, SetLatchFilter, SetReplayLength, SetReplayMode};
-use crate::ReplayArgs;
+use tasd::spec::{ConsoleType, InputChunk, KEY_CONSOLE_TYPE, KEY_INPUT_CHUNK, KEY_MEMORY_INIT, MemoryInit, TasdMovie};
+use crate::replay::comms::{Command, ControllerPak, Device, Response, System, TransitionTiming, VeritasMode};
+use crate::replay::comms::Command::{SetLatchFilter, SetReplayMode, SetTransitionTiming};
+use crate::replay::overlay::Overlay;
+use crate::replay::worker::{MEMPAK_SIZE, PreparedMovie, Worker};
+use crate::{ReplayArgs, TimingArgs};
+use crate::config::{DevicesSection, VeritasConfig};
 
-mod comms;
+pub mod comms;
+mod events;
+pub mod overlay;
+mod transitions;
+pub mod upload;
+mod worker;
 
-pub fn handle(args: ReplayArgs) {
+/// Prefix of the USB serial number of every VeriTAS.
+pub const SERIAL_PREFIX: &str = "VeriTAS";
+
+pub fn handle(args: ReplayArgs, mut config: VeritasConfig) {
     if args.list_devices {
-        for port in serialport::available_ports().unwrap() {
-            info!("{:?}", port);
+        let devices = list_devices();
+        if devices.is_empty() {
+            info!("No devices found.");
+        }
+        
+        for dev in devices {
+            let alias = config.devices.alias_of(dev.board_id()).unwrap_or("-");
+            info!("{}  serial: {}  alias: {alias}", dev.path, dev.serial);
         }
         
         return;
     }
     
-    let device_path = args.device.unwrap_or_else(|| {
-        serialport::available_ports().unwrap()
-            .into_iter()
-            .filter_map(|info| if let SerialPortType::UsbPort(usbport) = info.port_type { Some((info.port_name, usbport)) } else { None })
-            .find(|(_, port)| port.serial_number == Some("VeriTAS".into()))
-            .map(|(name, _)| name)
-            .unwrap()
-    });
-    let mut dev = Device::new(device_path, 500000, Duration::from_secs(6)).unwrap();
-    dev.clear(ClearBuffer::All);
-    
-    if dev.send_command(Command::Ping) != Response::Pong {
-        panic!("Failed to ping device.");
+    if args.device.len() > 1 && (args.name_device.is_some() || args.manual.is_some()) {
+        error!("Only one device can be selected for this");
+        return;
     }
+    let selector = args.device.first().map(String::as_str);
     
-    if args.disable_reset && dev.send_command(Command::UseInitialReset(false)).is_not_ok() {
-        warn!("Failed to disable initial reset");
+    if let Some(alias) = args.name_device {
+        match find_device(selector, &config.devices) {
+            Ok(dev) => {
+                config.devices.aliases.insert(alias.clone(), dev.board_id().into());
+                config.save();
+                info!("Named {} ({}) as '{alias}'", dev.path, dev.board_id());
+            },
+            Err(err) => error!("{err}"),
+        }
+        
+        return;
     }
     
-    if let Some(manual) = args.manual {
+    if let Some(manual) = &args.manual {
+        let mut dev = connect(selector, &config.devices);
+        if !configure(&mut dev, &args) {
+            return;
+        }
         let _stdout = stdout();
         
         match manual.to_lowercase().as_str() {
@@ -157,34 +177,68 @@ pub fn handle(args: ReplayArgs) {
         return;
     }
     
-    let tasd = TasdMovie::new(&PathBuf::from(args.movie.unwrap())).expect("Failed to parse movie.");
+    let tasd = TasdMovie::new(&PathBuf::from(args.movie.as_ref().unwrap())).expect("Failed to parse movie.");
     let console = tasd.search_by_key(vec![KEY_CONSOLE_TYPE]).first().expect("No console type provided in TASD. Cannot continue.").as_any().downcast_ref::<ConsoleType>().unwrap();
-    let mut transitions: Vec<Transition> = tasd.search_by_key(vec![KEY_TRANSITION]).into_iter().map(|packet| packet.as_any().downcast_ref::<Transition>().unwrap().clone()).collect();
-    for trans in &mut transitions {
-        trans.index /= 2;
-        
-        info!("{trans}");
-    }
-    let inputs: Vec<u8> = {
-        let chunks: Vec<&[u8]> = tasd.search_by_key(vec![KEY_INPUT_CHUNK]).iter().map(|packet| packet.as_any().downcast_ref::<InputChunk>().unwrap().inputs.as_slice()).collect();
-        let mut inputs = vec![];
-        for chunk in chunks {
-            inputs.extend_from_slice(chunk);
+    let movie = match PreparedMovie::from_tasd(&tasd, console.kind.into()) {
+        Ok(mut movie) => {
+            if let Some(path) = &args.mempak {
+                match std::fs::read(path) {
+                    Ok(mempak) if mempak.len() == MEMPAK_SIZE => {
+                        movie.mempak = Some(mempak);
+                        movie.controller_paks[0] = ControllerPak::Mempak;
+                    },
+                    Ok(mempak) => {
+                        error!("{path} is {} bytes, but a mempak image must be {MEMPAK_SIZE} bytes", mempak.len());
+                        return;
+                    },
+                    Err(err) => {
+                        error!("Failed to read {path}: {err}");
+                        return;
+                    }
+                }
+            }
+            for (port, pak) in args.controller_pak.iter().enumerate() {
+                movie.controller_paks[port] = *pak;
+            }
+            if !args.n64_ports.is_empty() {
+                movie.n64_ports = args.n64_ports.iter().fold(0, |ports, port| ports | (1 << (port - 1)));
+            }
+            
+            Arc::new(movie)
+        },
+        Err(err) => {
+            error!("{err}");
+            return;
         }
-        
-        inputs
     };
     
-    let ports: [Vec<u8>; 2] = {
-        let chunks: Vec<InputChunk> = tasd.search_by_key(vec![KEY_INPUT_CHUNK]).into_iter().map(|packet| packet.as_any().downcast_ref::<InputChunk>().unwrap().clone()).collect();
-        let mut ports = std::array::from_fn(|_| vec![]);
-        
-        for i in (0..chunks.len()).step_by(1) {
-            let chunk = &chunks[i];
-            ports[chunk.port as usize - 1].extend_from_slice(&chunk.inputs);
+    // Every selected device replays the movie, in lockstep
+    let mut selected: Vec<DeviceInfo> = vec![];
+    let selectors: Vec<Option<&str>> = if args.device.is_empty() { vec![None] } else { args.device.iter().map(|sel| Some(sel.as_str())).collect() };
+    for selector in selectors {
+        match find_device(selector, &config.devices) {
+            Ok(info) if selected.iter().any(|other| other.path == info.path) => {
+                error!("{} was selected more than once", info.path);
+                return;
+            },
+            Ok(info) => selected.push(info),
+            Err(err) => {
+                error!("{err}");
+                return;
+            }
         }
-        
-        ports
+    }
+    
+    let overlay = match args.overlay.map(|addr| (addr, Overlay::start(addr, movie.clone()))) {
+        Some((addr, Ok(overlay))) => {
+            info!("Overlay available at http://{addr}/");
+            Some(overlay)
+        },
+        Some((addr, Err(err))) => {
+            error!("Failed to start the overlay on {addr}: {err}");
+            return;
+        },
+        None => None,
     };
     
     let exit_early = Arc::new(AtomicBool::new(false));
@@ -193,136 +247,225 @@ pub fn handle(args: ReplayArgs) {
         exit.store(true, Ordering::Relaxed);
     }).expect("Failed to set CTRL+C handler");
     
-    match console.kind.into() {
-        System::Nes => {
-            dev.send_command(SetLatchFilter(args.latch_filter.unwrap_or(8000)));
-            dev.send_command(SetReplayLength((inputs.len() / 2) as u64));
-            dev.send_command(ProvideTransitions(TransitionData::from_vec(transitions)));
-            
-            if let Response::DeviceStatus(text) = dev.send_command(GetStatus) {
-                info!("{text}");
-            } else {
-                warn!("Failed to receive device status");
-            }
-            
-            let mut ptr = 0usize;
-            let mut has_started = false;
-            let mut prev_empty = 2;
-            
-            info!("Prefilling buffer...");
-            while ptr < inputs.len() {
-                if exit_early.load(Ordering::Relaxed) {
-                    if dev.send_command(SetReplayMode(VeritasMode::Idle)).is_not_ok() {
-                        error!("Failed to set replay mode!");
-                    }
-                    info!("Exiting..");
-                    break;
-                }
-                
-                let remaining = inputs.len() - ptr;
-                let input = &inputs[ptr..(ptr + max(2, min(prev_empty, min(16, remaining))))];
-                
-                if let Response::BufferStatus { written, remaining_space } = dev.send_command(ProvideInput(System::Nes, input.to_vec())) {
-                    ptr += written as usize;
-                    prev_empty = remaining_space as usize;
-                    debug!("written: {written}, remaining_space: {remaining_space}");
-                    
-                    if remaining_space == 0 && !has_started {
-                        has_started = true;
-                        
-                        if dev.send_command(SetReplayMode(VeritasMode::ReplayNes)).is_not_ok() {
-                            error!("Failed to set replay mode!");
-                            return;
-                        }
-                        info!("Starting replay.")
-                    } else if remaining_space < 128 && has_started {
-                        std::thread::sleep(Duration::from_millis(2000));
-                    }
-                } else {
-                    error!("Failed to receive buffer status! desync likely!");
-                }
-            }
-            
-            if !has_started {
-                if dev.send_command(SetReplayMode(VeritasMode::ReplayNes)).is_not_ok() {
-                    error!("Failed to set replay mode!");
-                    return;
-                }
-                info!("Starting replay.")
-            }
-        },
-        System::N64 => {
-            todo!()
-        },
-        System::Genesis => {
-            let mut inputs = vec![];
-            /*for i in (0..ports[0].len()).step_by(2) {
-                inputs.extend_from_slice(&[ports[0][i], ports[0][i + 1], ports[1][i], ports[1][i + 1]]);
-            }*/
-            for i in 0..ports[0].len() {
-                inputs.extend_from_slice(&[ports[0][i], 0xFF, *ports[1].get(i).unwrap_or(&0xFF), 0xFF]);
-                //println!("{:02X?}", &inputs[(inputs.len() - 4)..]);
-            }
-            //return;
-            
-            dev.send_command(SetReplayLength((inputs.len() / 4) as u64));
-            //TODO dev.send_command(ProvideTransitions(TransitionData::from_vec(transitions)));
-            
-            if let Response::DeviceStatus(text) = dev.send_command(GetStatus) {
-                info!("{text}");
-            } else {
-                warn!("Failed to receive device status");
-            }
-            
-            let mut ptr = 0usize;
-            let mut has_started = false;
-            let mut prev_empty = 4;
-            
-            info!("Prefilling buffer...");
-            while ptr < inputs.len() {
-                if exit_early.load(Ordering::Relaxed) {
-                    if dev.send_command(SetReplayMode(VeritasMode::Idle)).is_not_ok() {
-                        error!("Failed to set replay mode!");
-                    }
-                    info!("Exiting..");
-                    break;
-                }
-                
-                let remaining = inputs.len() - ptr;
-                let input = inputs[ptr..(ptr + max(4, min(prev_empty, min(16, remaining))))].to_vec();
-                
-                if let Response::BufferStatus { written, remaining_space } = dev.send_command(ProvideInput(System::Genesis, input)) {
-                    ptr += written as usize;
-                    prev_empty = remaining_space as usize;
-                    debug!("written: {written}, remaining_space: {remaining_space}");
-                    
-                    if remaining_space == 0 && !has_started {
-                        has_started = true;
-                        
-                        if dev.send_command(SetReplayMode(VeritasMode::ReplayGenesis)).is_not_ok() {
-                            error!("Failed to set replay mode!");
-                            return;
-                        }
-                        info!("Starting replay.")
-                    } else if remaining_space < 128 && has_started {
-                        std::thread::sleep(Duration::from_millis(2000));
-                    }
-                } else {
-                    error!("Failed to receive buffer status! desync likely!");
-                }
-            }
+    let timing = transition_timing(&args.timing, movie.timing);
+    let mut workers = vec![];
+    for info in selected {
+        let label = config.devices.alias_of(info.board_id()).unwrap_or(&info.path).to_owned();
+        let mut worker = Worker::new(label.clone(), open(&info), movie.clone(), config.logs.events_path.clone());
+        
+        if !configure(worker.device(), &args) {
+            worker.save_event_log();
+            abandon(&mut workers);
+            return;
+        }
+        if worker.device().send_command(SetTransitionTiming(timing)).is_not_ok() {
+            warn!("[{label}] Failed to set transition timing");
+        }
+        
+        if !worker.prepare(args.latch_filter, args.host_timeout) {
+            worker.stop();
+            worker.save_event_log();
+            abandon(&mut workers);
+            return;
+        }
+        // Prefilling stops its own device if it's interrupted
+        if !worker.prefill(&exit_early) {
+            worker.save_event_log();
+            abandon(&mut workers);
+            return;
+        }
+        workers.push(worker);
+    }
+    
+    // The overlay follows the first device, since the rest are replaying in lockstep with it
+    if let Some(overlay) = overlay {
+        workers[0].set_overlay(overlay);
+    }
+    
+    worker::run(workers, exit_early);
+}
+
+/// Returns devices that were already prepared to idle, so they drop the inputs and transitions of a replay that
+/// won't be started, and saves their event logs.
+fn abandon(workers: &mut [Worker]) {
+    for worker in workers {
+        worker.stop();
+        worker.save_event_log();
+    }
+}
+
+/// Applies the options shared by every kind of replay.
+fn configure(dev: &mut Device, args: &ReplayArgs) -> bool {
+    if args.disable_reset && dev.send_command(Command::UseInitialReset(false)).is_not_ok() {
+        warn!("Failed to disable initial reset");
+    }
+    
+    if args.wait_for_console && dev.send_command(Command::WaitForConsole(true)).is_not_ok() {
+        error!("Failed to enable waiting for the console");
+        return false;
+    } else if args.wait_for_console {
+        info!("The replay will begin once the console is powered on.");
+    }
+    
+    true
+}
+
+/// A connected VeriTAS.
+#[derive(Debug, Clone)]
+pub struct DeviceInfo {
+    pub path: String,
+    /// Formatted as `VeriTAS-<board id>`. Older firmware only reports `VeriTAS`, or also appends its version.
+    pub serial: String,
+}
+impl DeviceInfo {
+    /// Unique ID of the board. Unlike the serial number, this doesn't change between firmware versions.
+    pub fn board_id(&self) -> &str {
+        self.serial.split('-').nth(1).unwrap_or(&self.serial)
+    }
+}
+
+/// Returns every connected VeriTAS.
+pub fn list_devices() -> Vec<DeviceInfo> {
+    serialport::available_ports().unwrap_or_default()
+        .into_iter()
+        .filter_map(|info| if let SerialPortType::UsbPort(usbport) = info.port_type { Some((info.port_name, usbport)) } else { None })
+        .filter_map(|(path, port)| port.serial_number.map(|serial| DeviceInfo { path, serial }))
+        .filter(|dev| dev.serial.starts_with(SERIAL_PREFIX))
+        .collect()
+}
+
+/// Finds a connected device by its path, serial number, board ID, or alias. If nothing is selected, there
+/// must only be one device connected.
+pub fn find_device(selector: Option<&str>, devices: &DevicesSection) -> Result<DeviceInfo, String> {
+    let mut connected = list_devices();
+    
+    match selector {
+        Some(selector) => {
+            let aliased = devices.aliases.get(selector).map(String::as_str);
             
-            if !has_started {
-                if dev.send_command(SetReplayMode(VeritasMode::ReplayGenesis)).is_not_ok() {
-                    error!("Failed to set replay mode!");
-                    return;
-                }
-                info!("Starting replay.")
-            }
-        },
-        System::A2600 => {
-            todo!()
+            connected.into_iter()
+                .find(|dev| dev.path == selector || dev.serial == selector || dev.board_id() == selector || Some(dev.board_id()) == aliased)
+                .ok_or_else(|| format!("No device found matching '{selector}'"))
         },
-        _ => unimplemented!()
+        None if connected.len() == 1 => Ok(connected.remove(0)),
+        None if connected.is_empty() => Err("No devices found".into()),
+        None => Err("Multiple devices connected, select one with --device (see --list-devices)".into()),
+    }
+}
+
+/// Opens the selected device (see [`find_device`]), and checks that it responds.
+pub fn connect(selector: Option<&str>, devices: &DevicesSection) -> Device {
+    let info = find_device(selector, devices).unwrap_or_else(|err| panic!("{err}"));
+    
+    open(&info)
+}
+
+/// Opens a device, and checks that it responds.
+pub fn open(info: &DeviceInfo) -> Device {
+    let mut dev = Device::new(&info.path, 500000, Duration::from_secs(6)).unwrap();
+    dev.clear(ClearBuffer::All);
+    
+    if dev.send_command(Command::Ping) != Response::Pong {
+        panic!("Failed to ping device.");
+    }
+    
+    dev
+}
+
+/// Timing for the movie's transitions. Times given on the command line take precedence over the movie's own.
+fn transition_timing(args: &TimingArgs, movie: Option<TransitionTiming>) -> TransitionTiming {
+    let movie = movie.unwrap_or(transitions::DEFAULT_TIMING);
+    
+    TransitionTiming {
+        reset_hold_us: args.reset_hold_us.unwrap_or(movie.reset_hold_us),
+        reset_settle_us: args.reset_settle_us.unwrap_or(movie.reset_settle_us),
+        hard_reset_hold_ms: args.hard_reset_hold_ms.unwrap_or(movie.hard_reset_hold_ms),
+        hard_reset_boot_ms: args.hard_reset_boot_ms.unwrap_or(movie.hard_reset_boot_ms),
+    }
+}
+
+/// Concatenated data of every INPUT_CHUNK packet, in the order they appear in the movie.
+fn chunk_inputs(tasd: &TasdMovie) -> Vec<u8> {
+    let chunks: Vec<&[u8]> = tasd.search_by_key(vec![KEY_INPUT_CHUNK]).iter().map(|packet| packet.as_any().downcast_ref::<InputChunk>().unwrap().inputs.as_slice()).collect();
+    let mut inputs = vec![];
+    for chunk in chunks {
+        inputs.extend_from_slice(chunk);
+    }
+    
+    inputs
+}
+
+/// Initial mempak contents from the movie's MEMORY_INIT packets. The TASD spec has no device kind for
+/// N64 controller paks yet, so this uses a Custom/Other device whose name mentions "pak".
+fn mempak_init(tasd: &TasdMovie) -> Option<Vec<u8>> {
+    let init = tasd.search_by_key(vec![KEY_MEMORY_INIT]).into_iter()
+        .map(|packet| packet.as_any().downcast_ref::<MemoryInit>().unwrap())
+        .find(|init| init.device_kind == 0xFFFF && init.name.to_lowercase().contains("pak"))?;
+    
+    match init.data_kind {
+        0x02 => Some(vec![0x00; MEMPAK_SIZE]),
+        0x03 => Some(vec![0xFF; MEMPAK_SIZE]),
+        0x04 => Some((0..MEMPAK_SIZE).map(|i| if (i / 4) % 2 == 0 { 0x00 } else { 0xFF }).collect()),
+        0xFF => init.data.as_ref().map(|data| {
+            let mut mempak = data.clone();
+            mempak.resize(MEMPAK_SIZE, 0x00);
+            mempak
+        }),
+        _ => None
     }
-}
\ No newline at end of file
+}
+
+/// Data of every INPUT_CHUNK packet, separated by port.
+fn port_inputs<const N: usize>(tasd: &TasdMovie) -> [Vec<u8>; N] {
+    let chunks: Vec<InputChunk> = tasd.search_by_key(vec![KEY_INPUT_CHUNK]).into_iter().map(|packet| packet.as_any().downcast_ref::<InputChunk>().unwrap().clone()).collect();
+    let mut ports = std::array::from_fn(|_| vec![]);
+    
+    for chunk in chunks {
+        match (chunk.port as usize).checked_sub(1).and_then(|port| ports.get_mut(port)) {
+            Some(port) => port.extend_from_slice(&chunk.inputs),
+            None => warn!("Skipping input chunk for port {}, only ports 1 to {N} are supported", chunk.port),
+        }
+    }
+    
+    ports
+}
+
+/// Interleaves both ports into the 4-byte frames the device uses for Genesis replays.
+fn genesis_inputs(ports: &[Vec<u8>; 2]) -> Vec<u8> {
+    let mut inputs = vec![];
+    /*for i in (0..ports[0].len()).step_by(2) {
+        inputs.extend_from_slice(&[ports[0][i], ports[0][i + 1], ports[1][i], ports[1][i + 1]]);
+    }*/
+    for i in 0..ports[0].len() {
+        inputs.extend_from_slice(&[ports[0][i], 0xFF, *ports[1].get(i).unwrap_or(&0xFF), 0xFF]);
+    }
+    
+    inputs
+}
+
+/// Interleaves all four ports into the 16-byte frames the device uses for N64 replays (4 bytes per controller).
+/// Runs for as long as the longest port, padding the others with released controllers.
+fn n64_inputs(ports: &[Vec<u8>; 4]) -> Vec<u8> {
+    let len = ports.iter().map(Vec::len).max().unwrap_or(0);
+    let mut inputs = vec![];
+    for i in (0..len).step_by(4) {
+        for port in ports {
+            inputs.extend((i..(i + 4)).map(|j| *port.get(j).unwrap_or(&0x00)));
+        }
+    }
+    
+    inputs
+}
+
+/// Interleaves both ports into the 4-byte frames the device uses for SNES replays (2 bytes per controller).
+fn snes_inputs(ports: &[Vec<u8>; 2]) -> Vec<u8> {
+    let mut inputs = vec![];
+    for i in (0..ports[0].len()).step_by(2) {
+        for port in ports {
+            inputs.push(*port.get(i).unwrap_or(&0xFF));
+            inputs.push(*port.get(i + 1).unwrap_or(&0xFF));
+        }
+    }
+    
+    inputs
+}
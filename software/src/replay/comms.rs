@@ -2,9 +2,10 @@ use std::io::{Read, Write};
 use std::time::Duration;
 use bincode::{Decode, Encode};
 use bincode::config::Configuration;
+use clap::ValueEnum;
 use num_enum::{FromPrimitive, IntoPrimitive};
 use serialport::{ClearBuffer, SerialPort};
-use tasd::spec::Transition;
+pub use veritas_core::transition::{TransitionData, TransitionTiming};
 
 const BINCODE_CONFIG: Configuration = bincode::config::standard();
 
@@ -16,8 +17,53 @@ pub enum Command {
     SetReplayLength(u64),
     SetLatchFilter(u32),
     UseInitialReset(bool),
+    SetTransitionTiming(TransitionTiming),
+    WaitForConsole(bool),
+    SetHostTimeout(u32),
+    SetControllerPak {
+        port: u8,
+        pak: ControllerPak,
+    },
+    SetN64Ports(u8),
+    WriteMempak {
+        offset: u16,
+        data: Vec<u8>,
+    },
+    EraseStoredMovie,
+    WriteStoredMovie {
+        offset: u32,
+        data: Vec<u8>,
+    },
+    StartStoredMovie,
+    RebootToBootloader,
+    GetVersion,
     GetStatus,
     Ping,
+    ReadPins,
+    TestOutputs,
+    /// Counts edges on the controller port lines for the given number of milliseconds, before responding.
+    MeasureEdges(u32),
+    TestDisplays,
+    /// Shows bytes on one of the displays, until the replay or another command replaces them.
+    SetDisplay {
+        port: u8,
+        data: Vec<u8>,
+    },
+    /// Changes which input bit each display bit shows, for one console. Lasts until the device restarts.
+    SetDisplayLayout {
+        system: System,
+        layout: [u8; 16],
+    },
+    /// Display brightness in percent, where 0 blanks them.
+    SetDisplayBrightness(u8),
+    GetProgress,
+    /// Takes the oldest frames read from the controllers while spying.
+    GetSpyInputs,
+    /// Reads the event log, starting from the given record number. Passing `u32::MAX` returns no records, just
+    /// the number the next event will get.
+    GetEventLog(u32),
+    /// Lines of each of a console's controller ports.
+    GetPinMap(System),
 }
 
 #[derive(Debug, PartialEq, Eq, Clone, Encode, Decode)]
@@ -30,6 +76,38 @@ pub enum Response {
     },
     Pong,
     Err,
+    Version(String),
+    /// A message logged by the firmware, sent ahead of the response to a command.
+    Log {
+        level: LogLevel,
+        message: String,
+    },
+    /// Levels of the controller port lines (bit n is `PIN_CNT_n`), and whether the console is powered.
+    Pins {
+        levels: u32,
+        console: bool,
+    },
+    /// Controller port lines that didn't read back as driven, with the same layout as `Pins`.
+    OutputFaults(u32),
+    /// Edges counted on each controller port line, indexed by `PIN_CNT_n`.
+    Edges(Vec<u32>),
+    /// Current mode, and how many of the movie's inputs the console has consumed.
+    Progress {
+        mode: VeritasMode,
+        index_cur: u32,
+        index_len: u32,
+    },
+    /// Frames read from the controllers while spying, oldest first. Each is 4 bytes: 2 for each port, with a
+    /// NES or Genesis controller's input in the first, and 0xFF in the second.
+    SpyInputs(Vec<u8>),
+    /// Records from the event log, oldest first, and the number of the first. If it's after the number asked
+    /// for, the records in between were overwritten.
+    EventLog {
+        first: u32,
+        events: Vec<EventRecord>,
+    },
+    /// Lines of each controller port, by port.
+    PinMap(Vec<Vec<PortLine>>),
 }
 impl Response {
     pub fn is_not_ok(&self) -> bool {
@@ -37,27 +115,6 @@ impl Response {
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Clone, Encode, Decode)]
-pub struct TransitionData {
-    index: u64,
-    index_kind: u8,
-    transition_kind: u8,
-}
-impl TransitionData {
-    pub fn from_vec(other: Vec<Transition>) -> Vec<Self> {
-        other.into_iter().map(TransitionData::from).collect()
-    }
-}
-impl From<Transition> for TransitionData {
-    fn from(value: Transition) -> Self {
-        Self {
-            index: value.index,
-            index_kind: value.index_kind,
-            transition_kind: value.transition_kind
-        }
-    }
-}
-
 #[derive(Debug, PartialEq, Eq, Copy, Clone, Encode, Decode, FromPrimitive, IntoPrimitive)]
 #[repr(u8)]
 pub enum System {
@@ -70,6 +127,32 @@ pub enum System {
     Unknown = 0xFF,
 }
 
+/// Accessory plugged into the emulated N64 controller.
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Encode, Decode, ValueEnum)]
+pub enum ControllerPak {
+    None,
+    Mempak,
+    Rumble,
+}
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Encode, Decode)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+impl From<LogLevel> for log::Level {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Error => log::Level::Error,
+            LogLevel::Warn => log::Level::Warn,
+            LogLevel::Info => log::Level::Info,
+            LogLevel::Debug => log::Level::Debug,
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Copy, Clone, Encode, Decode)]
 #[repr(u8)]
 pub enum VeritasMode {
@@ -79,6 +162,82 @@ pub enum VeritasMode {
     ReplayNes = 0x03,
     ReplayA2600 = 0x04,
     ReplayGenesis = 0x05,
+    ReplaySnes = 0x06,
+    SpyNes = 0x07,
+    SpySnes = 0x08,
+    SpyGenesis = 0x09,
+}
+
+/// Why the failsafe stopped a replay.
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Encode, Decode)]
+pub enum FailsafeReason {
+    HostTimeout,
+    UsbSuspended,
+}
+
+/// Something the firmware logged during a replay.
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Encode, Decode)]
+pub enum Event {
+    ModeChanged(VeritasMode),
+    /// A transition (by its TASD kind) started, once `index` inputs had been consumed.
+    Transition {
+        kind: u8,
+        index: u32,
+    },
+    /// The console needed an input that hadn't arrived yet, once `index` inputs had been consumed.
+    Underrun {
+        index: u32,
+    },
+    InitialReset,
+    /// The console was detected turning on (true) or off.
+    ConsoleDetected(bool),
+    BadCommand,
+    ResponseTooLarge,
+    Failsafe(FailsafeReason),
+}
+
+/// An event, and when it happened in microseconds since the device booted.
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Encode, Decode)]
+pub struct EventRecord {
+    pub timestamp_us: u64,
+    pub event: Event,
+}
+
+/// What a controller port line carries.
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Encode, Decode)]
+pub enum LineKind {
+    Data,
+    Clock,
+    Latch,
+    Detect,
+    Select,
+    Up,
+    Down,
+    Left,
+    Right,
+    BA,
+    CStart,
+}
+
+/// How a controller port line is driven, which decides how it can be checked.
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Encode, Decode)]
+pub enum LineRole {
+    /// Driven by the device, and checked by the output test.
+    Output,
+    /// Driven by the console (latch, clock or select), and checked for edges while a game is running.
+    Input,
+    /// Driven by both, like the N64's data line, and checked by both tests.
+    Bidirectional,
+    /// Held high by the console while it's powered.
+    Sense,
+}
+
+/// A controller port line, numbered as the firmware's `PIN_CNT_n`.
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Encode, Decode)]
+pub struct PortLine {
+    pub kind: LineKind,
+    pub pin: u8,
+    pub role: LineRole,
 }
 
 pub struct Device {
@@ -113,12 +272,19 @@ impl Device {
         self.recv_response()
     }
     
+    /// Waits for the response to a command. Log messages the firmware sends ahead of it are passed on to the
+    /// logger, under the `firmware` target.
     fn recv_response(&mut self) -> Response {
-        let len = u32::from_be_bytes(self.read(4).try_into().unwrap());
-        let payload = self.read(len as usize);
-        let (response, _) = bincode::decode_from_slice(&payload, BINCODE_CONFIG).expect("failed to decode response, this should never happen");
-        
-        response
+        loop {
+            let len = u32::from_be_bytes(self.read(4).try_into().unwrap());
+            let payload = self.read(len as usize);
+            let (response, _) = bincode::decode_from_slice(&payload, BINCODE_CONFIG).expect("failed to decode response, this should never happen");
+            
+            match response {
+                Response::Log { level, message } => log::log!(target: "firmware", level.into(), "{message}"),
+                response => return response,
+            }
+        }
     }
     
     pub fn clear(&self, buffer: ClearBuffer) {
@@ -0,0 +1,54 @@
+use std::fmt::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+use camino::{Utf8Path, Utf8PathBuf};
+use crate::replay::comms::{Command, Device, EventRecord, Response};
+
+/// Reads the device's event log, from record number `start` to the newest. Returns the records, and how many
+/// of them were overwritten before they could be read.
+pub fn fetch(dev: &mut Device, start: u32) -> Result<(Vec<EventRecord>, u32), Response> {
+    let mut records = vec![];
+    let mut next = start;
+    let mut overwritten = 0;
+    
+    loop {
+        let (first, events) = match dev.send_command(Command::GetEventLog(next)) {
+            Response::EventLog { first, events } => (first, events),
+            resp => return Err(resp),
+        };
+        if events.is_empty() {
+            break;
+        }
+        
+        overwritten += first.wrapping_sub(next);
+        next = first.wrapping_add(events.len() as u32);
+        records.extend(events);
+    }
+    
+    Ok((records, overwritten))
+}
+
+/// Writes the records to a new file in `dir`, one per line, with their time in seconds since the device booted.
+/// Returns the path of the file.
+pub fn save(dir: &Utf8Path, label: &str, records: &[EventRecord], overwritten: u32) -> std::io::Result<Utf8PathBuf> {
+    let mut text = String::new();
+    if overwritten > 0 {
+        writeln!(text, "({overwritten} events were overwritten before they could be read)").unwrap();
+    }
+    for record in records {
+        let secs = record.timestamp_us / 1_000_000;
+        let micros = record.timestamp_us % 1_000_000;
+        writeln!(text, "[{secs:>6}.{micros:06}] {:?}", record.event).unwrap();
+    }
+    
+    // Labels can be device paths, which aren't usable in a file name
+    let name: String = label.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+        .collect();
+    let time = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or_default();
+    
+    let path = dir.join(format!("events-{name}-{time}.log"));
+    std::fs::create_dir_all(dir)?;
+    std::fs::write(&path, text)?;
+    
+    Ok(path)
+}
\ No newline at end of file
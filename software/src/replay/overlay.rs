@@ -0,0 +1,434 @@
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Sender};
+use std::time::Duration;
+use base64ct::{Base64, Encoding};
+use log::debug;
+use sha1::{Digest, Sha1};
+use crate::replay::comms::System;
+use crate::replay::worker::PreparedMovie;
+
+/// Appended to a client's key to accept a WebSocket connection (RFC 6455).
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+/// Longest request head accepted from a client.
+const MAX_REQUEST_SIZE: usize = 8192;
+/// Time given to a client to accept a message, before it's dropped so it can't hold up the others.
+const WRITE_TIMEOUT: Duration = Duration::from_millis(250);
+
+const OVERLAY_JS: &str = include_str!("../includes/overlay/overlay.js");
+const SKIN_NES: &str = include_str!("../includes/overlay/nes.html");
+const SKIN_SNES: &str = include_str!("../includes/overlay/snes.html");
+const SKIN_N64: &str = include_str!("../includes/overlay/n64.html");
+const SKIN_GENESIS: &str = include_str!("../includes/overlay/genesis.html");
+
+/// Latest state, and the WebSocket clients it's pushed to.
+struct Shared {
+    state: String,
+    clients: Vec<TcpStream>,
+}
+
+/// A local web server for a browser-source overlay, which mirrors the inputs the console is seeing.
+///
+/// Skins for each console are served at `/nes`, `/snes`, `/n64` and `/genesis` (and the console's own at `/`),
+/// and connect back to `/ws` for updates. The current state can also be fetched from `/state`. Everything is
+/// served from the binary, so it works without internet access.
+pub struct Overlay {
+    system: System,
+    /// Movie being replayed, or none while inputs are recorded from real controllers.
+    movie: Option<Arc<PreparedMovie>>,
+    updates: Sender<String>,
+    index_cur: Option<u32>,
+}
+impl Overlay {
+    /// Starts serving on the given address, in the background, for a replay of the movie.
+    pub fn start(addr: SocketAddr, movie: Arc<PreparedMovie>) -> std::io::Result<Self> {
+        let updates = serve_in_background(TcpListener::bind(addr)?, movie.system, movie_state(&movie, 0));
+        
+        Ok(Self {
+            system: movie.system,
+            movie: Some(movie),
+            updates,
+            index_cur: None,
+        })
+    }
+    
+    /// Starts serving on the given address, in the background, for inputs that are passed in as they're read.
+    pub fn start_live(addr: SocketAddr, system: System) -> std::io::Result<Self> {
+        let updates = serve_in_background(TcpListener::bind(addr)?, system, state_json(system, 0, 0, None));
+        
+        Ok(Self {
+            system,
+            movie: None,
+            updates,
+            index_cur: None,
+        })
+    }
+    
+    /// Publishes the inputs the console is seeing, once it has consumed `index_cur` inputs. Doesn't block, so
+    /// it's safe to call while streaming.
+    pub fn publish(&mut self, index_cur: u32) {
+        let Some(movie) = &self.movie else {
+            return;
+        };
+        if self.index_cur == Some(index_cur) {
+            return;
+        }
+        self.index_cur = Some(index_cur);
+        
+        self.updates.send(movie_state(movie, index_cur)).ok();
+    }
+    
+    /// Publishes an input read from the controllers, as the `count`th input so far. `input` is a whole frame,
+    /// laid out as in a movie for the console.
+    pub fn publish_input(&mut self, count: u32, input: &[u8]) {
+        self.updates.send(state_json(self.system, count, count as u64, Some(input))).ok();
+    }
+}
+
+/// Serves the listener's clients from background threads. Returns the sender that new states are published
+/// through.
+fn serve_in_background(listener: TcpListener, console: System, state: String) -> Sender<String> {
+    let shared = Arc::new(Mutex::new(Shared { state, clients: vec![] }));
+    
+    let accepted = shared.clone();
+    std::thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let shared = accepted.clone();
+            std::thread::spawn(move || {
+                if let Err(err) = serve(stream, &shared, console) {
+                    debug!("Overlay connection closed: {err}");
+                }
+            });
+        }
+    });
+    
+    let (updates, received) = channel::<String>();
+    std::thread::spawn(move || {
+        for state in received {
+            let mut shared = shared.lock().unwrap();
+            let frame = text_frame(&state);
+            shared.clients.retain_mut(|client| client.write_all(&frame).is_ok());
+            shared.state = state;
+        }
+    });
+    
+    updates
+}
+
+/// Number of controller ports in a movie's frames.
+fn port_count(system: System) -> usize {
+    match system {
+        System::N64 => 4,
+        _ => 2,
+    }
+}
+
+fn console_name(system: System) -> &'static str {
+    match system {
+        System::Nes => "nes",
+        System::Snes => "snes",
+        System::N64 => "n64",
+        System::Genesis => "genesis",
+        System::A2600 => "a2600",
+        System::Unknown => "unknown",
+    }
+}
+
+/// The state of a replay, once the console has consumed `index_cur` of the movie's inputs.
+fn movie_state(movie: &PreparedMovie, index_cur: u32) -> String {
+    let frame = (index_cur as usize).checked_sub(1)
+        .and_then(|index| movie.inputs.get((index * movie.frame_size)..((index + 1) * movie.frame_size)));
+    
+    state_json(movie.system, index_cur, movie.length(), frame)
+}
+
+/// The state sent to clients: the console, how many inputs have been consumed, how many there are, and the
+/// bytes of the latest consumed input for each port (empty before the first), as they appear in the TASD.
+fn state_json(system: System, index_cur: u32, length: u64, frame: Option<&[u8]>) -> String {
+    let ports = port_count(system);
+    
+    let ports: Vec<String> = (0..ports)
+        .map(|port| match frame {
+            Some(frame) => {
+                let port_size = frame.len() / ports;
+                let bytes: Vec<String> = frame[(port * port_size)..((port + 1) * port_size)].iter().map(u8::to_string).collect();
+                format!("[{}]", bytes.join(","))
+            },
+            None => "[]".into(),
+        })
+        .collect();
+    
+    format!(r#"{{"console":"{}","frame":{},"length":{},"ports":[{}]}}"#, console_name(system), index_cur, length, ports.join(","))
+}
+
+/// Handles a single HTTP request, which may upgrade to a WebSocket.
+fn serve(stream: TcpStream, shared: &Mutex<Shared>, console: System) -> std::io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut stream = stream;
+    
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut headers = HashMap::new();
+    let mut size = request_line.len();
+    loop {
+        let mut line = String::new();
+        size += reader.read_line(&mut line)?;
+        if line.trim().is_empty() || size > MAX_REQUEST_SIZE {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.insert(name.trim().to_ascii_lowercase(), value.trim().to_owned());
+        }
+    }
+    
+    let path = match request_line.split_whitespace().collect::<Vec<_>>()[..] {
+        ["GET", path, _] => path.split('?').next().unwrap_or(path),
+        _ => return respond(&mut stream, "405 Method Not Allowed", "text/plain", "Method not allowed"),
+    };
+    
+    match path {
+        "/" => respond(&mut stream, "200 OK", "text/html", skin(console)),
+        "/nes" => respond(&mut stream, "200 OK", "text/html", SKIN_NES),
+        "/snes" => respond(&mut stream, "200 OK", "text/html", SKIN_SNES),
+        "/n64" => respond(&mut stream, "200 OK", "text/html", SKIN_N64),
+        "/genesis" => respond(&mut stream, "200 OK", "text/html", SKIN_GENESIS),
+        "/overlay.js" => respond(&mut stream, "200 OK", "text/javascript", OVERLAY_JS),
+        "/state" => {
+            let state = shared.lock().unwrap().state.clone();
+            respond(&mut stream, "200 OK", "application/json", &state)
+        },
+        "/ws" => match headers.get("sec-websocket-key") {
+            Some(key) => {
+                let accept = accept_key(key);
+                write!(stream, "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {accept}\r\n\r\n")?;
+                stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+                
+                {
+                    let mut shared = shared.lock().unwrap();
+                    stream.write_all(&text_frame(&shared.state))?;
+                    shared.clients.push(stream.try_clone()?);
+                }
+                
+                // Nothing the client sends is needed, but reading it notices when the client goes away
+                let result = discard_frames(&mut reader);
+                stream.shutdown(Shutdown::Both).ok();
+                result
+            },
+            None => respond(&mut stream, "400 Bad Request", "text/plain", "Expected a WebSocket upgrade"),
+        },
+        _ => respond(&mut stream, "404 Not Found", "text/plain", "Not found"),
+    }
+}
+
+fn skin(console: System) -> &'static str {
+    match console {
+        System::Snes => SKIN_SNES,
+        System::N64 => SKIN_N64,
+        System::Genesis => SKIN_GENESIS,
+        _ => SKIN_NES,
+    }
+}
+
+fn respond(stream: &mut TcpStream, status: &str, content_type: &str, body: &str) -> std::io::Result<()> {
+    write!(stream, "HTTP/1.1 {status}\r\nContent-Type: {content_type}; charset=utf-8\r\nContent-Length: {}\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n{body}", body.len())
+}
+
+/// The Sec-WebSocket-Accept value that answers a client's Sec-WebSocket-Key.
+fn accept_key(key: &str) -> String {
+    Base64::encode_string(&Sha1::digest(format!("{key}{WEBSOCKET_GUID}").as_bytes()))
+}
+
+/// An unfragmented WebSocket text frame. Frames from a server are never masked.
+fn text_frame(text: &str) -> Vec<u8> {
+    let payload = text.as_bytes();
+    let mut frame = vec![0x81];
+    match payload.len() {
+        len @ 0..=125 => frame.push(len as u8),
+        len @ 126..=0xFFFF => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        },
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        },
+    }
+    frame.extend_from_slice(payload);
+    
+    frame
+}
+
+/// Reads frames from a client until it closes the connection.
+fn discard_frames<R: Read>(reader: &mut R) -> std::io::Result<()> {
+    loop {
+        let mut head = [0u8; 2];
+        reader.read_exact(&mut head)?;
+        if head[0] & 0x0F == 0x8 {
+            return Ok(());
+        }
+        
+        let len = match head[1] & 0x7F {
+            126 => {
+                let mut len = [0u8; 2];
+                reader.read_exact(&mut len)?;
+                u16::from_be_bytes(len) as u64
+            },
+            127 => {
+                let mut len = [0u8; 8];
+                reader.read_exact(&mut len)?;
+                u64::from_be_bytes(len)
+            },
+            len => len as u64,
+        };
+        let mask_len = if head[1] & 0x80 != 0 { 4 } else { 0 };
+        
+        let skipped = std::io::copy(&mut reader.by_ref().take(len + mask_len), &mut std::io::sink())?;
+        if skipped < len + mask_len {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use super::*;
+    
+    /// A frame as a client sends it: masked, with the given opcode and payload length.
+    fn client_frame(opcode: u8, len: usize) -> Vec<u8> {
+        let mut frame = vec![0x80 | opcode];
+        match len {
+            0..=125 => frame.push(0x80 | len as u8),
+            126..=0xFFFF => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            },
+            _ => {
+                frame.push(0x80 | 127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            },
+        }
+        frame.extend_from_slice(&[0x12, 0x34, 0x56, 0x78]);
+        frame.resize(frame.len() + len, 0xAA);
+        
+        frame
+    }
+    
+    /// Connects to the server and sends a GET request for `path`, with any extra header lines.
+    fn request(addr: SocketAddr, path: &str, headers: &str) -> TcpStream {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        write!(stream, "GET {path} HTTP/1.1\r\nHost: localhost\r\n{headers}\r\n").unwrap();
+        
+        stream
+    }
+    
+    /// Reads a response head, up to and including the blank line that ends it.
+    fn read_head<R: BufRead>(reader: &mut R) -> String {
+        let mut head = String::new();
+        while !head.ends_with("\r\n\r\n") {
+            assert_ne!(reader.read_line(&mut head).unwrap(), 0, "connection closed mid-head: {head}");
+        }
+        
+        head
+    }
+    
+    fn read_frame<R: Read>(reader: &mut R, len: usize) -> Vec<u8> {
+        let mut frame = vec![0; len];
+        reader.read_exact(&mut frame).unwrap();
+        
+        frame
+    }
+    
+    #[test]
+    fn accept_key_matches_the_rfc_example() {
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+    
+    #[test]
+    fn text_frames_use_the_shortest_length() {
+        let frame = text_frame(&"a".repeat(125));
+        assert_eq!(frame[..2], [0x81, 125]);
+        assert_eq!(frame.len(), 2 + 125);
+        
+        let frame = text_frame(&"a".repeat(126));
+        assert_eq!(frame[..4], [0x81, 126, 0x00, 126]);
+        assert_eq!(frame.len(), 4 + 126);
+        
+        let frame = text_frame(&"a".repeat(0xFFFF));
+        assert_eq!(frame[..4], [0x81, 126, 0xFF, 0xFF]);
+        
+        let frame = text_frame(&"a".repeat(65536));
+        assert_eq!(frame[..10], [0x81, 127, 0, 0, 0, 0, 0, 1, 0, 0]);
+        assert_eq!(frame.len(), 10 + 65536);
+        assert!(frame[10..].iter().all(|byte| *byte == b'a'));
+    }
+    
+    #[test]
+    fn client_frames_are_skipped_until_close() {
+        let mut data = vec![];
+        for len in [0, 125, 126, 65536] {
+            data.extend(client_frame(0x1, len));
+        }
+        data.extend(client_frame(0x8, 2));
+        data.extend(client_frame(0x1, 10));
+        
+        let mut reader = Cursor::new(&data);
+        discard_frames(&mut reader).unwrap();
+        
+        // Stops right after the close frame's head, leaving whatever follows it unread
+        let close = data.len() - client_frame(0x1, 10).len() - client_frame(0x8, 2).len();
+        assert_eq!(reader.position() as usize, close + 2);
+    }
+    
+    #[test]
+    fn truncated_client_frames_end_the_connection() {
+        let mut data = client_frame(0x1, 65536);
+        data.truncate(1000);
+        
+        assert!(discard_frames(&mut Cursor::new(&data)).is_ok());
+        assert!(discard_frames(&mut Cursor::new(&data[..1])).is_err());
+    }
+    
+    #[test]
+    fn state_and_updates_are_served_over_loopback() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let updates = serve_in_background(listener, System::Nes, state_json(System::Nes, 0, 10, None));
+        
+        let mut state = String::new();
+        request(addr, "/state", "").read_to_string(&mut state).unwrap();
+        let (head, body) = state.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(head.contains("Content-Type: application/json"));
+        assert_eq!(body, r#"{"console":"nes","frame":0,"length":10,"ports":[[],[]]}"#);
+        
+        let stream = request(addr, "/ws", "Upgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n");
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let head = read_head(&mut reader);
+        assert!(head.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(head.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+        
+        // The current state is sent on connecting, then every update
+        let initial = text_frame(r#"{"console":"nes","frame":0,"length":10,"ports":[[],[]]}"#);
+        assert_eq!(read_frame(&mut reader, initial.len()), initial);
+        
+        let update = state_json(System::Nes, 3, 10, Some(&[0x80, 0x01]));
+        updates.send(update.clone()).unwrap();
+        let expected = text_frame(&update);
+        assert_eq!(read_frame(&mut reader, expected.len()), expected);
+        assert_eq!(update, r#"{"console":"nes","frame":3,"length":10,"ports":[[128],[1]]}"#);
+        
+        // Closing from the client makes the server hang up
+        (&stream).write_all(&client_frame(0x8, 0)).unwrap();
+        let mut rest = vec![];
+        reader.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+        
+        let mut response = String::new();
+        request(addr, "/ws", "").read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    }
+}
\ No newline at end of file
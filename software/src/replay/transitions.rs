@@ -0,0 +1,324 @@
+use std::collections::BTreeSet;
+use log::{info, warn};
+use tasd::spec::{InputChunk, KEY_INPUT_CHUNK, KEY_LAG_FRAME_CHUNK, KEY_PORT_CONTROLLER, KEY_TRANSITION, KEY_UNSPECIFIED, LagFrameChunk, PortController, TasdMovie, Transition, Unspecified};
+use crate::replay::comms::{System, TransitionData, TransitionTiming};
+
+/// TASD index kind: frame number, which includes lag frames.
+pub const INDEX_FRAME: u8 = 0x01;
+/// TASD index kind: byte offset into the concatenated data of every INPUT_CHUNK packet.
+pub const INDEX_INPUT_CHUNK: u8 = 0x05;
+
+/// Index kind sent to the device. The index is the number of inputs the device will have consumed
+/// (i.e. its latch/frame counter) when the transition is performed.
+pub const DEVICE_INDEX_KIND: u8 = INDEX_FRAME;
+
+const TRANSITION_SOFT_RESET: u8 = 0x01;
+const TRANSITION_POWER_RESET: u8 = 0x02;
+
+/// Timing used when neither the command line nor the movie sets it.
+pub const DEFAULT_TIMING: TransitionTiming = TransitionTiming::new();
+/// Start of an UNSPECIFIED packet holding the movie's transition timing, as TASD has no packet for it. It's
+/// followed by the reset hold and settle times in microseconds, then optionally the hard reset hold and boot
+/// times in milliseconds, each a big-endian u32.
+const TIMING_TAG: &[u8] = b"VERITAS_TRANSITION_TIMING";
+
+/// Returns the transition timing stored in the movie, if it has any.
+pub fn movie_timing(tasd: &TasdMovie) -> Option<TransitionTiming> {
+    tasd.search_by_key(vec![KEY_UNSPECIFIED]).into_iter()
+        .filter_map(|packet| packet.as_any().downcast_ref::<Unspecified>())
+        .find_map(|packet| {
+            let times = packet.payload.strip_prefix(TIMING_TAG)?;
+            if times.len() != 8 && times.len() != 16 {
+                warn!("Ignoring transition timing with a {} byte payload, expected 8 or 16", times.len());
+                return None;
+            }
+            
+            let time = |i: usize| u32::from_be_bytes(times[(i * 4)..(i * 4 + 4)].try_into().unwrap());
+            let mut timing = TransitionTiming {
+                reset_hold_us: time(0),
+                reset_settle_us: time(1),
+                ..DEFAULT_TIMING
+            };
+            if times.len() == 16 {
+                timing.hard_reset_hold_ms = time(2);
+                timing.hard_reset_boot_ms = time(3);
+            }
+            
+            Some(timing)
+        })
+}
+
+/// Returns every transition in the movie that the device is able to perform.
+pub fn collect(tasd: &TasdMovie) -> Vec<Transition> {
+    tasd.search_by_key(vec![KEY_TRANSITION]).into_iter()
+        .map(|packet| packet.as_any().downcast_ref::<Transition>().unwrap().clone())
+        .filter(|trans| match trans.transition_kind {
+            TRANSITION_SOFT_RESET => true,
+            TRANSITION_POWER_RESET => {
+                info!("Power reset will be performed as a hard reset, through the console's reset header: {trans}");
+                true
+            },
+            _ => {
+                warn!("Skipping unsupported transition: {trans}");
+                false
+            }
+        })
+        .collect()
+}
+
+/// Number of input bytes a single frame uses on a controller, or `None` if the controller is unknown.
+fn controller_width(kind: u16) -> Option<usize> {
+    match kind {
+        0x0101 => Some(1),
+        0x0102 => Some(2),
+        0x0201 => Some(2),
+        0x0202 => Some(8),
+        0x0301..=0x0304 => Some(4),
+        0x0801 => Some(1),
+        0x0802 => Some(2),
+        0x0901 => Some(1),
+        _ => None,
+    }
+}
+
+/// Number of input bytes a single frame uses on the default controller of a system.
+fn default_width(system: System) -> usize {
+    match system {
+        System::Snes => 2,
+        System::N64 => 4,
+        _ => 1,
+    }
+}
+
+/// Number of INPUT_CHUNK bytes that make up one frame of input, across every port used by the movie.
+pub fn frame_width(tasd: &TasdMovie, system: System) -> usize {
+    let ports: BTreeSet<u8> = tasd.search_by_key(vec![KEY_INPUT_CHUNK]).into_iter()
+        .map(|packet| packet.as_any().downcast_ref::<InputChunk>().unwrap().port)
+        .collect();
+    let controllers: Vec<&PortController> = tasd.search_by_key(vec![KEY_PORT_CONTROLLER]).into_iter()
+        .map(|packet| packet.as_any().downcast_ref::<PortController>().unwrap())
+        .collect();
+    
+    ports.into_iter()
+        .map(|port| controllers.iter()
+            .find(|controller| controller.port == port)
+            .and_then(|controller| controller_width(controller.kind))
+            .unwrap_or(default_width(system)))
+        .sum::<usize>()
+        .max(1)
+}
+
+/// Converts a TASD frame number (which counts lag frames) into the number of polled frames before it.
+fn frame_to_latch(frame: u64, lag_chunks: &[LagFrameChunk]) -> u64 {
+    let lag: u64 = lag_chunks.iter()
+        .filter(|chunk| (chunk.frame as u64) < frame)
+        .map(|chunk| (chunk.count as u64).min(frame - chunk.frame as u64))
+        .sum();
+    
+    frame - lag
+}
+
+/// Converts the movie's transitions into the device's frame/latch counter.
+///
+/// `length` is the number of frames of input that will be sent to the device. Fails if a transition
+/// uses an unsupported index kind, or would occur outside of the movie.
+pub fn convert(tasd: &TasdMovie, system: System, length: u64) -> Result<Vec<TransitionData>, String> {
+    let width = frame_width(tasd, system) as u64;
+    let lag_chunks: Vec<LagFrameChunk> = tasd.search_by_key(vec![KEY_LAG_FRAME_CHUNK]).into_iter()
+        .map(|packet| packet.as_any().downcast_ref::<LagFrameChunk>().unwrap().clone())
+        .collect();
+    
+    let mut converted = vec![];
+    for trans in collect(tasd) {
+        let index = match trans.index_kind {
+            INDEX_FRAME => frame_to_latch(trans.index, &lag_chunks),
+            INDEX_INPUT_CHUNK => {
+                if trans.index % width != 0 {
+                    warn!("Transition index is not aligned to a {width} byte frame, rounding down: {trans}");
+                }
+                
+                trans.index / width
+            },
+            _ => return Err(format!("Unsupported transition index kind: {trans}")),
+        };
+        
+        if index >= length {
+            return Err(format!("Transition occurs after the end of the movie (frame {index} of {length}): {trans}"));
+        }
+        
+        info!("{trans} -> device frame {index}");
+        converted.push(TransitionData::new(index, DEVICE_INDEX_KIND, trans.transition_kind));
+    }
+    
+    converted.sort_by_key(|trans| trans.index());
+    
+    Ok(converted)
+}
+
+#[cfg(test)]
+mod tests {
+    use tasd::spec::Packet;
+    use super::*;
+    
+    fn movie(packets: Vec<Box<dyn Packet>>) -> TasdMovie {
+        TasdMovie { packets, ..Default::default() }
+    }
+    
+    fn soft_reset(index_kind: u8, index: u64) -> Box<dyn Packet> {
+        Box::new(Transition::new(index_kind, index, TRANSITION_SOFT_RESET, None))
+    }
+    
+    /// Two ports of NES controllers, with the given packets after them.
+    fn nes(packets: Vec<Box<dyn Packet>>) -> TasdMovie {
+        let mut all: Vec<Box<dyn Packet>> = vec![
+            Box::new(PortController::new(1, 0x0101)),
+            Box::new(PortController::new(2, 0x0101)),
+            Box::new(InputChunk::new(1, vec![0; 100])),
+            Box::new(InputChunk::new(2, vec![0; 100])),
+        ];
+        all.extend(packets);
+        
+        movie(all)
+    }
+    
+    /// Two ports of SNES controllers, with the given packets after them.
+    fn snes(packets: Vec<Box<dyn Packet>>) -> TasdMovie {
+        let mut all: Vec<Box<dyn Packet>> = vec![
+            Box::new(PortController::new(1, 0x0201)),
+            Box::new(PortController::new(2, 0x0201)),
+            Box::new(InputChunk::new(1, vec![0; 200])),
+            Box::new(InputChunk::new(2, vec![0; 200])),
+        ];
+        all.extend(packets);
+        
+        movie(all)
+    }
+    
+    fn indexes(converted: Vec<TransitionData>) -> Vec<u64> {
+        converted.iter().map(|trans| trans.index()).collect()
+    }
+    
+    #[test]
+    fn lag_frames_before_the_index_are_skipped() {
+        let lag = [LagFrameChunk::new(10, 5), LagFrameChunk::new(30, 2)];
+        
+        assert_eq!(frame_to_latch(5, &lag), 5);
+        assert_eq!(frame_to_latch(10, &lag), 10);
+        assert_eq!(frame_to_latch(20, &lag), 15);
+        assert_eq!(frame_to_latch(40, &lag), 33);
+    }
+    
+    #[test]
+    fn lag_chunks_are_only_counted_up_to_the_index() {
+        let lag = [LagFrameChunk::new(10, 5)];
+        
+        assert_eq!(frame_to_latch(11, &lag), 10);
+        assert_eq!(frame_to_latch(14, &lag), 10);
+        assert_eq!(frame_to_latch(15, &lag), 10);
+        assert_eq!(frame_to_latch(16, &lag), 11);
+    }
+    
+    #[test]
+    fn nes_frame_indexes_become_latches() {
+        let tasd = nes(vec![
+            Box::new(LagFrameChunk::new(3, 4)),
+            soft_reset(INDEX_FRAME, 20),
+            soft_reset(INDEX_FRAME, 2),
+        ]);
+        
+        assert_eq!(indexes(convert(&tasd, System::Nes, 50).unwrap()), vec![2, 16]);
+    }
+    
+    #[test]
+    fn nes_input_chunk_indexes_count_both_ports() {
+        let tasd = nes(vec![soft_reset(INDEX_INPUT_CHUNK, 40), soft_reset(INDEX_INPUT_CHUNK, 41)]);
+        
+        // An unaligned index rounds down to the frame it's in
+        assert_eq!(indexes(convert(&tasd, System::Nes, 50).unwrap()), vec![20, 20]);
+    }
+    
+    #[test]
+    fn snes_indexes_use_the_controller_width() {
+        let tasd = snes(vec![
+            Box::new(LagFrameChunk::new(0, 1)),
+            soft_reset(INDEX_INPUT_CHUNK, 40),
+            soft_reset(INDEX_FRAME, 11),
+        ]);
+        
+        assert_eq!(indexes(convert(&tasd, System::Snes, 50).unwrap()), vec![10, 10]);
+    }
+    
+    #[test]
+    fn default_widths_apply_without_port_controllers() {
+        let tasd = movie(vec![
+            Box::new(InputChunk::new(1, vec![0; 8])),
+            Box::new(InputChunk::new(2, vec![0; 8])),
+            soft_reset(INDEX_INPUT_CHUNK, 8),
+        ]);
+        
+        assert_eq!(frame_width(&tasd, System::Snes), 4);
+        assert_eq!(indexes(convert(&tasd, System::Snes, 4).unwrap()), vec![2]);
+    }
+    
+    #[test]
+    fn indexes_past_the_end_are_rejected() {
+        assert!(convert(&nes(vec![soft_reset(INDEX_FRAME, 49)]), System::Nes, 50).is_ok());
+        assert!(convert(&nes(vec![soft_reset(INDEX_FRAME, 50)]), System::Nes, 50).is_err());
+        assert!(convert(&nes(vec![soft_reset(INDEX_INPUT_CHUNK, 100)]), System::Nes, 50).is_err());
+        assert!(convert(&snes(vec![soft_reset(INDEX_INPUT_CHUNK, u64::MAX)]), System::Snes, 50).is_err());
+        
+        // Lag before the transition can bring it back inside the movie
+        let tasd = nes(vec![Box::new(LagFrameChunk::new(0, 10)), soft_reset(INDEX_FRAME, 55)]);
+        assert_eq!(indexes(convert(&tasd, System::Nes, 50).unwrap()), vec![45]);
+    }
+    
+    #[test]
+    fn unsupported_index_kinds_are_rejected() {
+        assert!(convert(&nes(vec![soft_reset(0x02, 10)]), System::Nes, 50).is_err());
+    }
+    
+    #[test]
+    fn power_resets_are_kept() {
+        let tasd = nes(vec![
+            Box::new(Transition::new(INDEX_FRAME, 10, TRANSITION_POWER_RESET, None)),
+            soft_reset(INDEX_FRAME, 20),
+            Box::new(Transition::new(INDEX_FRAME, 30, 0x03, None)),
+        ]);
+        
+        let converted = convert(&tasd, System::Nes, 50).unwrap();
+        assert_eq!(indexes(converted.clone()), vec![10, 20]);
+        assert_eq!(converted[0].transition_kind(), TRANSITION_POWER_RESET);
+    }
+    
+    #[test]
+    fn timing_is_read_from_the_tagged_packet() {
+        let mut payload = TIMING_TAG.to_vec();
+        payload.extend_from_slice(&50000u32.to_be_bytes());
+        payload.extend_from_slice(&100u32.to_be_bytes());
+        let tasd = movie(vec![Box::new(Unspecified::new(b"something else".to_vec())), Box::new(Unspecified::new(payload))]);
+        
+        assert_eq!(movie_timing(&tasd), Some(TransitionTiming { reset_hold_us: 50000, reset_settle_us: 100, ..DEFAULT_TIMING }));
+        
+        let mut payload = TIMING_TAG.to_vec();
+        for time in [50000u32, 100, 2000, 300] {
+            payload.extend_from_slice(&time.to_be_bytes());
+        }
+        let tasd = movie(vec![Box::new(Unspecified::new(payload))]);
+        
+        assert_eq!(movie_timing(&tasd), Some(TransitionTiming {
+            reset_hold_us: 50000,
+            reset_settle_us: 100,
+            hard_reset_hold_ms: 2000,
+            hard_reset_boot_ms: 300,
+        }));
+    }
+    
+    #[test]
+    fn malformed_timing_is_ignored() {
+        let mut payload = TIMING_TAG.to_vec();
+        payload.extend_from_slice(&50000u32.to_be_bytes());
+        
+        assert_eq!(movie_timing(&movie(vec![Box::new(Unspecified::new(payload))])), None);
+        assert_eq!(movie_timing(&movie(vec![])), None);
+    }
+}
@@ -0,0 +1,100 @@
+use std::path::PathBuf;
+use log::{debug, error, info};
+use tasd::spec::{ConsoleType, KEY_CONSOLE_TYPE, TasdMovie};
+use veritas_core::stored;
+use veritas_core::stored::{INPUTS_OFFSET, REGION_SIZE, StoredMovie};
+use crate::replay::comms::{Command, System};
+use crate::replay::{chunk_inputs, connect, genesis_inputs, port_inputs, snes_inputs, transition_timing, transitions};
+use crate::UploadArgs;
+use crate::config::DevicesSection;
+
+/// Bytes written per command. Must evenly divide a sector.
+const CHUNK_SIZE: usize = 1024;
+
+pub fn handle(args: UploadArgs, devices: &DevicesSection) {
+    let mut dev = connect(args.device.as_deref(), devices);
+    
+    if let Some(movie) = args.movie {
+        let tasd = TasdMovie::new(&PathBuf::from(movie)).expect("Failed to parse movie.");
+        let console = tasd.search_by_key(vec![KEY_CONSOLE_TYPE]).first().expect("No console type provided in TASD. Cannot continue.").as_any().downcast_ref::<ConsoleType>().unwrap();
+        
+        let system: System = console.kind.into();
+        let (inputs, width) = match system {
+            System::Nes => (chunk_inputs(&tasd), 2),
+            System::Snes => (snes_inputs(&port_inputs(&tasd)), 4),
+            System::Genesis => (genesis_inputs(&port_inputs(&tasd)), 4),
+            _ => {
+                error!("Standalone replays aren't supported for {system:?}");
+                return;
+            }
+        };
+        
+        let length = inputs.len() / width;
+        let transitions = match transitions::convert(&tasd, system, length as u64) {
+            Ok(transitions) => transitions,
+            Err(err) => {
+                error!("{err}");
+                return;
+            }
+        };
+        
+        if !stored::inputs_fit(inputs.len()) {
+            error!("Movie is too large to store on the device ({} bytes of inputs, {} available)", inputs.len(), REGION_SIZE - INPUTS_OFFSET);
+            return;
+        }
+        
+        let header = StoredMovie {
+            system: system.into(),
+            length: length as u32,
+            latch_filter_us: args.latch_filter.unwrap_or(8000),
+            use_initial_reset: !args.disable_reset,
+            wait_for_console: args.wait_for_console,
+            autostart: args.autostart,
+            timing: transition_timing(&args.timing, transitions::movie_timing(&tasd)),
+            transitions,
+        };
+        let header = match stored::encode_header(&header) {
+            Some(header) => header,
+            None => {
+                error!("Movie has too many transitions to store on the device");
+                return;
+            }
+        };
+        
+        // The header is erased first and written last, and the device only commits it once it's been written in
+        // full, so an interrupted upload never leaves a movie that can be started.
+        if dev.send_command(Command::EraseStoredMovie).is_not_ok() {
+            error!("Failed to erase the stored movie! Is a replay running?");
+            return;
+        }
+        
+        info!("Uploading {length} frames ({} bytes)...", inputs.len());
+        for (i, chunk) in inputs.chunks(CHUNK_SIZE).enumerate() {
+            let offset = INPUTS_OFFSET + i * CHUNK_SIZE;
+            if dev.send_command(Command::WriteStoredMovie { offset: offset as u32, data: chunk.to_vec() }).is_not_ok() {
+                error!("Failed to write inputs at offset {offset:#X}!");
+                return;
+            }
+            debug!("Uploaded {}/{} bytes", offset - INPUTS_OFFSET + chunk.len(), inputs.len());
+        }
+        
+        if dev.send_command(Command::WriteStoredMovie { offset: 0, data: header }).is_not_ok() {
+            error!("Failed to write the movie header!");
+            return;
+        }
+        
+        info!("Movie stored on the device.");
+        if args.autostart {
+            info!("The replay will start whenever the console is powered on.");
+        }
+    }
+    
+    if args.start {
+        if dev.send_command(Command::StartStoredMovie).is_not_ok() {
+            error!("Failed to start the stored movie!");
+            return;
+        }
+        
+        info!("Started replaying the stored movie.");
+    }
+}
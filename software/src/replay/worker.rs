@@ -0,0 +1,443 @@
+use std::cmp::{max, min};
+use std::sync::{Arc, Barrier};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use camino::Utf8PathBuf;
+use log::{debug, error, info, warn};
+use tasd::spec::TasdMovie;
+use crate::replay::{chunk_inputs, events, genesis_inputs, mempak_init, n64_inputs, port_inputs, snes_inputs, transitions};
+use crate::replay::overlay::Overlay;
+use crate::replay::comms::{ControllerPak, Device, Response, System, TransitionData, TransitionTiming, VeritasMode};
+use crate::replay::comms::Command::{GetEventLog, GetProgress, GetStatus, ProvideInput, ProvideTransitions, SetControllerPak, SetHostTimeout, SetLatchFilter, SetN64Ports, SetReplayLength, SetReplayMode, WriteMempak};
+
+/// How often progress is reported while replaying.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+/// How often the device is asked for its progress while an overlay is following it, about once per frame.
+const OVERLAY_INTERVAL: Duration = Duration::from_millis(16);
+/// How often the device is asked whether it's finished the replay, once every input has been sent.
+const FOLLOW_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Size of the emulated N64 mempak.
+pub const MEMPAK_SIZE: usize = 0x8000;
+/// Number of mempak bytes sent per command.
+const MEMPAK_CHUNK_SIZE: usize = 1024;
+/// Number of transitions sent per command, which keeps each command well under the device's size limit.
+const TRANSITION_CHUNK_SIZE: usize = 128;
+
+/// A movie's inputs and transitions, in the form the device expects them.
+pub struct PreparedMovie {
+    pub system: System,
+    pub mode: VeritasMode,
+    /// Number of bytes the device uses for a single frame of input.
+    pub frame_size: usize,
+    pub inputs: Vec<u8>,
+    pub transitions: Vec<TransitionData>,
+    /// Transition timing stored in the movie.
+    pub timing: Option<TransitionTiming>,
+    /// N64 ports with a controller plugged in, one bit per port.
+    pub n64_ports: u8,
+    /// Accessory plugged into each emulated N64 controller.
+    pub controller_paks: [ControllerPak; 4],
+    /// Initial contents of the emulated N64 mempak.
+    pub mempak: Option<Vec<u8>>,
+}
+impl PreparedMovie {
+    pub fn from_tasd(tasd: &TasdMovie, system: System) -> Result<Self, String> {
+        let mut n64_ports = 0b0001;
+        let (mode, frame_size, mut inputs) = match system {
+            System::Nes => (VeritasMode::ReplayNes, 2, chunk_inputs(tasd)),
+            System::Snes => (VeritasMode::ReplaySnes, 4, snes_inputs(&port_inputs(tasd))),
+            System::Genesis => (VeritasMode::ReplayGenesis, 4, genesis_inputs(&port_inputs(tasd))),
+            System::N64 => {
+                let ports = port_inputs(tasd);
+                n64_ports |= ports.iter().enumerate().fold(0, |mask, (i, port)| if port.is_empty() { mask } else { mask | (1 << i) });
+                
+                (VeritasMode::ReplayN64, 16, n64_inputs(&ports))
+            },
+            _ => return Err(format!("Unsupported console: {system:?}")),
+        };
+        let partial = inputs.len() % frame_size;
+        if partial != 0 {
+            // The device only accepts whole frames, so the last one is completed with released buttons
+            warn!("Movie ends partway through a frame, padding the last frame with {} released bytes", frame_size - partial);
+            let released = if system == System::N64 { 0x00 } else { 0xFF };
+            inputs.resize(inputs.len() + frame_size - partial, released);
+        }
+        let length = (inputs.len() / frame_size) as u64;
+        let transitions = transitions::convert(tasd, system, length)?;
+        let mempak = if system == System::N64 { mempak_init(tasd) } else { None };
+        
+        Ok(Self {
+            system,
+            mode,
+            frame_size,
+            inputs,
+            transitions,
+            timing: transitions::movie_timing(tasd),
+            n64_ports,
+            controller_paks: [if mempak.is_some() { ControllerPak::Mempak } else { ControllerPak::None }, ControllerPak::None, ControllerPak::None, ControllerPak::None],
+            mempak,
+        })
+    }
+    
+    pub fn length(&self) -> u64 {
+        (self.inputs.len() / self.frame_size) as u64
+    }
+}
+
+/// Streams a movie to a single device.
+pub struct Worker {
+    /// Name used to tell devices apart in the log.
+    label: String,
+    dev: Device,
+    movie: Arc<PreparedMovie>,
+    /// Offset of the next input to send.
+    ptr: usize,
+    prev_empty: usize,
+    /// Copy of `ptr`, for reporting progress from another thread.
+    progress: Arc<AtomicUsize>,
+    /// Overlay following the console's progress on this device.
+    overlay: Option<Overlay>,
+    last_overlay_update: Instant,
+    /// Number of the first event log record made after the worker was created.
+    event_start: Option<u32>,
+    /// Directory the event log is saved to.
+    events_path: Utf8PathBuf,
+}
+impl Worker {
+    /// Creates a worker for a device that hasn't been configured yet. Only events the device logs from here on
+    /// are saved.
+    pub fn new(label: String, mut dev: Device, movie: Arc<PreparedMovie>, events_path: Utf8PathBuf) -> Self {
+        let prev_empty = movie.frame_size;
+        let event_start = match dev.send_command(GetEventLog(u32::MAX)) {
+            Response::EventLog { first, .. } => Some(first),
+            _ => {
+                warn!("[{label}] Failed to read the event log, it won't be saved");
+                None
+            }
+        };
+        
+        Self {
+            label,
+            dev,
+            movie,
+            ptr: 0,
+            prev_empty,
+            progress: Arc::new(AtomicUsize::new(0)),
+            overlay: None,
+            last_overlay_update: Instant::now(),
+            event_start,
+            events_path,
+        }
+    }
+    
+    /// The worker's device, for commands sent before it's prepared.
+    pub fn device(&mut self) -> &mut Device {
+        &mut self.dev
+    }
+    
+    /// Has the overlay follow the inputs consumed by this device's console, until the replay finishes.
+    pub fn set_overlay(&mut self, overlay: Overlay) {
+        self.overlay = Some(overlay);
+    }
+    
+    /// Sends everything except the inputs to the device.
+    pub fn prepare(&mut self, latch_filter: Option<u32>, host_timeout: u32) -> bool {
+        if matches!(self.movie.system, System::Nes | System::Snes) {
+            self.dev.send_command(SetLatchFilter(latch_filter.unwrap_or(8000)));
+        }
+        if self.movie.system == System::N64 && !self.prepare_n64() {
+            return false;
+        }
+        if self.dev.send_command(SetReplayLength(self.movie.length())).is_not_ok() {
+            error!("[{}] Failed to set replay length!", self.label);
+            return false;
+        }
+        for chunk in self.movie.transitions.chunks(TRANSITION_CHUNK_SIZE) {
+            if self.dev.send_command(ProvideTransitions(chunk.to_vec())).is_not_ok() {
+                error!("[{}] Device rejected the movie's transitions!", self.label);
+                return false;
+            }
+        }
+        if self.dev.send_command(SetHostTimeout(host_timeout)).is_not_ok() {
+            warn!("[{}] Failed to set host timeout, the replay won't stop if this program does", self.label);
+        }
+        
+        if let Response::DeviceStatus(text) = self.dev.send_command(GetStatus) {
+            info!("[{}] {text}", self.label);
+        } else {
+            warn!("[{}] Failed to receive device status", self.label);
+        }
+        
+        true
+    }
+    
+    /// Plugs in the movie's controllers and their paks, and uploads the mempak's initial contents.
+    fn prepare_n64(&mut self) -> bool {
+        if self.dev.send_command(SetN64Ports(self.movie.n64_ports)).is_not_ok() {
+            error!("[{}] Failed to set N64 controller ports!", self.label);
+            return false;
+        }
+        for (port, pak) in self.movie.controller_paks.iter().enumerate() {
+            if self.dev.send_command(SetControllerPak { port: port as u8, pak: *pak }).is_not_ok() {
+                error!("[{}] Failed to plug a {pak:?} into port {}! Only one port can hold the mempak.", self.label, port + 1);
+                return false;
+            }
+        }
+        
+        if let (true, Some(mempak)) = (self.movie.controller_paks.contains(&ControllerPak::Mempak), &self.movie.mempak) {
+            for (i, chunk) in mempak.chunks(MEMPAK_CHUNK_SIZE).enumerate() {
+                let offset = (i * MEMPAK_CHUNK_SIZE) as u16;
+                if self.dev.send_command(WriteMempak { offset, data: chunk.to_vec() }).is_not_ok() {
+                    error!("[{}] Failed to upload mempak!", self.label);
+                    return false;
+                }
+            }
+            info!("[{}] Uploaded mempak.", self.label);
+        }
+        
+        true
+    }
+    
+    /// Fills the device's input buffer, without starting the replay.
+    pub fn prefill(&mut self, exit_early: &AtomicBool) -> bool {
+        info!("[{}] Prefilling buffer...", self.label);
+        while self.ptr < self.movie.inputs.len() {
+            if exit_early.load(Ordering::Relaxed) {
+                self.stop();
+                return false;
+            }
+            
+            if self.provide_input() == Some(0) {
+                break;
+            }
+        }
+        
+        true
+    }
+    
+    pub fn start(&mut self) -> bool {
+        if self.dev.send_command(SetReplayMode(self.movie.mode)).is_not_ok() {
+            error!("[{}] Failed to set replay mode!", self.label);
+            return false;
+        }
+        info!("[{}] Starting replay.", self.label);
+        
+        true
+    }
+    
+    /// Sends the rest of the inputs as the device consumes them.
+    pub fn stream(&mut self, exit_early: &AtomicBool) {
+        while self.ptr < self.movie.inputs.len() {
+            if exit_early.load(Ordering::Relaxed) {
+                self.stop();
+                return;
+            }
+            
+            if let Some(remaining_space) = self.provide_input() {
+                if remaining_space < 128 {
+                    self.wait(Duration::from_millis(2000));
+                }
+            }
+            self.update_overlay();
+        }
+        
+        // The device no longer needs anything from us to finish the replay
+        if self.dev.send_command(SetHostTimeout(0)).is_not_ok() {
+            warn!("[{}] Failed to clear host timeout", self.label);
+        }
+        info!("[{}] All inputs sent.", self.label);
+        
+        self.follow(exit_early);
+    }
+    
+    /// Sleeps, while keeping the overlay up to date.
+    fn wait(&mut self, duration: Duration) {
+        if self.overlay.is_none() {
+            sleep(duration);
+            return;
+        }
+        
+        let start = Instant::now();
+        while start.elapsed() < duration {
+            sleep(OVERLAY_INTERVAL);
+            self.update_overlay();
+        }
+    }
+    
+    /// Waits for the device to finish the replay, keeping the overlay up to date.
+    fn follow(&mut self, exit_early: &AtomicBool) {
+        let interval = if self.overlay.is_some() { OVERLAY_INTERVAL } else { FOLLOW_INTERVAL };
+        loop {
+            if exit_early.load(Ordering::Relaxed) {
+                self.stop();
+                return;
+            }
+            
+            sleep(interval);
+            match self.poll_progress() {
+                Some(mode) if mode != self.movie.mode => break,
+                _ => (),
+            }
+        }
+        
+        info!("[{}] Replay finished.", self.label);
+    }
+    
+    /// Passes the device's progress on to the overlay, if there is one and it's due for an update.
+    fn update_overlay(&mut self) {
+        if self.overlay.is_none() || self.last_overlay_update.elapsed() < OVERLAY_INTERVAL {
+            return;
+        }
+        self.last_overlay_update = Instant::now();
+        
+        self.poll_progress();
+    }
+    
+    /// Asks the device for its progress, and passes it on to the overlay if there is one. Returns the device's
+    /// mode, if it answered.
+    fn poll_progress(&mut self) -> Option<VeritasMode> {
+        match self.dev.send_command(GetProgress) {
+            Response::Progress { mode, index_cur, .. } => {
+                if let Some(overlay) = self.overlay.as_mut() {
+                    overlay.publish(index_cur);
+                }
+                Some(mode)
+            },
+            resp => {
+                warn!("[{}] Failed to get replay progress: {resp:?}", self.label);
+                None
+            }
+        }
+    }
+    
+    /// Sends the next few inputs, returning how much space is left in the device's buffer.
+    fn provide_input(&mut self) -> Option<u16> {
+        let inputs = &self.movie.inputs;
+        let remaining = inputs.len() - self.ptr;
+        let size = min(remaining, max(self.movie.frame_size, min(self.prev_empty, 16)));
+        let input = inputs[self.ptr..(self.ptr + size)].to_vec();
+        
+        if let Response::BufferStatus { written, remaining_space } = self.dev.send_command(ProvideInput(self.movie.system, input)) {
+            self.ptr += written as usize;
+            self.prev_empty = remaining_space as usize;
+            self.progress.store(self.ptr, Ordering::Relaxed);
+            debug!("[{}] written: {written}, remaining_space: {remaining_space}", self.label);
+            
+            Some(remaining_space)
+        } else {
+            error!("[{}] Failed to receive buffer status! desync likely!", self.label);
+            None
+        }
+    }
+    
+    /// Reads the events the device logged since it was prepared, and saves them to a file.
+    pub fn save_event_log(&mut self) {
+        let Some(start) = self.event_start else {
+            return;
+        };
+        
+        let (records, overwritten) = match events::fetch(&mut self.dev, start) {
+            Ok(log) => log,
+            Err(resp) => {
+                warn!("[{}] Failed to read the event log: {resp:?}", self.label);
+                return;
+            }
+        };
+        if overwritten > 0 {
+            warn!("[{}] {overwritten} events were overwritten before they could be read", self.label);
+        }
+        
+        match events::save(&self.events_path, &self.label, &records, overwritten) {
+            Ok(path) => info!("[{}] Saved {} events to {path}", self.label, records.len()),
+            Err(err) => error!("[{}] Failed to save the event log: {err}", self.label),
+        }
+    }
+    
+    pub fn stop(&mut self) {
+        if self.dev.send_command(SetReplayMode(VeritasMode::Idle)).is_not_ok() {
+            error!("[{}] Failed to set replay mode!", self.label);
+        }
+        info!("[{}] Exiting..", self.label);
+    }
+}
+
+/// Starts every worker at the same moment, then streams to each device on its own thread until
+/// they're all done.
+///
+/// Workers should already be prepared and prefilled, so that starting them only takes a single
+/// command each.
+pub fn run(workers: Vec<Worker>, exit_early: Arc<AtomicBool>) {
+    let barrier = Arc::new(Barrier::new(workers.len()));
+    let mut progress = vec![];
+    let mut handles = vec![];
+    
+    for mut worker in workers {
+        progress.push((worker.label.clone(), worker.progress.clone(), worker.movie.clone()));
+        
+        let barrier = barrier.clone();
+        let exit_early = exit_early.clone();
+        handles.push(std::thread::spawn(move || {
+            barrier.wait();
+            
+            if worker.start() {
+                worker.stream(&exit_early);
+            }
+            worker.save_event_log();
+        }));
+    }
+    
+    let mut last_report = Instant::now();
+    while !handles.iter().all(|handle| handle.is_finished()) {
+        sleep(Duration::from_millis(100));
+        
+        if last_report.elapsed() >= PROGRESS_INTERVAL && !exit_early.load(Ordering::Relaxed) {
+            last_report = Instant::now();
+            for (label, ptr, movie) in &progress {
+                let sent = ptr.load(Ordering::Relaxed) / movie.frame_size;
+                info!("[{label}] {sent}/{} frames sent ({:.1}%)", movie.length(), sent as f64 * 100.0 / movie.length().max(1) as f64);
+            }
+        }
+    }
+    
+    for handle in handles {
+        if handle.join().is_err() {
+            error!("A replay thread panicked!");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tasd::spec::{InputChunk, Packet};
+    use super::*;
+    
+    fn movie(packets: Vec<Box<dyn Packet>>) -> TasdMovie {
+        TasdMovie { packets, ..Default::default() }
+    }
+    
+    #[test]
+    fn partial_frames_are_padded_with_released_buttons() {
+        let nes = PreparedMovie::from_tasd(&movie(vec![Box::new(InputChunk::new(1, vec![0x7F; 5]))]), System::Nes).unwrap();
+        assert_eq!(nes.inputs, vec![0x7F, 0x7F, 0x7F, 0x7F, 0x7F, 0xFF]);
+        assert_eq!(nes.length(), 3);
+        
+        let n64 = PreparedMovie::from_tasd(&movie(vec![Box::new(InputChunk::new(1, vec![0x80; 6]))]), System::N64).unwrap();
+        assert_eq!(n64.inputs.len(), 32);
+        assert_eq!(&n64.inputs[16..20], &[0x80, 0x80, 0x00, 0x00]);
+    }
+    
+    #[test]
+    fn chunks_for_missing_ports_are_skipped() {
+        let tasd = movie(vec![
+            Box::new(InputChunk::new(0, vec![0x01; 4])),
+            Box::new(InputChunk::new(4, vec![0x02; 4])),
+            Box::new(InputChunk::new(5, vec![0x03; 4])),
+        ]);
+        let n64 = PreparedMovie::from_tasd(&tasd, System::N64).unwrap();
+        
+        assert_eq!(n64.n64_ports, 0b1001);
+        assert_eq!(n64.inputs, [[0x00; 12].as_slice(), &[0x02; 4]].concat());
+    }
+}
@@ -0,0 +1,174 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use camino::Utf8Path;
+use clap::ValueEnum;
+use log::{error, info, warn};
+use tasd::spec::{ConsoleType, InputChunk, PortController, TasdMovie};
+use crate::config::DevicesSection;
+use crate::replay;
+use crate::replay::comms::{Command, Response, System, VeritasMode};
+use crate::replay::overlay::Overlay;
+use crate::SpyArgs;
+
+/// How often the device is asked for the frames it has read, while it has no more waiting.
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+/// Frames the device sends at most in one response. A full response means more are waiting.
+const MAX_SPY_FRAMES: usize = 48;
+/// Time in milliseconds the device keeps spying without hearing from this program.
+const HOST_TIMEOUT_MS: u32 = 2000;
+
+/// Console whose controllers are plugged through the device.
+#[derive(Debug, PartialEq, Eq, Copy, Clone, ValueEnum)]
+pub enum Console {
+    Nes,
+    Snes,
+    Genesis,
+}
+impl Console {
+    fn system(self) -> System {
+        match self {
+            Console::Nes => System::Nes,
+            Console::Snes => System::Snes,
+            Console::Genesis => System::Genesis,
+        }
+    }
+    
+    fn mode(self) -> VeritasMode {
+        match self {
+            Console::Nes => VeritasMode::SpyNes,
+            Console::Snes => VeritasMode::SpySnes,
+            Console::Genesis => VeritasMode::SpyGenesis,
+        }
+    }
+    
+    /// Number of bytes each port's input takes in a TASD.
+    fn port_bytes(self) -> usize {
+        match self {
+            Console::Nes | Console::Genesis => 1,
+            Console::Snes => 2,
+        }
+    }
+    
+    /// TASD controller type of a standard controller.
+    fn controller_kind(self) -> u16 {
+        match self {
+            Console::Nes => 0x0101,
+            Console::Snes => 0x0201,
+            Console::Genesis => 0x0801,
+        }
+    }
+    
+    /// A frame from the device (2 bytes per port), laid out as in a movie the device would replay.
+    fn movie_frame(self, frame: [u8; 4]) -> Vec<u8> {
+        match self {
+            Console::Nes => vec![frame[0], frame[2]],
+            Console::Snes | Console::Genesis => frame.to_vec(),
+        }
+    }
+}
+
+pub fn handle(args: SpyArgs, devices: &DevicesSection) {
+    let console = args.console;
+    
+    let mut overlay = match args.overlay.map(|addr| (addr, Overlay::start_live(addr, console.system()))) {
+        Some((addr, Ok(overlay))) => {
+            info!("Overlay available at http://{addr}/");
+            Some(overlay)
+        },
+        Some((addr, Err(err))) => {
+            error!("Failed to start the overlay on {addr}: {err}");
+            return;
+        },
+        None => None,
+    };
+    
+    let mut dev = replay::connect(args.device.as_deref(), devices);
+    
+    if let Some(latch_filter) = args.latch_filter {
+        if dev.send_command(Command::SetLatchFilter(latch_filter)).is_not_ok() {
+            error!("Failed to set latch filter!");
+            return;
+        }
+    }
+    if dev.send_command(Command::SetHostTimeout(HOST_TIMEOUT_MS)).is_not_ok() {
+        error!("Failed to set host timeout!");
+        return;
+    }
+    if dev.send_command(Command::SetReplayMode(console.mode())).is_not_ok() {
+        error!("Failed to start spying! Is a replay running?");
+        return;
+    }
+    
+    let exit = Arc::new(AtomicBool::new(false));
+    let exit_handler = exit.clone();
+    ctrlc::set_handler(move || {
+        exit_handler.store(true, Ordering::Relaxed);
+    }).expect("Failed to set CTRL+C handler");
+    
+    info!("Recording {console:?} inputs, press Ctrl+C to stop...");
+    let mut frames = vec![];
+    let mut stopped = false;
+    loop {
+        // Once the device has stopped, whatever it still holds is collected before finishing
+        if !stopped && exit.load(Ordering::Relaxed) {
+            if dev.send_command(Command::SetReplayMode(VeritasMode::Idle)).is_not_ok() {
+                warn!("Failed to stop spying");
+            }
+            stopped = true;
+        }
+        
+        let data = match dev.send_command(Command::GetSpyInputs) {
+            Response::SpyInputs(data) => data,
+            resp => {
+                error!("Failed to read inputs: {resp:?}");
+                break;
+            }
+        };
+        for frame in data.chunks_exact(4) {
+            let frame: [u8; 4] = frame.try_into().unwrap();
+            frames.push(frame);
+            
+            if let Some(overlay) = overlay.as_mut() {
+                overlay.publish_input(frames.len() as u32, &console.movie_frame(frame));
+            }
+        }
+        
+        if data.len() < MAX_SPY_FRAMES * 4 {
+            if stopped {
+                break;
+            }
+            std::thread::sleep(POLL_INTERVAL);
+        }
+    }
+    
+    if dev.send_command(Command::SetHostTimeout(0)).is_not_ok() {
+        warn!("Failed to clear host timeout");
+    }
+    info!("Recorded {} frames", frames.len());
+    
+    if let Some(path) = args.output {
+        match write_tasd(&path, console, &frames) {
+            Ok(()) => info!("Saved the inputs to {path}"),
+            Err(err) => error!("Failed to save the inputs to {path}: {err}"),
+        }
+    }
+}
+
+/// Writes the frames to a TASD, as a chunk per port per frame, which every replay reads back the same way.
+fn write_tasd(path: &Utf8Path, console: Console, frames: &[[u8; 4]]) -> std::io::Result<()> {
+    let mut tasd = TasdMovie { source_path: path.into(), ..Default::default() };
+    
+    tasd.packets.push(Box::new(ConsoleType::new(console.system().into(), None)));
+    for port in 1..=2 {
+        tasd.packets.push(Box::new(PortController::new(port, console.controller_kind())));
+    }
+    for frame in frames {
+        for port in 0..2 {
+            let start = port * 2;
+            tasd.packets.push(Box::new(InputChunk::new(port as u8 + 1, frame[start..(start + console.port_bytes())].to_vec())));
+        }
+    }
+    
+    tasd.save()
+}
\ No newline at end of file